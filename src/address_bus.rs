@@ -3,15 +3,49 @@ use iset::IntervalMap;
 use crate::AddressBusDevice;
 
 use std::cmp::{max, min};
+use std::collections::HashMap;
+use std::hash::{BuildHasherDefault, Hasher};
+
+// Pages are 4 KiB
+pub const PAGE_SIZE_SHIFT: u64 = 12;
+
+// A map keyed by addresses or page numbers. These are looked up for every instruction, and don't need the
+// protection against collision attacks of the default hasher
+pub type AddressMap<V> = HashMap<u64, V, BuildHasherDefault<AddressHasher>>;
+
+#[derive(Default)]
+pub struct AddressHasher {
+    hash: u64,
+}
+
+impl Hasher for AddressHasher {
+    fn write(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            self.write_u64(byte as u64);
+        }
+    }
+
+    fn write_u64(&mut self, value: u64) {
+        self.hash = (self.hash ^ value).wrapping_mul(0x9e3779b97f4a7c15);
+    }
+
+    fn finish(&self) -> u64 {
+        self.hash
+    }
+}
 
 pub struct AddressBus {
     entries: IntervalMap<u64, Box<dyn AddressBusDevice>>,
+
+    // Versions of the pages instructions were cached from, changed by every write to the page
+    page_versions: AddressMap<u64>,
 }
 
 impl AddressBus {
     pub fn new() -> Self {
         Self {
             entries: IntervalMap::new(),
+            page_versions: AddressMap::default(),
         }
     }
 
@@ -30,7 +64,60 @@ impl AddressBus {
         }
     }
 
+    // The version of a page. From now on writes to the page change its version
+    pub fn page_version(&mut self, page: u64) -> u64 {
+        *self.page_versions.entry(page).or_insert(0)
+    }
+
+    // Whether the whole range is backed by a single device whose contents can be cached
+    pub fn is_cacheable(&self, address: u64, length: u64) -> bool {
+        let end = address.saturating_add(length);
+        let mut entries = self.entries.iter(address..end);
+
+        match (entries.next(), entries.next()) {
+            (Some((location, device)), None) => {
+                location.start <= address && location.end >= end && device.cacheable()
+            }
+            _ => false,
+        }
+    }
+
+    // Whether every byte of the range belongs to a device
+    pub fn is_mapped(&self, address: u64, length: u64) -> bool {
+        if length == 0 {
+            return true;
+        }
+
+        let end = address.saturating_add(length);
+        let mut mapped_end = address;
+
+        for (location, _) in self.entries.iter(address..end) {
+            if location.start > mapped_end {
+                return false;
+            }
+
+            mapped_end = max(mapped_end, location.end);
+        }
+
+        mapped_end >= end
+    }
+
+    // Empty reads and writes do nothing. The interval map doesn't allow looking up empty ranges
     pub fn write(&mut self, src: &[u8], address: u64) {
+        if src.is_empty() {
+            return;
+        }
+
+        if !self.page_versions.is_empty() {
+            let last_byte = address.saturating_add(src.len() as u64 - 1);
+
+            for page in address >> PAGE_SIZE_SHIFT..=last_byte >> PAGE_SIZE_SHIFT {
+                if let Some(version) = self.page_versions.get_mut(&page) {
+                    *version += 1;
+                }
+            }
+        }
+
         for (entry_location, entry) in self.entries.iter_mut(address..address + src.len() as u64) {
             let start_address = max(entry_location.start.into(), address);
             let end_address = min(entry_location.end, address + src.len() as u64);
@@ -47,6 +134,10 @@ impl AddressBus {
     }
 
     pub fn read(&mut self, dest: &mut [u8], address: u64) {
+        if dest.is_empty() {
+            return;
+        }
+
         for (entry_location, entry) in self.entries.iter_mut(address..address + dest.len() as u64) {
             let start_address = max(entry_location.start.into(), address);
             let end_address = min(entry_location.end, address + dest.len() as u64);
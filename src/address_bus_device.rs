@@ -1,4 +1,10 @@
 pub trait AddressBusDevice {
     fn write(&mut self, src: &[u8], address: u64, offset: u64);
     fn read(&mut self, src: &mut [u8], address: u64, offset: u64);
+
+    /// Whether reads only ever return what was written through the address bus, which lets the CPU cache
+    /// instructions fetched from the device
+    fn cacheable(&self) -> bool {
+        false
+    }
 }
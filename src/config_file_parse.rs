@@ -1,8 +1,11 @@
 mod try_parse;
 
 use crate::{
-    debug_println, library_device::LibraryPortDevice, AddressBus, LibraryAddressDevice, PortBus,
-    PortBusDevice,
+    cpu::{parse_feature, BootParameters, RegisterId, ResetVector},
+    debug_println,
+    library_device::LibraryPortDevice,
+    machine::MachineParameters,
+    AddressBus, LibraryAddressDevice, PortBus, PortBusDevice,
 };
 use path_absolutize::*;
 use std::{
@@ -14,7 +17,7 @@ use std::{
     path::{self, Path, PathBuf},
     rc::Rc,
 };
-use try_parse::try_parse_number;
+pub use try_parse::try_parse_number;
 
 #[derive(Debug, Clone, Copy)]
 enum DeviceType {
@@ -84,6 +87,8 @@ impl ConfigEntry {
 #[derive(Debug)]
 pub struct Config {
     entries: Vec<ConfigEntry>,
+    boot_parameters: BootParameters,
+    machine_parameters: MachineParameters,
 }
 
 impl Config {
@@ -116,6 +121,14 @@ impl Config {
         Ok(this)
     }
 
+    pub fn boot_parameters(&self) -> &BootParameters {
+        &self.boot_parameters
+    }
+
+    pub fn machine_parameters(&self) -> &MachineParameters {
+        &self.machine_parameters
+    }
+
     pub fn apply_config(
         &self,
         address_bus: &mut AddressBus,
@@ -244,6 +257,8 @@ impl Config {
         P: AsRef<str> + std::fmt::Display,
     {
         let mut entries: Vec<ConfigEntry> = Vec::new();
+        let mut boot_parameters = BootParameters::default();
+        let mut machine_parameters = MachineParameters::default();
 
         for (line_idx, line) in config.as_ref().lines().enumerate() {
             if line.trim().is_empty() {
@@ -252,11 +267,171 @@ impl Config {
 
             let line_number = line_idx + 1;
 
-            let entry = Self::parse_config_line(line, line_number)?;
-            entries.push(entry);
+            match line.split_ascii_whitespace().next() {
+                Some(
+                    "reset-vector" | "initial-sp" | "initial-flags" | "initial-register"
+                    | "disable-feature",
+                ) => Self::parse_boot_parameter_line(line, line_number, &mut boot_parameters)?,
+
+                Some("cores" | "quantum" | "schedule-seed" | "ipi-port") => {
+                    Self::parse_machine_parameter_line(line, line_number, &mut machine_parameters)?
+                }
+
+                _ => {
+                    let entry = Self::parse_config_line(line, line_number)?;
+                    entries.push(entry);
+                }
+            }
+        }
+
+        Ok(Self {
+            entries,
+            boot_parameters,
+            machine_parameters,
+        })
+    }
+
+    fn parse_boot_parameter_line(
+        line: &str,
+        line_number: usize,
+        boot_parameters: &mut BootParameters,
+    ) -> Result<(), ()> {
+        let split = line.split_ascii_whitespace().collect::<Vec<_>>();
+
+        match split[..] {
+            ["reset-vector", kind, address] => {
+                let address = Self::parse_number_on_line(address, line_number, "reset vector")?;
+
+                boot_parameters.reset_vector = match kind {
+                    "pointer" => ResetVector::Pointer(address),
+                    "address" => ResetVector::Address(address),
+                    _ => {
+                        println!(
+                            "Invalid reset vector type on line {}. Expected \"pointer\" or \"address\"",
+                            line_number
+                        );
+                        return Err(());
+                    }
+                };
+            }
+
+            ["initial-sp", value] => {
+                boot_parameters.initial_sp =
+                    Self::parse_number_on_line(value, line_number, "initial stack pointer")?;
+            }
+
+            ["initial-flags", value] => {
+                boot_parameters.initial_flags =
+                    Self::parse_number_on_line(value, line_number, "initial flags")?;
+            }
+
+            ["initial-register", register, value] => {
+                let id = match RegisterId::try_from(register) {
+                    Ok(RegisterId::Sp | RegisterId::Ip) => {
+                        println!(
+                            "Register {} cannot be set on line {}. Use \"initial-sp\" or \"reset-vector\" instead",
+                            register, line_number
+                        );
+                        return Err(());
+                    }
+                    Ok(id) => id,
+                    Err(_) => {
+                        println!("Invalid register name on line {}", line_number);
+                        return Err(());
+                    }
+                };
+
+                let value = Self::parse_number_on_line(value, line_number, "register value")?;
+                boot_parameters.set_initial_register(id, value);
+            }
+
+            ["disable-feature", name] => match parse_feature(name) {
+                Some(feature) => boot_parameters.features &= !feature,
+                None => {
+                    println!("Unknown feature \"{}\" on line {}", name, line_number);
+                    return Err(());
+                }
+            },
+
+            _ => {
+                println!("Invalid {} entry on line {}", split[0], line_number);
+                return Err(());
+            }
+        }
+
+        Ok(())
+    }
+
+    fn parse_machine_parameter_line(
+        line: &str,
+        line_number: usize,
+        machine_parameters: &mut MachineParameters,
+    ) -> Result<(), ()> {
+        let split = line.split_ascii_whitespace().collect::<Vec<_>>();
+
+        match split[..] {
+            ["cores", count] => {
+                let count = Self::parse_number_on_line(count, line_number, "core count")?;
+
+                if count == 0 {
+                    println!("The core count on line {} must be at least 1", line_number);
+                    return Err(());
+                }
+
+                machine_parameters.cores = count as usize;
+            }
+
+            ["quantum", count] => {
+                let count = Self::parse_number_on_line(count, line_number, "quantum")?;
+
+                if count == 0 {
+                    println!("The quantum on line {} must be at least 1", line_number);
+                    return Err(());
+                }
+
+                machine_parameters.quantum = count;
+            }
+
+            ["schedule-seed", seed] => {
+                machine_parameters.schedule_seed = Some(Self::parse_number_on_line(
+                    seed,
+                    line_number,
+                    "schedule seed",
+                )?);
+            }
+
+            ["ipi-port", port] => {
+                let port = Self::parse_number_on_line(port, line_number, "port")?;
+
+                machine_parameters.inter_processor_interrupt_port = match port.try_into() {
+                    Ok(port) => port,
+                    Err(_) => {
+                        println!(
+                            "Port too large. Port should be within the range 0-{}",
+                            u16::MAX
+                        );
+                        return Err(());
+                    }
+                };
+            }
+
+            _ => {
+                println!("Invalid {} entry on line {}", split[0], line_number);
+                return Err(());
+            }
         }
 
-        Ok(Self { entries })
+        Ok(())
+    }
+
+    fn parse_number_on_line(number: &str, line_number: usize, what: &str) -> Result<u64, ()> {
+        match try_parse_number(number) {
+            Ok(value) => Ok(value),
+            Err(e) => {
+                println!("Error: {e} on line {} when parsing {}", line_number, what);
+                Err(())
+            }
+        }
     }
 
     fn parse_config_line<P>(line: P, line_number: usize) -> Result<ConfigEntry, ()>
@@ -387,3 +562,74 @@ impl Config {
         ))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn boot_parameters_are_parsed() {
+        let config = Config::parse_config(
+            "reset-vector address 0x1000\n\
+             initial-sp 0x8000\n\
+             \n\
+             initial-flags 0b101\n\
+             initial-register x1 5\n\
+             initial-register X10 0x20\n\
+             initial-register x1 6",
+        )
+        .unwrap();
+
+        let boot_parameters = config.boot_parameters();
+        assert!(matches!(
+            boot_parameters.reset_vector,
+            ResetVector::Address(0x1000)
+        ));
+        assert_eq!(boot_parameters.initial_sp, 0x8000);
+        assert_eq!(boot_parameters.initial_flags, 0b101);
+        assert_eq!(
+            boot_parameters.initial_registers,
+            [(RegisterId::X10, 0x20), (RegisterId::X1, 6)]
+        );
+
+        let config = Config::parse_config("reset-vector pointer 0x10").unwrap();
+        assert!(matches!(
+            config.boot_parameters().reset_vector,
+            ResetVector::Pointer(0x10)
+        ));
+    }
+
+    #[test]
+    fn missing_boot_parameters_keep_their_defaults() {
+        let config = Config::parse_config("").unwrap();
+        let defaults = BootParameters::default();
+
+        let boot_parameters = config.boot_parameters();
+        assert!(matches!(
+            boot_parameters.reset_vector,
+            ResetVector::Pointer(0)
+        ));
+        assert_eq!(boot_parameters.initial_sp, defaults.initial_sp);
+        assert_eq!(boot_parameters.initial_flags, defaults.initial_flags);
+        assert!(boot_parameters.initial_registers.is_empty());
+    }
+
+    #[test]
+    fn invalid_boot_parameters() {
+        for line in [
+            "reset-vector 0x1000",
+            "reset-vector somewhere 0x1000",
+            "reset-vector address zero",
+            "initial-sp",
+            "initial-sp 0x8000 0x9000",
+            "initial-flags flags",
+            "initial-register x1",
+            "initial-register x16 1",
+            "initial-register sp 0x8000",
+            "initial-register ip 0x1000",
+            "initial-register x1 one",
+        ] {
+            assert!(Config::parse_config(line).is_err(), "{}", line);
+        }
+    }
+}
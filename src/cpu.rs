@@ -1,20 +1,44 @@
+mod boot_parameters;
+mod condition;
+mod control_register;
+mod debug;
+mod features;
+mod float_control;
+mod instruction_cache;
 mod instruction_lookup;
 mod instructions;
+#[cfg(feature = "jit")]
+mod jit;
+mod operand;
+mod performance_counters;
 mod register_id;
 mod reserved_idt_entries;
 mod size;
+#[cfg(test)]
+mod test_cpu;
 
 use crate::debug_println;
 
 use self::instruction_lookup::{LookupEntry, LOOKUP_TABLE};
-use super::address_bus::AddressBus;
+use super::address_bus::{AddressBus, PAGE_SIZE_SHIFT};
 use crate::port_bus::PortBus;
+use crate::system_call_host::SystemCallHost;
+use condition::Condition;
+use control_register::*;
+use instruction_cache::{DecodedOperands, Fetch, InstructionCache};
 use instructions::InstructionResult;
-use register_id::RegisterId;
+use operand::{MemoryOperand, Operand};
+use performance_counters::{PerformanceCounter, PerformanceCounters};
 use reserved_idt_entries::*;
 use size::Size;
 
-use std::{cell::RefCell, fmt::Display, ops::Add, rc::Rc, time::Duration};
+pub use boot_parameters::{BootParameters, ResetVector};
+pub use debug::{DebugHook, DebugTrap};
+pub use features::parse_feature;
+pub use register_id::RegisterId;
+use register_id::{FLOAT_REGISTER_COUNT, REGISTER_COUNT, VECTOR_REGISTER_COUNT};
+
+use std::{cell::RefCell, rc::Rc};
 
 #[derive(Debug, Clone, Copy)]
 enum CpuFlag {
@@ -23,30 +47,117 @@ enum CpuFlag {
     Zero = 2,
     Carry = 3,
     InterruptEnable = 4,
+    // Raises the DEBUG trap after every instruction that starts with it set
+    Trap = 5,
 }
 
 pub struct Cpu {
     address_bus: Rc<RefCell<AddressBus>>,
     port_bus: Rc<RefCell<PortBus>>,
 
-    registers: [u64; 7],
-    idt: u64,
+    registers: [u64; REGISTER_COUNT],
+    control_registers: [u64; CONTROL_REGISTER_COUNT],
+
+    /// Raw bits of the floating point registers. Single precision values are kept in the lowest 32 bits
+    float_registers: [u64; FLOAT_REGISTER_COUNT],
+    float_control: u64,
+
+    vector_registers: [u128; VECTOR_REGISTER_COUNT],
+
+    /// Set by the `EXT` prefix for the duration of the instruction it prefixes
+    register_extension: Option<u8>,
+
+    /// Set by the `MEMD` and `MEMS` prefixes for the duration of the instruction they prefix
+    memory_operand: Option<MemoryOperand>,
+
+    /// Address of the first byte of the instruction being executed, including any prefixes
+    instruction_start: u64,
+
+    /// Instructions that were already fetched, or None if every instruction is fetched from the address bus
+    instruction_cache: Option<InstructionCache>,
+    /// Where the bytes of the instruction being executed come from
+    fetch: Fetch,
+
+    /// Runs hot blocks as host code, or None if every instruction is interpreted
+    #[cfg(feature = "jit")]
+    jit: Option<jit::Jit>,
+
+    /// Pushed in the interrupt frame when the current instruction raises an exception. Both are 0 unless the
+    /// exception sets them
+    error_code: u64,
+    fault_address: u64,
+
+    /// Set when an arithmetic instruction overflows with trap-on-overflow enabled. The trap is raised once the
+    /// instruction is done
+    overflow_trap_pending: bool,
 
     flags: u64,
     halted: bool,
+
+    performance_counters: PerformanceCounters,
+
+    boot_parameters: BootParameters,
+
+    /// Reported by CPUID so guests can tell the cores of a machine apart
+    core_id: u64,
+    core_count: u64,
+
+    /// Services SYSCALL on the host in hosted mode. Without it SYSCALL raises the SYSTEM_CALL interrupt
+    system_call_host: Option<Rc<RefCell<SystemCallHost>>>,
+
+    /// Lets a debugger on the host intercept the DEBUG vector
+    debug_hook: Option<DebugHook>,
 }
 
 impl Cpu {
-    pub fn new(address_bus: Rc<RefCell<AddressBus>>, port_bus: Rc<RefCell<PortBus>>) -> Self {
+    pub fn new(
+        address_bus: Rc<RefCell<AddressBus>>,
+        port_bus: Rc<RefCell<PortBus>>,
+        boot_parameters: BootParameters,
+        core_id: u64,
+        core_count: u64,
+    ) -> Self {
         let mut cpu = Self {
             address_bus,
             port_bus,
 
-            registers: [0; 7],
-            idt: 0,
+            registers: [0; REGISTER_COUNT],
+            control_registers: [0; CONTROL_REGISTER_COUNT],
+
+            float_registers: [0; FLOAT_REGISTER_COUNT],
+            float_control: float_control::FLOAT_CONTROL_RESET,
+
+            vector_registers: [0; VECTOR_REGISTER_COUNT],
+
+            register_extension: None,
+            memory_operand: None,
+
+            instruction_start: 0,
+
+            instruction_cache: Some(InstructionCache::new()),
+            fetch: Fetch::Uncached,
+
+            #[cfg(feature = "jit")]
+            jit: None,
+
+            error_code: 0,
+            fault_address: 0,
+
+            overflow_trap_pending: false,
 
             flags: 0,
             halted: false,
+
+            performance_counters: PerformanceCounters::new(),
+
+            boot_parameters,
+
+            core_id,
+            core_count,
+
+            system_call_host: None,
+
+            debug_hook: None,
         };
 
         cpu.reset();
@@ -54,73 +165,352 @@ impl Cpu {
         cpu
     }
 
-    pub fn clock(&mut self) {
+    // Runs the next instruction, or with the JIT a compiled block of at most `max_instructions` instructions.
+    // Returns the number of clock cycles that took, which is one per instruction, or one while halted
+    #[cfg_attr(not(feature = "jit"), allow(unused_variables))]
+    pub fn clock(&mut self, max_instructions: u64) -> u64 {
+        self.count(PerformanceCounter::Cycles);
+
         if !self.halted {
+            self.instruction_start = self.register(RegisterId::Ip);
+            self.error_code = 0;
+            self.fault_address = 0;
+
+            #[cfg(feature = "jit")]
+            if let Some(instruction_count) = self.execute_compiled_block(max_instructions) {
+                return instruction_count;
+            }
+
+            if self.instruction_cache.is_some() {
+                self.fetch = self.start_fetch(self.instruction_start);
+
+                if let Some(instruction) = self.fetch.cached_instruction() {
+                    let (entry, fetches) = (instruction.entry(), instruction.fetches());
+
+                    self.performance_counters
+                        .add(PerformanceCounter::BusAccesses, fetches);
+                    self.register_add_assign(RegisterId::Ip, 1);
+                    self.execute_instruction(entry);
+                    return 1;
+                }
+            }
+
             let opcode = self.fetch_byte();
             self.execute_opcode(opcode);
         }
+
+        1
     }
 
     pub fn reset(&mut self) {
         debug_println!("Resetting CPU!");
-        #[cfg(debug_assertions)]
-        std::thread::sleep(Duration::from_secs_f32(1.0));
+        #[cfg(all(debug_assertions, not(test)))]
+        std::thread::sleep(std::time::Duration::from_secs_f32(1.0));
+
+        let execution_start = match self.boot_parameters.reset_vector {
+            ResetVector::Pointer(address) => {
+                let mut execution_start = [0u8; 8];
+                self.read(&mut execution_start, address);
+
+                u64::from_le_bytes(execution_start)
+            }
+
+            ResetVector::Address(address) => address,
+        };
 
-        // The first 8 bytes in memory is the address the CPU will start executing code
-        let mut execution_start = [0u8; 8];
-        self.read(&mut execution_start, 0);
+        self.registers = [0; REGISTER_COUNT];
 
-        let execution_start = u64::from_le_bytes(execution_start);
+        self.control_registers = [0; CONTROL_REGISTER_COUNT];
+        self.control_register_assign(ControlRegister::IdtLimit, IDT_LIMIT_RESET);
 
-        self.set_flag(CpuFlag::InterruptEnable, true);
+        self.float_registers = [0; FLOAT_REGISTER_COUNT];
+        self.float_control = float_control::FLOAT_CONTROL_RESET;
+
+        self.vector_registers = [0; VECTOR_REGISTER_COUNT];
+
+        self.performance_counters = PerformanceCounters::new();
+
+        for (id, value) in self.boot_parameters.initial_registers.clone() {
+            self.register_assign(id, value);
+        }
+
+        self.flags = self.boot_parameters.initial_flags;
 
         self.register_assign(RegisterId::Ip, execution_start);
-        self.register_assign(RegisterId::Sp, 0xffff);
+        self.register_assign(RegisterId::Sp, self.boot_parameters.initial_sp);
+    }
+
+    pub fn enable_hosted_mode(&mut self, system_call_host: Rc<RefCell<SystemCallHost>>) {
+        self.system_call_host = Some(system_call_host);
+    }
+
+    pub fn disable_instruction_cache(&mut self) {
+        self.instruction_cache = None;
+    }
+
+    // Compiles hot blocks to host code. A compiled block runs in a single call to `clock`
+    #[cfg(feature = "jit")]
+    pub fn enable_jit(&mut self) -> Result<(), String> {
+        self.jit = Some(jit::Jit::new()?);
+        Ok(())
+    }
+
+    pub fn set_debug_hook(&mut self, debug_hook: DebugHook) {
+        self.debug_hook = Some(debug_hook);
+    }
+
+    pub fn core_id(&self) -> u64 {
+        self.core_id
     }
 
-    pub fn halted(&self) -> bool {
-        self.halted
+    pub fn instruction_pointer(&self) -> u64 {
+        self.register(RegisterId::Ip)
+    }
+
+    // Delivers an interrupt from outside of the CPU. Returns false without delivering it while interrupts are
+    // disabled, so the caller can keep it pending
+    pub fn external_interrupt(&mut self, idt_entry: u8) -> bool {
+        if self.get_flag(CpuFlag::InterruptEnable) {
+            self.interrupt_request(idt_entry);
+            true
+        } else {
+            false
+        }
     }
 }
 
 impl Cpu {
-    fn fetch_byte(&mut self) -> u8 {
-        let mut byte: [u8; 1] = [0; 1];
+    fn count(&mut self, counter: PerformanceCounter) {
+        self.performance_counters.increment(counter);
+    }
+
+    fn control_register(&self, register: ControlRegister) -> u64 {
+        self.control_registers[register as usize]
+    }
+
+    fn control_register_assign(&mut self, register: ControlRegister, value: u64) {
+        self.control_registers[register as usize] = value;
+    }
+
+    fn mode_enabled(&self, mode_bit: u64) -> bool {
+        self.control_register(ControlRegister::Mode) & mode_bit != 0
+    }
+
+    fn is_supervisor(&self) -> bool {
+        !self.mode_enabled(MODE_USER)
+    }
+
+    // Raises ALIGNMENT_CHECK if alignment checking is enabled and the address isn't a multiple of `size`
+    fn check_alignment(&mut self, address: u64, size: u64) -> Result<(), u8> {
+        if self.mode_enabled(MODE_ALIGNMENT_CHECK) && !address.is_multiple_of(size) {
+            debug_println!("Unaligned access of {} bytes at {:#x}", size, address);
+            self.error_code = size;
+            self.fault_address = address;
+            Err(ALIGNMENT_CHECK)
+        } else {
+            Ok(())
+        }
+    }
+
+    // Sets the Overflow flag for arithmetic instructions that produce a result. With trap-on-overflow enabled an
+    // overflow raises the OVERFLOW trap once the instruction is done
+    fn set_overflow_flag(&mut self, overflow: bool) {
+        self.set_flag(CpuFlag::Overflow, overflow);
+
+        if overflow && self.mode_enabled(MODE_TRAP_ON_OVERFLOW) {
+            self.overflow_trap_pending = true;
+        }
+    }
+
+    // Takes the instruction from the instruction cache if it is there, otherwise gets ready to cache it
+    fn start_fetch(&mut self, address: u64) -> Fetch {
+        let instruction_cache = match &mut self.instruction_cache {
+            Some(instruction_cache) => instruction_cache,
+            None => return Fetch::Uncached,
+        };
+
+        let mut address_bus = self.address_bus.borrow_mut();
+        let page_version = address_bus.page_version(address >> PAGE_SIZE_SHIFT);
+
+        if let Some(fetch) = self.fetch.next_in_block(address, page_version) {
+            fetch
+        } else if let Some(block) = instruction_cache.lookup(address, page_version) {
+            Fetch::Cached { block, index: 0 }
+        } else if address_bus.is_cacheable(address, 1) {
+            Fetch::recording(address, page_version)
+        } else {
+            Fetch::Uncached
+        }
+    }
+
+    // Caches the instruction that just completed if it was recorded and all of its bytes are cacheable
+    fn finish_fetch(&mut self) {
+        if !matches!(self.fetch, Fetch::Recording { .. }) {
+            return;
+        }
+
+        if let (
+            Some(instruction_cache),
+            Fetch::Recording {
+                page_version,
+                instruction,
+            },
+        ) = (
+            &mut self.instruction_cache,
+            std::mem::replace(&mut self.fetch, Fetch::Uncached),
+        ) {
+            let address_bus = self.address_bus.borrow();
+
+            if address_bus.is_cacheable(self.instruction_start, instruction.length()) {
+                instruction_cache.insert(page_version, *instruction);
+            }
+        }
+    }
+
+    // Lets the debug hook handle the trap, or raises the DEBUG vector if there is no hook or it declines
+    fn debug_trap(&mut self, trap: DebugTrap) {
+        if let Some(debug_hook) = self.debug_hook.clone() {
+            if (debug_hook.borrow_mut())(self, trap) {
+                return;
+            }
+        }
+
+        self.error_code = trap as u64;
+        self.non_maskable_interrupt_request(DEBUG);
+    }
+
+    // Runs the compiled block starting at the current instruction if there is one and it has at most
+    // `max_instructions` instructions. Returns the number of instructions it executed, or None if the interpreter has
+    // to execute the instruction
+    #[cfg(feature = "jit")]
+    fn execute_compiled_block(&mut self, max_instructions: u64) -> Option<u64> {
+        // Both traps have to be checked after every instruction, which only the interpreter does
+        if self.get_flag(CpuFlag::Trap) || self.mode_enabled(MODE_TRAP_ON_OVERFLOW) {
+            return None;
+        }
+
+        let jit = self.jit.as_mut()?;
+
+        let mut address_bus = self.address_bus.borrow_mut();
+        let block = jit.block(self.instruction_start, &mut address_bus)?;
+        drop(address_bus);
+
+        if block.instruction_count > max_instructions {
+            return None;
+        }
+
+        let taken = block.run(&mut self.registers, &mut self.flags);
+        let (instruction_count, fetches) = (block.instruction_count, block.fetches);
+
+        // Every instruction of the block takes a cycle and fetches its bytes, the same as in the interpreter. The
+        // first cycle was already counted by `clock`
+        self.performance_counters
+            .add(PerformanceCounter::Cycles, instruction_count - 1);
+        self.performance_counters
+            .add(PerformanceCounter::BusAccesses, fetches);
+        self.performance_counters
+            .add(PerformanceCounter::RetiredInstructions, instruction_count);
+
+        if taken {
+            self.count(PerformanceCounter::TakenBranches);
+        }
+
+        Some(instruction_count)
+    }
+
+    // Jumps to an address, counting the jump as a taken branch
+    fn branch(&mut self, address: u64) {
+        self.count(PerformanceCounter::TakenBranches);
+        self.register_assign(RegisterId::Ip, address);
+    }
+}
+
+impl Cpu {
+    // Reads the next bytes of the instruction being executed and moves IP past them
+    fn fetch(&mut self, dest: &mut [u8]) {
+        let address = self.register(RegisterId::Ip);
+
+        let cached = match self.fetch {
+            Fetch::Uncached => false,
+            _ => self.fetch_cached(dest, address.wrapping_sub(self.instruction_start) as usize),
+        };
+
+        if !cached {
+            self.count(PerformanceCounter::BusAccesses);
+            self.address_bus.borrow_mut().read(dest, address);
+        }
+
+        self.register_add_assign(RegisterId::Ip, dest.len() as u64);
+    }
+
+    // Reads the bytes `offset` bytes into a cached instruction, or records them into the instruction being cached
+    // after reading them from the address bus. Returns false if they still have to be read from the address bus
+    fn fetch_cached(&mut self, dest: &mut [u8], offset: usize) -> bool {
+        if let Some(instruction) = self.fetch.cached_instruction() {
+            return instruction.read(dest, offset);
+        }
+
+        self.count(PerformanceCounter::BusAccesses);
         self.address_bus
             .borrow_mut()
-            .read(&mut byte, self.register(RegisterId::Ip));
+            .read(dest, self.register(RegisterId::Ip));
+        self.fetch.record(dest, offset);
+
+        true
+    }
+
+    // Returns the operands that were decoded from the bytes at IP when the instruction was cached, and moves IP past
+    // them. The decoders in instructions.rs use these instead of decoding the bytes again
+    fn cached_operands(&mut self) -> Option<DecodedOperands> {
+        let instruction = self.fetch.cached_instruction()?;
+        let offset = self
+            .register(RegisterId::Ip)
+            .wrapping_sub(self.instruction_start);
+        let (operands, length) = instruction.operands(offset)?;
+
+        self.register_add_assign(RegisterId::Ip, length);
+
+        Some(operands)
+    }
+
+    // Keeps the operands decoded from the bytes between `start` and IP with the instruction being cached
+    fn record_operands(&mut self, start: u64, operands: DecodedOperands) {
+        if let Fetch::Recording { .. } = self.fetch {
+            let end = self.register(RegisterId::Ip);
+
+            self.fetch.record_operands(
+                start.wrapping_sub(self.instruction_start),
+                end.wrapping_sub(self.instruction_start),
+                operands,
+            );
+        }
+    }
+
+    fn fetch_byte(&mut self) -> u8 {
+        let mut byte = [0u8; 1];
+        self.fetch(&mut byte);
 
-        self.register_add_assign(RegisterId::Ip, 1);
         u8::from_le_bytes(byte)
     }
 
     fn fetch_word(&mut self) -> u16 {
         let mut word_bytes = [0u8; 2];
-        self.address_bus
-            .borrow_mut()
-            .read(&mut word_bytes, self.register(RegisterId::Ip));
+        self.fetch(&mut word_bytes);
 
-        self.register_add_assign(RegisterId::Ip, 2);
         u16::from_le_bytes(word_bytes)
     }
 
     fn fetch_dword(&mut self) -> u32 {
         let mut dword_bytes = [0u8; 4];
-        self.address_bus
-            .borrow_mut()
-            .read(&mut dword_bytes, self.register(RegisterId::Ip));
+        self.fetch(&mut dword_bytes);
 
-        self.register_add_assign(RegisterId::Ip, 4);
         u32::from_le_bytes(dword_bytes)
     }
 
     fn fetch_qword(&mut self) -> u64 {
         let mut qword_bytes = [0u8; 8];
-        self.address_bus
-            .borrow_mut()
-            .read(&mut qword_bytes, self.register(RegisterId::Ip));
+        self.fetch(&mut qword_bytes);
 
-        self.register_add_assign(RegisterId::Ip, 8);
         u64::from_le_bytes(qword_bytes)
     }
 
@@ -206,23 +596,62 @@ impl Cpu {
     }
 
     fn pop_flags(&mut self) {
-        self.flags = self.pop_qword();
+        let flags = self.pop_qword();
+
+        // Users can't enable or disable interrupts
+        self.flags = if self.is_supervisor() {
+            flags
+        } else {
+            let interrupt_enable = 1 << CpuFlag::InterruptEnable as u64;
+            flags & !interrupt_enable | self.flags & interrupt_enable
+        };
     }
 
     // Wrapper functions to make reading and writing from the address more ergonomic
     fn write(&mut self, src: &[u8], address: u64) {
+        self.count(PerformanceCounter::BusAccesses);
         self.address_bus.borrow_mut().write(src, address);
     }
 
     fn read(&mut self, dest: &mut [u8], address: u64) {
+        self.count(PerformanceCounter::BusAccesses);
         self.address_bus.borrow_mut().read(dest, address);
     }
 
+    // Reads the value at an address and writes back whatever `operation` returns, without letting go of the
+    // address bus in between, so no other bus master can access memory until the write is done. Interrupts are only
+    // taken between instructions, so they can't get in between either. Returns the value that was read
+    fn atomic_read_modify_write(
+        &mut self,
+        address: u64,
+        size: Size,
+        operation: impl FnOnce(u64) -> Option<u64>,
+    ) -> u64 {
+        let mut address_bus = self.address_bus.borrow_mut();
+
+        let mut value = [0u8; 8];
+        address_bus.read(&mut value[..size as usize], address);
+        self.performance_counters
+            .increment(PerformanceCounter::BusAccesses);
+
+        let value = u64::from_le_bytes(value);
+
+        if let Some(new_value) = operation(value) {
+            address_bus.write(&new_value.to_le_bytes()[..size as usize], address);
+            self.performance_counters
+                .increment(PerformanceCounter::BusAccesses);
+        }
+
+        value
+    }
+
     fn port_bus_write(&mut self, port: u16, value: u64) {
+        self.count(PerformanceCounter::BusAccesses);
         self.port_bus.borrow_mut().write(port, value)
     }
 
     fn port_bus_read(&mut self, port: u16) -> u64 {
+        self.count(PerformanceCounter::BusAccesses);
         self.port_bus.borrow_mut().read(port)
     }
 }
@@ -244,6 +673,27 @@ impl Cpu {
         *self.register_mut(id) = value;
     }
 
+    // Reads the lowest `size` bytes of an operand. Bytes of a register above `size` are not cleared
+    fn operand(&mut self, operand: Operand, size: Size) -> u64 {
+        match operand {
+            Operand::Register(id) => self.register(id),
+            Operand::Memory(address) => {
+                let mut value = [0u8; 8];
+                self.read(&mut value[..size as usize], address);
+
+                u64::from_le_bytes(value)
+            }
+        }
+    }
+
+    // Writes the lowest `size` bytes of an operand, leaving the rest untouched
+    fn operand_assign_sized(&mut self, operand: Operand, value: u64, size: Size) {
+        match operand {
+            Operand::Register(id) => self.register_assign_sized(id, value, size),
+            Operand::Memory(address) => self.write(&value.to_le_bytes()[..size as usize], address),
+        }
+    }
+
     fn register_assign_sized(&mut self, id: RegisterId, value: u64, size: Size) {
         let idx: usize = id.to_index();
 
@@ -289,28 +739,111 @@ impl Cpu {
         self.flags &= !(1 << flag);
         self.flags |= (value as u64) << flag;
     }
+
+    fn condition_met(&self, condition: Condition) -> bool {
+        let negative = self.get_flag(CpuFlag::Negative);
+        let overflow = self.get_flag(CpuFlag::Overflow);
+        let zero = self.get_flag(CpuFlag::Zero);
+        let carry = self.get_flag(CpuFlag::Carry);
+
+        match condition {
+            Condition::Zero => zero,
+            Condition::NotZero => !zero,
+            Condition::Overflow => overflow,
+            Condition::NotOverflow => !overflow,
+            Condition::Negative => negative,
+            Condition::NotNegative => !negative,
+            Condition::Carry => carry,
+            Condition::NotCarry => !carry,
+            Condition::BelowOrEqual => carry || zero,
+            Condition::Above => !carry && !zero,
+            Condition::Less => negative != overflow,
+            Condition::GreaterOrEqual => negative == overflow,
+            Condition::LessOrEqual => zero || negative != overflow,
+            Condition::Greater => !zero && negative == overflow,
+        }
+    }
 }
 
 impl Cpu {
+    // The features that are enabled, including the ones that depend on how the emulator is run
+    fn features(&self) -> u64 {
+        let mut features = self.boot_parameters.features & features::SUPPORTED_FEATURES;
+
+        if self.system_call_host.is_some() {
+            features |= features::HOSTED_SYSTEM_CALLS;
+        }
+
+        features
+    }
+
+    // Returns the lookup entry of an opcode, or None if the opcode is invalid because it isn't assigned or its
+    // feature is disabled
+    fn lookup_instruction(&self, opcode: u8) -> Option<&'static LookupEntry> {
+        let entry: &'static LookupEntry = &LOOKUP_TABLE[opcode as usize];
+
+        if entry.callback.is_some()
+            && self.features() & entry.required_feature == entry.required_feature
+        {
+            Some(entry)
+        } else {
+            None
+        }
+    }
+
     fn execute_opcode(&mut self, opcode: u8) {
-        if let Some(callback) = LOOKUP_TABLE[opcode as usize].callback {
+        if let Some(entry) = self.lookup_instruction(opcode) {
             debug_println!(
                 "Executing instruction '{}' {:#x}",
-                LOOKUP_TABLE[opcode as usize].instruction,
+                entry.instruction,
                 opcode
             );
-            if let Err(idt_entry) = callback(self) {
-                self.non_maskable_interrupt_request(idt_entry);
-            }
+            self.fetch.record_entry(entry);
+            self.execute_instruction(entry);
         } else {
             debug_println!(
                 "Invalid instruction {:#x} at {:#x}",
                 opcode,
                 self.register(RegisterId::Ip) - 1
             );
+            self.register_assign(RegisterId::Ip, self.instruction_start);
             self.non_maskable_interrupt_request(INVALID_INSTRUCTION);
         }
     }
+
+    // Runs the instruction whose opcode was just fetched
+    fn execute_instruction(&mut self, entry: &'static LookupEntry) {
+        let callback = match entry.callback {
+            Some(callback) => callback,
+            None => return,
+        };
+
+        let single_step = self.get_flag(CpuFlag::Trap);
+        let result = callback(self);
+        let overflow_trap = std::mem::take(&mut self.overflow_trap_pending);
+
+        match result {
+            Ok(()) => {
+                self.finish_fetch();
+                self.count(PerformanceCounter::RetiredInstructions);
+
+                // The trap returns to the next instruction, after the result has been written
+                if overflow_trap {
+                    self.non_maskable_interrupt_request(OVERFLOW);
+                }
+
+                // BRK already stopped at the same address
+                if single_step && !entry.raises_debug_trap {
+                    self.debug_trap(DebugTrap::SingleStep);
+                }
+            }
+            // Faults return to the start of the faulting instruction, so the handler can fix the cause and retry it
+            Err(idt_entry) => {
+                self.register_assign(RegisterId::Ip, self.instruction_start);
+                self.non_maskable_interrupt_request(idt_entry);
+            }
+        }
+    }
 }
 
 impl Cpu {
@@ -333,10 +866,17 @@ impl Cpu {
     }
 
     fn interrupt_handler(&mut self, idt_entry: u8) {
+        self.count(PerformanceCounter::Interrupts);
+
         let sizeof_idt_entry: u64 = 8;
 
-        if self.idt != 0 {
-            let idt_entry_address = self.idt + (idt_entry as u64 * sizeof_idt_entry);
+        let idt_base = self.control_register(ControlRegister::IdtBase);
+
+        if idt_entry as u64 >= self.control_register(ControlRegister::IdtLimit) {
+            debug_println!("IDT entry {} is past the end of the IDT", idt_entry);
+            self.reset();
+        } else if idt_base != 0 {
+            let idt_entry_address = idt_base + (idt_entry as u64 * sizeof_idt_entry);
 
             let mut handler_address = [0u8; 8];
             self.read(&mut handler_address, idt_entry_address);
@@ -344,8 +884,48 @@ impl Cpu {
             let handler_address = u64::from_le_bytes(handler_address);
 
             if handler_address != 0 {
+                // An interrupt wakes a halted CPU
+                self.halted = false;
+
+                let error_code = std::mem::take(&mut self.error_code);
+                let fault_address = std::mem::take(&mut self.fault_address);
+
+                let sp = self.register(RegisterId::Sp);
+                let mode = self.control_register(ControlRegister::Mode);
+
+                let uses_interrupt_stack = self
+                    .control_register(ControlRegister::InterruptStackVectors)
+                    .checked_shr(idt_entry as u32)
+                    .unwrap_or(0)
+                    & 1
+                    == 1;
+
+                if uses_interrupt_stack {
+                    self.register_assign(
+                        RegisterId::Sp,
+                        self.control_register(ControlRegister::InterruptStack),
+                    );
+                } else if !self.is_supervisor() {
+                    self.register_assign(
+                        RegisterId::Sp,
+                        self.control_register(ControlRegister::KernelStack),
+                    );
+                }
+
+                // Handlers run as the supervisor
+                self.control_register_assign(ControlRegister::Mode, mode & !MODE_USER);
+
+                self.push_qword(sp);
+                self.push_qword(mode);
                 self.push_flags();
                 self.push_qword(self.register(RegisterId::Ip));
+                self.push_qword(error_code);
+                self.push_qword(fault_address);
+
+                // Handlers start with interrupts disabled and without single stepping. RETI restores the flags from
+                // before the interrupt
+                self.set_flag(CpuFlag::InterruptEnable, false);
+                self.set_flag(CpuFlag::Trap, false);
 
                 self.register_assign(RegisterId::Ip, handler_address);
             } else {
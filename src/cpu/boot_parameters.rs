@@ -0,0 +1,44 @@
+use super::features::SUPPORTED_FEATURES;
+use super::{CpuFlag, RegisterId};
+
+/// Where the CPU gets the address it starts executing from after a reset
+#[derive(Debug, Clone, Copy)]
+pub enum ResetVector {
+    /// The entry point is the qword stored in memory at this address
+    Pointer(u64),
+    /// The entry point is this address
+    Address(u64),
+}
+
+/// The state the CPU is put into every time it is reset
+#[derive(Debug, Clone)]
+pub struct BootParameters {
+    pub reset_vector: ResetVector,
+    pub initial_sp: u64,
+    pub initial_flags: u64,
+    pub initial_registers: Vec<(RegisterId, u64)>,
+    /// The features reported by FEAT and CPUID. The instructions of features that aren't set are invalid
+    pub features: u64,
+}
+
+impl BootParameters {
+    /// Sets the initial value of a register, replacing any value previously given for it
+    pub fn set_initial_register(&mut self, id: RegisterId, value: u64) {
+        self.initial_registers
+            .retain(|(existing, _)| *existing != id);
+        self.initial_registers.push((id, value));
+    }
+}
+
+impl Default for BootParameters {
+    fn default() -> Self {
+        // The defaults match the original behaviour of reading the entry point from the first 8 bytes of memory
+        Self {
+            reset_vector: ResetVector::Pointer(0),
+            initial_sp: 0xffff,
+            initial_flags: 1 << CpuFlag::InterruptEnable as u64,
+            initial_registers: Vec::new(),
+            features: SUPPORTED_FEATURES,
+        }
+    }
+}
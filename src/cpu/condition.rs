@@ -0,0 +1,54 @@
+/// The conditions tested by the conditional jumps, CMOV and SET.
+/// The value of each variant is how it is encoded in the condition byte of CMOV and SET
+#[derive(Debug, Clone, Copy)]
+pub enum Condition {
+    Zero = 0,
+    NotZero = 1,
+    Overflow = 2,
+    NotOverflow = 3,
+    Negative = 4,
+    NotNegative = 5,
+    Carry = 6,
+    NotCarry = 7,
+    BelowOrEqual = 8,
+    Above = 9,
+    Less = 10,
+    GreaterOrEqual = 11,
+    LessOrEqual = 12,
+    Greater = 13,
+}
+
+impl Condition {
+    pub fn from_number(number: u8) -> Option<Self> {
+        match number {
+            0 => Some(Self::Zero),
+            1 => Some(Self::NotZero),
+            2 => Some(Self::Overflow),
+            3 => Some(Self::NotOverflow),
+            4 => Some(Self::Negative),
+            5 => Some(Self::NotNegative),
+            6 => Some(Self::Carry),
+            7 => Some(Self::NotCarry),
+            8 => Some(Self::BelowOrEqual),
+            9 => Some(Self::Above),
+            10 => Some(Self::Less),
+            11 => Some(Self::GreaterOrEqual),
+            12 => Some(Self::LessOrEqual),
+            13 => Some(Self::Greater),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn numbers_match_the_variants() {
+        for number in 0..=u8::MAX {
+            let expected = (number <= Condition::Greater as u8).then_some(number);
+            assert_eq!(Condition::from_number(number).map(|c| c as u8), expected);
+        }
+    }
+}
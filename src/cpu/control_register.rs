@@ -0,0 +1,51 @@
+// Registers holding the mode the CPU runs in. They are read with MFCR and written with MTCR, which can only be done
+// by the supervisor
+#[derive(Debug, Clone, Copy)]
+pub enum ControlRegister {
+    // Address of the interrupt descriptor table. 0 means there is no table, and every interrupt resets the CPU
+    IdtBase = 0,
+    // Number of entries in the interrupt descriptor table. Interrupts past the end reset the CPU
+    IdtLimit = 1,
+    // The MODE_* bits below
+    Mode = 2,
+    // Loaded into SP when an interrupt is taken while running as a user
+    KernelStack = 3,
+    // Loaded into SP when one of the vectors in InterruptStackVectors is taken, whatever the privilege, so
+    // critical handlers never run on a stack that could be corrupted
+    InterruptStack = 4,
+    // Bit n makes vector n switch to InterruptStack. Only vectors 0-63 can have a dedicated stack
+    InterruptStackVectors = 5,
+}
+
+pub const CONTROL_REGISTER_COUNT: usize = 6;
+
+impl ControlRegister {
+    pub fn from_number(number: u8) -> Option<Self> {
+        match number {
+            0 => Some(Self::IdtBase),
+            1 => Some(Self::IdtLimit),
+            2 => Some(Self::Mode),
+            3 => Some(Self::KernelStack),
+            4 => Some(Self::InterruptStack),
+            5 => Some(Self::InterruptStackVectors),
+            _ => None,
+        }
+    }
+}
+
+// Setting this bit raises GENERAL_PROTECTION_FAULT unless the MEMORY_MANAGEMENT_UNIT feature is reported, which
+// it never is yet
+pub const MODE_PAGING_ENABLE: u64 = 1 << 0;
+// Set while running as a user, which makes the privileged instructions raise GENERAL_PROTECTION_FAULT and keeps
+// POPF from changing the InterruptEnable flag
+pub const MODE_USER: u64 = 1 << 1;
+// Raises ALIGNMENT_CHECK when data is accessed at an address that isn't a multiple of its size
+pub const MODE_ALIGNMENT_CHECK: u64 = 1 << 2;
+// Raises the OVERFLOW trap after an arithmetic, multiply or shift instruction that sets the Overflow flag. The
+// comparisons CMP, MCMP, CMPXCHG and FCMP only report their result in the flags and never raise it
+pub const MODE_TRAP_ON_OVERFLOW: u64 = 1 << 3;
+
+pub const MODE_WRITABLE_BITS: u64 = MODE_USER | MODE_ALIGNMENT_CHECK | MODE_TRAP_ON_OVERFLOW;
+
+// Every vector is covered by the table after a reset
+pub const IDT_LIMIT_RESET: u64 = 256;
@@ -0,0 +1,16 @@
+use super::Cpu;
+
+use std::{cell::RefCell, rc::Rc};
+
+// Why the DEBUG vector was raised. The value is pushed as the error code of the interrupt frame
+#[derive(Debug, Clone, Copy)]
+pub enum DebugTrap {
+    // BRK was executed. The return address is the instruction after it
+    Breakpoint = 1,
+    // The Trap flag was set when the instruction before the return address started
+    SingleStep = 2,
+}
+
+/// Called instead of raising the DEBUG vector, with the CPU stopped after the instruction that trapped. Returning
+/// true tells the CPU the host handled the trap, and the guest continues without seeing it
+pub type DebugHook = Rc<RefCell<dyn FnMut(&Cpu, DebugTrap) -> bool>>;
@@ -0,0 +1,54 @@
+// Bits of the feature bitmap guests read with FEAT or CPUID to find out what the CPU supports.
+// Every feature except HOSTED_SYSTEM_CALLS can be disabled in the machine configuration, which makes its
+// instructions invalid
+
+pub const FLOATING_POINT_UNIT: u64 = 1 << 0;
+pub const VECTOR_UNIT: u64 = 1 << 1;
+pub const REGISTER_EXTENSION: u64 = 1 << 2;
+pub const MEMORY_OPERANDS: u64 = 1 << 3;
+pub const BLOCK_MEMORY: u64 = 1 << 4;
+pub const ATOMICS: u64 = 1 << 5;
+pub const PERFORMANCE_COUNTERS: u64 = 1 << 7;
+
+// Set when SYSCALL is serviced by the emulator instead of raising the SYSTEM_CALL interrupt
+pub const HOSTED_SYSTEM_CALLS: u64 = 1 << 6;
+
+// Never set, since there is no memory management unit yet. Setting MODE_PAGING_ENABLE raises
+// GENERAL_PROTECTION_FAULT instead. Reported so guests can tell that paging is missing
+pub const MEMORY_MANAGEMENT_UNIT: u64 = 1 << 8;
+
+pub const SUPPORTED_FEATURES: u64 = FLOATING_POINT_UNIT
+    | VECTOR_UNIT
+    | REGISTER_EXTENSION
+    | MEMORY_OPERANDS
+    | BLOCK_MEMORY
+    | ATOMICS
+    | PERFORMANCE_COUNTERS;
+
+// The names of the features that can be disabled, as used in config files and on the command line
+const FEATURE_NAMES: [(&str, u64); 7] = [
+    ("fpu", FLOATING_POINT_UNIT),
+    ("vector", VECTOR_UNIT),
+    ("register-extension", REGISTER_EXTENSION),
+    ("memory-operands", MEMORY_OPERANDS),
+    ("block-memory", BLOCK_MEMORY),
+    ("atomics", ATOMICS),
+    ("performance-counters", PERFORMANCE_COUNTERS),
+];
+
+pub fn parse_feature(name: &str) -> Option<u64> {
+    FEATURE_NAMES
+        .iter()
+        .find(|(feature_name, _)| *feature_name == name)
+        .map(|&(_, feature)| feature)
+}
+
+// The CPUID leaf that reports the highest leaf and the vendor string
+pub const CPUID_VENDOR_LEAF: u64 = 0;
+// The CPUID leaf that reports the version, the feature bitmap and the number of registers
+pub const CPUID_FEATURE_LEAF: u64 = 1;
+// The CPUID leaf that reports the ID of the core and the number of cores in the machine
+pub const CPUID_TOPOLOGY_LEAF: u64 = 2;
+
+// Reported by CPUID in X1 and X2, 8 bytes each in little endian
+pub const CPUID_VENDOR: &[u8; 16] = b"RustCpuEmulator\0";
@@ -0,0 +1,12 @@
+// Bits of the floating point control and status register.
+// The status bits are sticky, they are set by any instruction that raises the exception and only cleared by FSCSR.
+// An exception whose mask bit is clear raises FLOATING_POINT_EXCEPTION instead of producing a result
+
+pub const INVALID_OPERATION_STATUS: u64 = 1 << 0;
+pub const DIVIDE_BY_ZERO_STATUS: u64 = 1 << 1;
+
+pub const INVALID_OPERATION_MASK: u64 = 1 << 8;
+pub const DIVIDE_BY_ZERO_MASK: u64 = 1 << 9;
+
+// Every exception is masked after a reset
+pub const FLOAT_CONTROL_RESET: u64 = INVALID_OPERATION_MASK | DIVIDE_BY_ZERO_MASK;
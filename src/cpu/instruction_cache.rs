@@ -0,0 +1,549 @@
+// Keeps the decoded form of instructions that were already executed, so executing them again doesn't look up the
+// opcode, go through the address bus for every fetch or decode the operands again.
+//
+// Instructions are cached in blocks, which are runs of instructions that were executed one after the other in the
+// same page. A block is looked up once by its first address, and each of its instructions is then used as long as
+// execution continues in order. Blocks are grouped by page of the address space along with the version of the page
+// from the address bus at the time they were fetched. Any write to a page through the address bus, by any core or by
+// the host, changes its version, which drops the cached blocks of the page the next time one of them is used. Only
+// instructions that fit in a page of a cacheable device are cached
+
+use super::instruction_lookup::LookupEntry;
+use super::register_id::RegisterId;
+use super::size::Size;
+use crate::address_bus::{AddressMap, PAGE_SIZE_SHIFT};
+
+use std::rc::Rc;
+
+// Longer instructions are always fetched from the address bus
+pub const MAX_INSTRUCTION_LENGTH: usize = 32;
+
+// Instructions decode at most one operand byte and one address through the shared decoders. Any others are decoded
+// from the cached bytes every time
+const MAX_DECODED_OPERANDS: usize = 2;
+
+// The source operand of instructions in the form of "dst, src"
+#[derive(Clone, Copy)]
+pub enum Source {
+    Register(RegisterId),
+    Immediate(u64),
+}
+
+// Operands as returned by the decoders in instructions.rs, without the values of the registers they name
+#[derive(Clone, Copy)]
+pub enum DecodedOperands {
+    Binary {
+        dst: RegisterId,
+        src: Source,
+        size: Size,
+    },
+    Address(DecodedAddress),
+}
+
+#[derive(Clone, Copy)]
+pub struct DecodedAddress {
+    pub base: Option<RegisterId>,
+    pub index: Option<RegisterId>,
+    pub index_scale: u8,
+    pub ip_relative: bool,
+    pub displacement: u64,
+}
+
+// Operands decoded from the bytes at `offset` into the instruction
+#[derive(Clone, Copy)]
+struct OperandsAt {
+    offset: u8,
+    length: u8,
+    operands: DecodedOperands,
+}
+
+#[derive(Clone)]
+pub struct DecodedInstruction {
+    address: u64,
+    entry: Option<&'static LookupEntry>,
+    bytes: [u8; MAX_INSTRUCTION_LENGTH],
+    length: usize,
+    // Reads of the address bus it took to fetch the instruction, which are still counted as bus accesses
+    fetches: u64,
+    operands: [Option<OperandsAt>; MAX_DECODED_OPERANDS],
+}
+
+impl DecodedInstruction {
+    fn new(address: u64) -> Self {
+        Self {
+            address,
+            entry: None,
+            bytes: [0; MAX_INSTRUCTION_LENGTH],
+            length: 0,
+            fetches: 0,
+            operands: [None; MAX_DECODED_OPERANDS],
+        }
+    }
+
+    pub fn entry(&self) -> &'static LookupEntry {
+        self.entry
+            .expect("Unrecoverable error. Cached instruction has no lookup entry")
+    }
+
+    pub fn length(&self) -> u64 {
+        self.length as u64
+    }
+
+    pub fn fetches(&self) -> u64 {
+        self.fetches
+    }
+
+    // Copies `dest.len()` bytes starting `offset` bytes into the instruction. Returns false if they weren't cached
+    pub fn read(&self, dest: &mut [u8], offset: usize) -> bool {
+        match self.bytes[..self.length].get(offset..offset + dest.len()) {
+            Some(bytes) => {
+                dest.copy_from_slice(bytes);
+                true
+            }
+            None => false,
+        }
+    }
+
+    // Returns the operands decoded from the bytes at the offset and how many bytes they take up
+    pub fn operands(&self, offset: u64) -> Option<(DecodedOperands, u64)> {
+        self.operands
+            .iter()
+            .flatten()
+            .find(|operands| operands.offset as u64 == offset)
+            .map(|operands| (operands.operands, operands.length as u64))
+    }
+
+    // Adds bytes fetched `offset` bytes into the instruction. Returns false if they don't directly follow the bytes
+    // recorded so far, or the instruction gets too long to cache
+    fn record(&mut self, src: &[u8], offset: usize) -> bool {
+        if offset != self.length || offset + src.len() > MAX_INSTRUCTION_LENGTH {
+            return false;
+        }
+
+        self.bytes[offset..offset + src.len()].copy_from_slice(src);
+        self.length += src.len();
+        self.fetches += 1;
+
+        true
+    }
+
+    // Keeps the operands decoded from the bytes between the offsets, if there is room for them
+    fn record_operands(&mut self, offset: u64, end: u64, operands: DecodedOperands) {
+        if let Some(slot) = self.operands.iter_mut().find(|slot| slot.is_none()) {
+            *slot = Some(OperandsAt {
+                offset: offset as u8,
+                length: (end - offset) as u8,
+                operands,
+            });
+        }
+    }
+}
+
+pub struct CachedBlock {
+    page_version: u64,
+    instructions: Vec<DecodedInstruction>,
+}
+
+// Where the instruction being executed comes from
+pub enum Fetch {
+    // The address bus. The instruction won't be cached
+    Uncached,
+    // The instruction cache
+    Cached {
+        block: Rc<CachedBlock>,
+        index: usize,
+    },
+    // The address bus. The bytes and the decoded operands are recorded to cache the instruction once it completes.
+    // Boxed to keep the other variants small
+    Recording {
+        page_version: u64,
+        instruction: Box<DecodedInstruction>,
+    },
+}
+
+impl Fetch {
+    pub fn recording(address: u64, page_version: u64) -> Self {
+        Self::Recording {
+            page_version,
+            instruction: Box::new(DecodedInstruction::new(address)),
+        }
+    }
+
+    pub fn cached_instruction(&self) -> Option<&DecodedInstruction> {
+        match self {
+            Self::Cached { block, index } => Some(&block.instructions[*index]),
+            _ => None,
+        }
+    }
+
+    // Takes the instruction after the cached one that was just executed if it starts at the address, and the page
+    // wasn't written since the block was cached
+    pub fn next_in_block(&self, address: u64, page_version: u64) -> Option<Self> {
+        match self {
+            Self::Cached { block, index }
+                if block.page_version == page_version
+                    && block
+                        .instructions
+                        .get(index + 1)
+                        .is_some_and(|instruction| instruction.address == address) =>
+            {
+                Some(Self::Cached {
+                    block: block.clone(),
+                    index: index + 1,
+                })
+            }
+            _ => None,
+        }
+    }
+
+    // Records bytes fetched from the address bus, giving up on caching the instruction if they can't be recorded
+    pub fn record(&mut self, src: &[u8], offset: usize) {
+        if let Self::Recording { instruction, .. } = self {
+            if !instruction.record(src, offset) {
+                *self = Self::Uncached;
+            }
+        }
+    }
+
+    pub fn record_entry(&mut self, entry: &'static LookupEntry) {
+        if let Self::Recording { instruction, .. } = self {
+            instruction.entry = Some(entry);
+        }
+    }
+
+    pub fn record_operands(&mut self, offset: u64, end: u64, operands: DecodedOperands) {
+        if let Self::Recording { instruction, .. } = self {
+            instruction.record_operands(offset, end, operands);
+        }
+    }
+}
+
+struct CachedPage {
+    version: u64,
+    blocks: AddressMap<Rc<CachedBlock>>,
+}
+
+// The block the instructions being recorded are added to, until execution leaves it
+struct RecordingBlock {
+    page_version: u64,
+    instructions: Vec<DecodedInstruction>,
+}
+
+impl RecordingBlock {
+    fn continues_at(&self, address: u64, page_version: u64) -> bool {
+        let last = &self.instructions[self.instructions.len() - 1];
+
+        self.page_version == page_version
+            && last.address.wrapping_add(last.length as u64) == address
+            && last.address >> PAGE_SIZE_SHIFT == address >> PAGE_SIZE_SHIFT
+    }
+}
+
+pub struct InstructionCache {
+    pages: AddressMap<CachedPage>,
+    recording: Option<RecordingBlock>,
+}
+
+impl InstructionCache {
+    pub fn new() -> Self {
+        Self {
+            pages: AddressMap::default(),
+            recording: None,
+        }
+    }
+
+    // Returns the block starting at the address if it was cached while the page had the current version
+    pub fn lookup(&mut self, address: u64, page_version: u64) -> Option<Rc<CachedBlock>> {
+        let page = self.pages.get_mut(&(address >> PAGE_SIZE_SHIFT))?;
+
+        if page.version != page_version {
+            page.version = page_version;
+            page.blocks.clear();
+            return None;
+        }
+
+        let block = page.blocks.get(&address)?.clone();
+
+        // Execution reached a block that is already cached, so the one being recorded ends here
+        self.finish_block();
+
+        Some(block)
+    }
+
+    // Caches an instruction that completed, unless it crosses into the next page. It is added to the block being
+    // recorded if it directly follows it, otherwise it starts a new block
+    pub fn insert(&mut self, page_version: u64, instruction: DecodedInstruction) {
+        if instruction.length == 0 || instruction.entry.is_none() {
+            return;
+        }
+
+        let page_number = instruction.address >> PAGE_SIZE_SHIFT;
+        let last_byte = instruction
+            .address
+            .wrapping_add(instruction.length as u64 - 1);
+
+        if last_byte >> PAGE_SIZE_SHIFT != page_number {
+            return;
+        }
+
+        match &mut self.recording {
+            Some(block) if block.continues_at(instruction.address, page_version) => {
+                block.instructions.push(instruction)
+            }
+            _ => {
+                self.finish_block();
+                self.recording = Some(RecordingBlock {
+                    page_version,
+                    instructions: vec![instruction],
+                });
+            }
+        }
+    }
+
+    // Caches the block being recorded
+    fn finish_block(&mut self) {
+        let block = match self.recording.take() {
+            Some(block) => block,
+            None => return,
+        };
+
+        let address = block.instructions[0].address;
+        let page = self
+            .pages
+            .entry(address >> PAGE_SIZE_SHIFT)
+            .or_insert_with(|| CachedPage {
+                version: block.page_version,
+                blocks: AddressMap::default(),
+            });
+
+        // The page was written since the block was fetched
+        if page.version != block.page_version {
+            return;
+        }
+
+        page.blocks.insert(
+            address,
+            Rc::new(CachedBlock {
+                page_version: block.page_version,
+                instructions: block.instructions,
+            }),
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::test_cpu::{test_cpu, CODE_ADDRESS, MEMORY_SIZE};
+    use super::super::{BootParameters, Cpu, ResetVector};
+    use super::*;
+    use crate::address_bus::AddressBus;
+    use crate::memory::Memory;
+    use crate::port_bus::PortBus;
+    use crate::AddressBusDevice;
+
+    use std::cell::RefCell;
+    use std::time::Instant;
+
+    // In a different page from the code, since writing to the page of the code drops its blocks
+    const DATA_ADDRESS: u64 = 0x3000;
+
+    fn operands(size: Size, dst: RegisterId, src: Option<RegisterId>) -> u8 {
+        let size_bits = (size as u8).trailing_zeros() as u8;
+
+        size_bits << 6 | (dst as u8) << 3 | src.map_or(0, |src| src as u8)
+    }
+
+    // A loop that uses each of the decoders the cache keeps the operands of: register and immediate operands, an
+    // EXT prefix, and a MEMD prefixed ADD to an IP-relative address
+    fn decoder_loop() -> Vec<u8> {
+        // ADD X1, X2
+        let mut code = vec![
+            0x03,
+            operands(Size::Eight, RegisterId::X1, Some(RegisterId::X2)),
+        ];
+
+        // ADD X2, 3
+        code.extend([0x03, operands(Size::Eight, RegisterId::X2, None)]);
+        code.extend(3u64.to_le_bytes());
+
+        // EXT, then ADD X5, X1. X5 is 8, so the destination field gets an extension bit of 1
+        code.extend([0x02, 0b01 << 2, 0x03]);
+        code.push(0b11 << 6 | (RegisterId::X1 as u8));
+
+        // MEMD ADD [IP + disp32], X1
+        code.extend([
+            0x12,
+            0x03,
+            0b11 << 6 | (RegisterId::X1 as u8),
+            0b11 << 6,
+            0b101,
+        ]);
+        let end = CODE_ADDRESS + code.len() as u64 + 4;
+        code.extend((DATA_ADDRESS.wrapping_sub(end) as u32).to_le_bytes());
+
+        // JMP CODE_ADDRESS
+        code.extend([0x05, 0x00]);
+        code.extend(CODE_ADDRESS.to_le_bytes());
+
+        code
+    }
+
+    fn state(cpu: &mut Cpu) -> (Vec<u64>, u64, u64, Vec<Option<u64>>) {
+        let mut data = [0u8; 8];
+        cpu.read(&mut data, DATA_ADDRESS);
+
+        let counters = (0..5)
+            .map(|index| cpu.performance_counters.read(index))
+            .collect();
+
+        (
+            cpu.registers.to_vec(),
+            cpu.flags,
+            u64::from_le_bytes(data),
+            counters,
+        )
+    }
+
+    #[test]
+    fn cached_instructions_match_the_address_bus() {
+        let code = decoder_loop();
+
+        let mut cached = test_cpu(&code);
+        cached.step(500);
+
+        let mut uncached = test_cpu(&code);
+        uncached.disable_instruction_cache();
+        uncached.step(500);
+
+        assert!(cached.register(RegisterId::X1) != 0);
+        assert_eq!(state(&mut cached), state(&mut uncached));
+    }
+
+    #[test]
+    fn writes_to_a_cached_page_drop_its_blocks() {
+        // ADD X1, 1 followed by JMP CODE_ADDRESS
+        let mut code = vec![0x03, operands(Size::Eight, RegisterId::X1, None)];
+        code.extend(1u64.to_le_bytes());
+        code.extend([0x05, 0x00]);
+        code.extend(CODE_ADDRESS.to_le_bytes());
+
+        let mut cpu = test_cpu(&code);
+        cpu.step(20);
+        assert_eq!(cpu.register(RegisterId::X1), 10);
+
+        // The host patches the immediate to 16 while the loop is cached
+        cpu.write(&16u64.to_le_bytes(), CODE_ADDRESS + 2);
+        cpu.step(2);
+        assert_eq!(cpu.register(RegisterId::X1), 26);
+    }
+
+    // Returns the number of times it has been read, so every fetch from it gives a new value
+    struct Counter {
+        reads: u8,
+    }
+
+    impl AddressBusDevice for Counter {
+        fn write(&mut self, _src: &[u8], _address: u64, _offset: u64) {}
+
+        fn read(&mut self, dest: &mut [u8], _address: u64, _offset: u64) {
+            self.reads += 1;
+            dest.fill(0);
+            dest[0] = self.reads;
+        }
+    }
+
+    #[test]
+    fn instructions_running_into_an_uncacheable_device_are_not_cached() {
+        // The byte after the first 16 bytes of code is the counter
+        let counter_address = CODE_ADDRESS + 0x10;
+        let address_bus = Rc::new(RefCell::new(AddressBus::new()));
+        let mut bus = address_bus.borrow_mut();
+
+        bus.add_entry(0, counter_address, Memory::new(counter_address))
+            .unwrap();
+        bus.add_entry(counter_address, 1, Counter { reads: 0 })
+            .unwrap();
+        bus.add_entry(
+            counter_address + 1,
+            MEMORY_SIZE - counter_address - 1,
+            Memory::new(MEMORY_SIZE - counter_address - 1),
+        )
+        .unwrap();
+
+        // JMP to an ADD X1, imm8 whose immediate is the counter, which is followed by a JMP back to the ADD. Only
+        // the first byte of the ADD is in a cacheable device
+        let add_address = counter_address - 2;
+
+        let mut jump = vec![0x05, 0x00];
+        jump.extend(add_address.to_le_bytes());
+
+        bus.write(&jump, CODE_ADDRESS);
+        bus.write(
+            &[0x03, operands(Size::One, RegisterId::X1, None)],
+            add_address,
+        );
+        bus.write(&jump, counter_address + 1);
+        drop(bus);
+
+        let boot_parameters = BootParameters {
+            reset_vector: ResetVector::Address(CODE_ADDRESS),
+            initial_sp: MEMORY_SIZE,
+            ..Default::default()
+        };
+        let mut cpu = Cpu::new(
+            address_bus,
+            Rc::new(RefCell::new(PortBus::new())),
+            boot_parameters,
+            0,
+            1,
+        );
+
+        cpu.step(1 + 2 * 3);
+        assert_eq!(cpu.register(RegisterId::X1), 1 + 2 + 3);
+    }
+
+    // Not run by default. Run with
+    //   cargo test --release instruction_cache_speedup -- --ignored --nocapture
+    // to compare a tight loop with and without the cache
+    #[test]
+    #[ignore]
+    fn instruction_cache_speedup() {
+        // SUB X3, 1 and JNZ back to the start of the loop, after the body of decoder_loop without its JMP
+        let mut code = decoder_loop();
+        code.truncate(code.len() - 10);
+        code.extend([0x13, operands(Size::Eight, RegisterId::X3, None)]);
+        code.extend(1u64.to_le_bytes());
+        code.extend([0x25, 0x00]);
+        code.extend(CODE_ADDRESS.to_le_bytes());
+
+        let iterations = 1_000_000;
+        let clocks = iterations * 6;
+
+        let run = |cache: bool| {
+            let mut cpu = test_cpu(&code);
+            cpu.register_assign(RegisterId::X3, iterations as u64);
+
+            if !cache {
+                cpu.disable_instruction_cache();
+            }
+
+            let start = Instant::now();
+            cpu.step(clocks);
+            let elapsed = start.elapsed();
+
+            assert_eq!(cpu.register(RegisterId::X3), 0);
+            elapsed
+        };
+
+        let uncached = run(false);
+        let cached = run(true);
+
+        println!(
+            "{} instructions: {:?} without the cache, {:?} with it ({:.2}x)",
+            clocks,
+            uncached,
+            cached,
+            uncached.as_secs_f64() / cached.as_secs_f64()
+        );
+    }
+}
@@ -1,3 +1,4 @@
+use super::features::*;
 use super::{Cpu, InstructionResult};
 
 use crate::lazy_static::lazy_static;
@@ -5,6 +6,10 @@ use crate::lazy_static::lazy_static;
 pub struct LookupEntry {
     pub instruction: &'static str,
     pub callback: Option<fn(&mut Cpu) -> InstructionResult>,
+    /// The instruction is invalid unless this feature is enabled. 0 if it is always available
+    pub required_feature: u64,
+    /// The instruction raises the DEBUG trap itself, so the Trap flag doesn't raise another one after it
+    pub raises_debug_trap: bool,
 }
 
 impl LookupEntry {
@@ -15,6 +20,22 @@ impl LookupEntry {
         Self {
             instruction,
             callback,
+            required_feature: 0,
+            raises_debug_trap: false,
+        }
+    }
+
+    pub fn requires(self, required_feature: u64) -> Self {
+        Self {
+            required_feature,
+            ..self
+        }
+    }
+
+    pub fn raises_debug_trap(self) -> Self {
+        Self {
+            raises_debug_trap: true,
+            ..self
         }
     }
 }
@@ -22,50 +43,50 @@ lazy_static! {
     pub static ref LOOKUP_TABLE: [LookupEntry; 256] = [
         LookupEntry::new("HLT", Some(Cpu::HLT)), // 0x00
         LookupEntry::new("MOV", Some(Cpu::MOV)), // 0x01
-        LookupEntry::new("XXX", None), //0x02
+        LookupEntry::new("EXT", Some(Cpu::EXT)).requires(REGISTER_EXTENSION), //0x02
         LookupEntry::new("ADD", Some(Cpu::ADD)), //0x03
         LookupEntry::new("OR", Some(Cpu::OR)), //0x04
         LookupEntry::new("JMP", Some(Cpu::JMP)), //0x05
         LookupEntry::new("CALL", Some(Cpu::CALL)), //0x06
-        LookupEntry::new("XXX", None), //0x07
+        LookupEntry::new("SHL", Some(Cpu::SHL)), //0x07
         LookupEntry::new("LIDT", Some(Cpu::LIDT)), //0x08
-        LookupEntry::new("XXX", None), //0x09
-        LookupEntry::new("XXX", None), //0x0a
-        LookupEntry::new("XXX", None), //0x0b
-        LookupEntry::new("XXX", None), //0x0c
-        LookupEntry::new("XXX", None), //0x0d
+        LookupEntry::new("CMOV", Some(Cpu::CMOV)), //0x09
+        LookupEntry::new("MCPY", Some(Cpu::MCPY)).requires(BLOCK_MEMORY), //0x0a
+        LookupEntry::new("XCHG", Some(Cpu::XCHG)).requires(ATOMICS), //0x0b
+        LookupEntry::new("FLD", Some(Cpu::FLD)).requires(FLOATING_POINT_UNIT), //0x0c
+        LookupEntry::new("VLD", Some(Cpu::VLD)).requires(VECTOR_UNIT), //0x0d
         LookupEntry::new("XXX", None), //0x0e
         LookupEntry::new("XXX", None), //0x0f
         LookupEntry::new("IN", Some(Cpu::IN)), //0x10
         LookupEntry::new("CMP", Some(Cpu::CMP)), //0x11
-        LookupEntry::new("XXX", None), //0x12
+        LookupEntry::new("MEMD", Some(Cpu::MEMD)).requires(MEMORY_OPERANDS), //0x12
         LookupEntry::new("SUB", Some(Cpu::SUB)), //0x13
         LookupEntry::new("XOR", Some(Cpu::XOR)), //0x14
         LookupEntry::new("JZ", Some(Cpu::JZ)), //0x15
         LookupEntry::new("RET", Some(Cpu::RET)), //0x16
-        LookupEntry::new("XXX", None), //0x17
+        LookupEntry::new("SHR", Some(Cpu::SHR)), //0x17
         LookupEntry::new("INT", Some(Cpu::INT)), //0x18
-        LookupEntry::new("XXX", None), //0x19
-        LookupEntry::new("XXX", None), //0x1a
-        LookupEntry::new("XXX", None), //0x1b
-        LookupEntry::new("XXX", None), //0x1c
-        LookupEntry::new("XXX", None), //0x1d
+        LookupEntry::new("SET", Some(Cpu::SET)), //0x19
+        LookupEntry::new("MSET", Some(Cpu::MSET)).requires(BLOCK_MEMORY), //0x1a
+        LookupEntry::new("CMPXCHG", Some(Cpu::CMPXCHG)).requires(ATOMICS), //0x1b
+        LookupEntry::new("FST", Some(Cpu::FST)).requires(FLOATING_POINT_UNIT), //0x1c
+        LookupEntry::new("VST", Some(Cpu::VST)).requires(VECTOR_UNIT), //0x1d
         LookupEntry::new("XXX", None), //0x1e
         LookupEntry::new("XXX", None), //0x1f
         LookupEntry::new("OUT", Some(Cpu::OUT)), //0x20
         LookupEntry::new("PUSH", Some(Cpu::PUSH)), //0x21
-        LookupEntry::new("XXX", None), //0x22
+        LookupEntry::new("MEMS", Some(Cpu::MEMS)).requires(MEMORY_OPERANDS), //0x22
         LookupEntry::new("MUL", Some(Cpu::MUL)), //0x23
         LookupEntry::new("AND", Some(Cpu::AND)), //0x24
         LookupEntry::new("JNZ", Some(Cpu::JNZ)), //0x25
         LookupEntry::new("XXX", None), //0x26
-        LookupEntry::new("XXX", None), //0x27
+        LookupEntry::new("SAR", Some(Cpu::SAR)), //0x27
         LookupEntry::new("RETI", Some(Cpu::RETI)), //0x28
         LookupEntry::new("XXX", None), //0x29
-        LookupEntry::new("XXX", None), //0x2a
-        LookupEntry::new("XXX", None), //0x2b
-        LookupEntry::new("XXX", None), //0x2c
-        LookupEntry::new("XXX", None), //0x2d
+        LookupEntry::new("MCMP", Some(Cpu::MCMP)).requires(BLOCK_MEMORY), //0x2a
+        LookupEntry::new("XADD", Some(Cpu::XADD)).requires(ATOMICS), //0x2b
+        LookupEntry::new("FADD", Some(Cpu::FADD)).requires(FLOATING_POINT_UNIT), //0x2c
+        LookupEntry::new("VMOV", Some(Cpu::VMOV)).requires(VECTOR_UNIT), //0x2d
         LookupEntry::new("XXX", None), //0x2e
         LookupEntry::new("XXX", None), //0x2f
         LookupEntry::new("XXX", None), //0x30
@@ -75,140 +96,140 @@ lazy_static! {
         LookupEntry::new("NOT", Some(Cpu::NOT)), //0x34
         LookupEntry::new("JO", Some(Cpu::JO)), //0x35
         LookupEntry::new("XXX", None), //0x36
-        LookupEntry::new("XXX", None), //0x37
+        LookupEntry::new("ROL", Some(Cpu::ROL)), //0x37
         LookupEntry::new("CLI", Some(Cpu::CLI)), //0x38
         LookupEntry::new("XXX", None), //0x39
         LookupEntry::new("XXX", None), //0x3a
         LookupEntry::new("XXX", None), //0x3b
-        LookupEntry::new("XXX", None), //0x3c
-        LookupEntry::new("XXX", None), //0x3d
+        LookupEntry::new("FSUB", Some(Cpu::FSUB)).requires(FLOATING_POINT_UNIT), //0x3c
+        LookupEntry::new("VBCST", Some(Cpu::VBCST)).requires(VECTOR_UNIT), //0x3d
         LookupEntry::new("XXX", None), //0x3e
         LookupEntry::new("XXX", None), //0x3f
         LookupEntry::new("XXX", None), //0x40
         LookupEntry::new("STR", Some(Cpu::STR)), //0x41
         LookupEntry::new("XXX", None), //0x42
-        LookupEntry::new("XXX", None), //0x43
+        LookupEntry::new("ADC", Some(Cpu::ADC)), //0x43
         LookupEntry::new("NEG", Some(Cpu::NEG)), //0x44
         LookupEntry::new("JNO", Some(Cpu::JNO)), //0x45
         LookupEntry::new("XXX", None), //0x46
-        LookupEntry::new("XXX", None), //0x47
+        LookupEntry::new("ROR", Some(Cpu::ROR)), //0x47
         LookupEntry::new("STI", Some(Cpu::STI)), //0x48
         LookupEntry::new("XXX", None), //0x49
         LookupEntry::new("XXX", None), //0x4a
         LookupEntry::new("XXX", None), //0x4b
-        LookupEntry::new("XXX", None), //0x4c
-        LookupEntry::new("XXX", None), //0x4d
+        LookupEntry::new("FMUL", Some(Cpu::FMUL)).requires(FLOATING_POINT_UNIT), //0x4c
+        LookupEntry::new("VADD", Some(Cpu::VADD)).requires(VECTOR_UNIT), //0x4d
         LookupEntry::new("XXX", None), //0x4e
         LookupEntry::new("XXX", None), //0x4f
         LookupEntry::new("XXX", None), //0x50
         LookupEntry::new("LDR", Some(Cpu::LDR)), //0x51
         LookupEntry::new("XXX", None), //0x52
-        LookupEntry::new("XXX", None), //0x53
-        LookupEntry::new("XXX", None), //0x54
+        LookupEntry::new("SBB", Some(Cpu::SBB)), //0x53
+        LookupEntry::new("TEST", Some(Cpu::TEST)), //0x54
         LookupEntry::new("JS", Some(Cpu::JS)), //0x55
         LookupEntry::new("XXX", None), //0x56
-        LookupEntry::new("XXX", None), //0x57
-        LookupEntry::new("XXX", None), //0x58
+        LookupEntry::new("RCL", Some(Cpu::RCL)), //0x57
+        LookupEntry::new("CLC", Some(Cpu::CLC)), //0x58
         LookupEntry::new("XXX", None), //0x59
         LookupEntry::new("XXX", None), //0x5a
         LookupEntry::new("XXX", None), //0x5b
-        LookupEntry::new("XXX", None), //0x5c
-        LookupEntry::new("XXX", None), //0x5d
+        LookupEntry::new("FDIV", Some(Cpu::FDIV)).requires(FLOATING_POINT_UNIT), //0x5c
+        LookupEntry::new("VSUB", Some(Cpu::VSUB)).requires(VECTOR_UNIT), //0x5d
         LookupEntry::new("XXX", None), //0x5e
         LookupEntry::new("XXX", None), //0x5f
         LookupEntry::new("XXX", None), //0x60
         LookupEntry::new("LEA", Some(Cpu::LEA)), //0x61
         LookupEntry::new("XXX", None), //0x62
-        LookupEntry::new("XXX", None), //0x63
-        LookupEntry::new("XXX", None), //0x64
+        LookupEntry::new("IMUL", Some(Cpu::IMUL)), //0x63
+        LookupEntry::new("POPCNT", Some(Cpu::POPCNT)), //0x64
         LookupEntry::new("JNS", Some(Cpu::JNS)), //0x65
         LookupEntry::new("XXX", None), //0x66
-        LookupEntry::new("XXX", None), //0x67
-        LookupEntry::new("XXX", None), //0x68
+        LookupEntry::new("RCR", Some(Cpu::RCR)), //0x67
+        LookupEntry::new("STC", Some(Cpu::STC)), //0x68
         LookupEntry::new("XXX", None), //0x69
         LookupEntry::new("XXX", None), //0x6a
         LookupEntry::new("XXX", None), //0x6b
-        LookupEntry::new("XXX", None), //0x6c
-        LookupEntry::new("XXX", None), //0x6d
+        LookupEntry::new("FSQRT", Some(Cpu::FSQRT)).requires(FLOATING_POINT_UNIT), //0x6c
+        LookupEntry::new("VMUL", Some(Cpu::VMUL)).requires(VECTOR_UNIT), //0x6d
         LookupEntry::new("XXX", None), //0x6e
         LookupEntry::new("XXX", None), //0x6f
         LookupEntry::new("XXX", None), //0x70
         LookupEntry::new("PUSHF", Some(Cpu::PUSHF)), //0x71
         LookupEntry::new("XXX", None), //0x72
-        LookupEntry::new("XXX", None), //0x73
-        LookupEntry::new("XXX", None), //0x74
+        LookupEntry::new("IDIV", Some(Cpu::IDIV)), //0x73
+        LookupEntry::new("CLZ", Some(Cpu::CLZ)), //0x74
         LookupEntry::new("JC", Some(Cpu::JC)), //0x75
         LookupEntry::new("XXX", None), //0x76
         LookupEntry::new("XXX", None), //0x77
-        LookupEntry::new("XXX", None), //0x78
+        LookupEntry::new("CMC", Some(Cpu::CMC)), //0x78
         LookupEntry::new("XXX", None), //0x79
         LookupEntry::new("XXX", None), //0x7a
         LookupEntry::new("XXX", None), //0x7b
-        LookupEntry::new("XXX", None), //0x7c
-        LookupEntry::new("XXX", None), //0x7d
+        LookupEntry::new("FCMP", Some(Cpu::FCMP)).requires(FLOATING_POINT_UNIT), //0x7c
+        LookupEntry::new("VCMPEQ", Some(Cpu::VCMPEQ)).requires(VECTOR_UNIT), //0x7d
         LookupEntry::new("XXX", None), //0x7e
         LookupEntry::new("XXX", None), //0x7f
         LookupEntry::new("XXX", None), //0x80
         LookupEntry::new("POPF", Some(Cpu::POPF)), //0x81
         LookupEntry::new("XXX", None), //0x82
-        LookupEntry::new("XXX", None), //0x83
-        LookupEntry::new("XXX", None), //0x84
+        LookupEntry::new("MOD", Some(Cpu::MOD)), //0x83
+        LookupEntry::new("CTZ", Some(Cpu::CTZ)), //0x84
         LookupEntry::new("JNC", Some(Cpu::JNC)), //0x85
         LookupEntry::new("XXX", None), //0x86
         LookupEntry::new("XXX", None), //0x87
-        LookupEntry::new("XXX", None), //0x88
+        LookupEntry::new("FEAT", Some(Cpu::FEAT)), //0x88
         LookupEntry::new("XXX", None), //0x89
         LookupEntry::new("XXX", None), //0x8a
         LookupEntry::new("XXX", None), //0x8b
-        LookupEntry::new("XXX", None), //0x8c
-        LookupEntry::new("XXX", None), //0x8d
+        LookupEntry::new("FCVTIF", Some(Cpu::FCVTIF)).requires(FLOATING_POINT_UNIT), //0x8c
+        LookupEntry::new("VCMPGT", Some(Cpu::VCMPGT)).requires(VECTOR_UNIT), //0x8d
         LookupEntry::new("XXX", None), //0x8e
         LookupEntry::new("XXX", None), //0x8f
         LookupEntry::new("NOP", Some(Cpu::NOP)), //0x90
-        LookupEntry::new("XXX", None), //0x91
+        LookupEntry::new("LDZX", Some(Cpu::LDZX)), //0x91
         LookupEntry::new("XXX", None), //0x92
-        LookupEntry::new("XXX", None), //0x93
-        LookupEntry::new("XXX", None), //0x94
+        LookupEntry::new("IMOD", Some(Cpu::IMOD)), //0x93
+        LookupEntry::new("BSWAP", Some(Cpu::BSWAP)), //0x94
         LookupEntry::new("JBE", Some(Cpu::JBE)), //0x95
         LookupEntry::new("XXX", None), //0x96
         LookupEntry::new("XXX", None), //0x97
-        LookupEntry::new("XXX", None), //0x98
+        LookupEntry::new("CPUID", Some(Cpu::CPUID)), //0x98
         LookupEntry::new("XXX", None), //0x99
         LookupEntry::new("XXX", None), //0x9a
         LookupEntry::new("XXX", None), //0x9b
-        LookupEntry::new("XXX", None), //0x9c
-        LookupEntry::new("XXX", None), //0x9d
+        LookupEntry::new("FCVTFI", Some(Cpu::FCVTFI)).requires(FLOATING_POINT_UNIT), //0x9c
+        LookupEntry::new("VSHUF", Some(Cpu::VSHUF)).requires(VECTOR_UNIT), //0x9d
         LookupEntry::new("XXX", None), //0x9e
         LookupEntry::new("XXX", None), //0x9f
         LookupEntry::new("XXX", None), //0xa0
-        LookupEntry::new("XXX", None), //0xa1
+        LookupEntry::new("LDSX", Some(Cpu::LDSX)), //0xa1
         LookupEntry::new("XXX", None), //0xa2
-        LookupEntry::new("XXX", None), //0xa3
-        LookupEntry::new("XXX", None), //0xa4
+        LookupEntry::new("MULW", Some(Cpu::MULW)), //0xa3
+        LookupEntry::new("BT", Some(Cpu::BT)), //0xa4
         LookupEntry::new("JA", Some(Cpu::JA)), //0xa5
         LookupEntry::new("XXX", None), //0xa6
         LookupEntry::new("XXX", None), //0xa7
-        LookupEntry::new("XXX", None), //0xa8
+        LookupEntry::new("SYSCALL", Some(Cpu::SYSCALL)), //0xa8
         LookupEntry::new("XXX", None), //0xa9
         LookupEntry::new("XXX", None), //0xaa
         LookupEntry::new("XXX", None), //0xab
-        LookupEntry::new("XXX", None), //0xac
+        LookupEntry::new("FMOV", Some(Cpu::FMOV)).requires(FLOATING_POINT_UNIT), //0xac
         LookupEntry::new("XXX", None), //0xad
         LookupEntry::new("XXX", None), //0xae
         LookupEntry::new("XXX", None), //0xaf
         LookupEntry::new("XXX", None), //0xb0
         LookupEntry::new("XXX", None), //0xb1
         LookupEntry::new("XXX", None), //0xb2
-        LookupEntry::new("XXX", None), //0xb3
-        LookupEntry::new("XXX", None), //0xb4
+        LookupEntry::new("IMULW", Some(Cpu::IMULW)), //0xb3
+        LookupEntry::new("BTS", Some(Cpu::BTS)), //0xb4
         LookupEntry::new("JL", Some(Cpu::JL)), //0xb5
         LookupEntry::new("XXX", None), //0xb6
         LookupEntry::new("XXX", None), //0xb7
-        LookupEntry::new("XXX", None), //0xb8
+        LookupEntry::new("RDPMC", Some(Cpu::RDPMC)).requires(PERFORMANCE_COUNTERS), //0xb8
         LookupEntry::new("XXX", None), //0xb9
         LookupEntry::new("XXX", None), //0xba
         LookupEntry::new("XXX", None), //0xbb
-        LookupEntry::new("XXX", None), //0xbc
+        LookupEntry::new("FSCSR", Some(Cpu::FSCSR)).requires(FLOATING_POINT_UNIT), //0xbc
         LookupEntry::new("XXX", None), //0xbd
         LookupEntry::new("XXX", None), //0xbe
         LookupEntry::new("XXX", None), //0xbf
@@ -216,15 +237,15 @@ lazy_static! {
         LookupEntry::new("XXX", None), //0xc1
         LookupEntry::new("XXX", None), //0xc2
         LookupEntry::new("XXX", None), //0xc3
-        LookupEntry::new("XXX", None), //0xc4
+        LookupEntry::new("BTR", Some(Cpu::BTR)), //0xc4
         LookupEntry::new("JGE", Some(Cpu::JGE)), //0xc5
         LookupEntry::new("XXX", None), //0xc6
         LookupEntry::new("XXX", None), //0xc7
-        LookupEntry::new("XXX", None), //0xc8
+        LookupEntry::new("PMCTL", Some(Cpu::PMCTL)).requires(PERFORMANCE_COUNTERS), //0xc8
         LookupEntry::new("XXX", None), //0xc9
         LookupEntry::new("XXX", None), //0xca
         LookupEntry::new("XXX", None), //0xcb
-        LookupEntry::new("XXX", None), //0xcc
+        LookupEntry::new("FRCSR", Some(Cpu::FRCSR)).requires(FLOATING_POINT_UNIT), //0xcc
         LookupEntry::new("XXX", None), //0xcd
         LookupEntry::new("XXX", None), //0xce
         LookupEntry::new("XXX", None), //0xcf
@@ -232,11 +253,11 @@ lazy_static! {
         LookupEntry::new("XXX", None), //0xd1
         LookupEntry::new("XXX", None), //0xd2
         LookupEntry::new("XXX", None), //0xd3
-        LookupEntry::new("XXX", None), //0xd4
+        LookupEntry::new("BTC", Some(Cpu::BTC)), //0xd4
         LookupEntry::new("JLE", Some(Cpu::JLE)), //0xd5
         LookupEntry::new("XXX", None), //0xd6
         LookupEntry::new("XXX", None), //0xd7
-        LookupEntry::new("XXX", None), //0xd8
+        LookupEntry::new("MFCR", Some(Cpu::MFCR)), //0xd8
         LookupEntry::new("XXX", None), //0xd9
         LookupEntry::new("XXX", None), //0xda
         LookupEntry::new("XXX", None), //0xdb
@@ -252,7 +273,7 @@ lazy_static! {
         LookupEntry::new("JG", Some(Cpu::JG)), //0xe5
         LookupEntry::new("XXX", None), //0xe6
         LookupEntry::new("XXX", None), //0xe7
-        LookupEntry::new("XXX", None), //0xe8
+        LookupEntry::new("MTCR", Some(Cpu::MTCR)), //0xe8
         LookupEntry::new("XXX", None), //0xe9
         LookupEntry::new("XXX", None), //0xea
         LookupEntry::new("XXX", None), //0xeb
@@ -268,7 +289,7 @@ lazy_static! {
         LookupEntry::new("XXX", None), //0xf5
         LookupEntry::new("XXX", None), //0xf6
         LookupEntry::new("XXX", None), //0xf7
-        LookupEntry::new("XXX", None), //0xf8
+        LookupEntry::new("BRK", Some(Cpu::BRK)).raises_debug_trap(), //0xf8
         LookupEntry::new("XXX", None), //0xf9
         LookupEntry::new("XXX", None), //0xfa
         LookupEntry::new("XXX", None), //0xfb
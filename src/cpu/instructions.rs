@@ -1,11 +1,32 @@
+mod float;
+mod vector;
+
+use super::control_register::*;
+use super::features::{
+    CPUID_FEATURE_LEAF, CPUID_TOPOLOGY_LEAF, CPUID_VENDOR, CPUID_VENDOR_LEAF,
+    MEMORY_MANAGEMENT_UNIT,
+};
+use super::instruction_cache::{DecodedAddress, DecodedOperands, Source};
+use super::instruction_lookup::LookupEntry;
+use super::register_id::{FLOAT_REGISTER_COUNT, REGISTER_COUNT, VECTOR_REGISTER_COUNT};
 use super::reserved_idt_entries::*;
-use super::{Cpu, CpuFlag, RegisterId, Size};
+use super::{Condition, Cpu, CpuFlag, DebugTrap, MemoryOperand, Operand, RegisterId, Size};
 use crate::debug_println;
 use num_derive::FromPrimitive;
 use num_traits::FromPrimitive;
+use std::rc::Rc;
 
 pub type InstructionResult = Result<(), u8>;
 
+// The most bytes the block memory instructions process before returning to the instruction boundary
+const BLOCK_CHUNK_SIZE: u64 = 0x1000;
+
+// The instructions that can be prefixed with MEMD or MEMS
+const MEMORY_OPERAND_INSTRUCTIONS: [&str; 17] = [
+    "MOV", "ADD", "SUB", "ADC", "SBB", "AND", "OR", "XOR", "CMP", "TEST", "SHL", "SHR", "SAR",
+    "ROL", "ROR", "RCL", "RCR",
+];
+
 fn get_sign_bit(value: u64, size: Size) -> bool {
     (value >> ((size as u64) * 8 - 1) & 1) > 0
 }
@@ -79,791 +100,3560 @@ fn does_unsigned_div_overflow(lhs: u64, rhs: u64, size: Size) -> bool {
     }
 }
 
-fn get_effective_address(cpu: &mut Cpu) -> u64 {
-    let fetched_byte = cpu.fetch_byte();
-
-    let base_id: Option<RegisterId> = RegisterId::from_u8(fetched_byte & 0b111);
-    let index_id: Option<RegisterId> = RegisterId::from_u8(fetched_byte >> 3 & 0b111);
-
-    let const_offset = cpu.fetch_qword();
-
-    let base_value = if let Some(base_id) = base_id {
-        cpu.register(base_id)
-    } else {
-        0
-    };
+fn sign_extend(value: u64, size: Size) -> u64 {
+    match size {
+        Size::One => value as i8 as u64,
+        Size::Two => value as i16 as u64,
+        Size::Four => value as i32 as u64,
+        Size::Eight => value,
+    }
+}
 
-    let index_value = if let Some(index_id) = index_id {
-        cpu.register(index_id)
-    } else {
-        0
-    };
+// Returns true when adding the values and the carry overflows as unsigned values
+fn does_unsigned_add_with_carry_overflow(lhs: u64, rhs: u64, carry: bool, size: Size) -> bool {
+    let sum =
+        trunucate_value(lhs, size) as u128 + trunucate_value(rhs, size) as u128 + carry as u128;
 
-    let address = base_value
-        .wrapping_add(index_value)
-        .wrapping_add(const_offset);
+    sum > trunucate_value(u64::MAX, size) as u128
+}
 
-    debug_println!("Parsed address: {:#x}", address);
+// Returns true when adding the values and the carry overflows as signed values
+fn does_signed_add_with_carry_overflow(lhs: u64, rhs: u64, carry: bool, size: Size) -> bool {
+    let sum = sign_extend(lhs, size) as i64 as i128
+        + sign_extend(rhs, size) as i64 as i128
+        + carry as i128;
 
-    address
+    sum != sign_extend(sum as u64, size) as i64 as i128
 }
 
-#[allow(non_snake_case)]
-impl Cpu {
-    pub(super) fn HLT(&mut self) -> InstructionResult {
-        debug_println!("X0:       {} ({0:#x})", self.register(RegisterId::X0));
-        debug_println!("X1:       {} ({0:#x})", self.register(RegisterId::X1));
-        debug_println!("X2:       {} ({0:#x})", self.register(RegisterId::X2));
-        debug_println!("X3:       {} ({0:#x})", self.register(RegisterId::X3));
-        debug_println!("X4:       {} ({0:#x})", self.register(RegisterId::X4));
-        debug_println!("SP:       {} ({0:#x})", self.register(RegisterId::Sp));
-        debug_println!("IP:       {} ({0:#x})", self.register(RegisterId::Ip));
-        debug_println!("Negative: {}", self.get_flag(CpuFlag::Negative) as u8);
-        debug_println!("Zero:     {}", self.get_flag(CpuFlag::Zero) as u8);
-        debug_println!("Carry:    {}", self.get_flag(CpuFlag::Carry) as u8);
-        debug_println!("Overflow: {}", self.get_flag(CpuFlag::Overflow) as u8);
+// Returns true when subtracting rhs and the borrow from lhs underflows as unsigned values
+fn does_unsigned_sub_with_borrow_overflow(lhs: u64, rhs: u64, borrow: bool, size: Size) -> bool {
+    (trunucate_value(lhs, size) as u128) < trunucate_value(rhs, size) as u128 + borrow as u128
+}
 
-        self.halted = true;
+// Returns true when subtracting rhs and the borrow from lhs overflows as signed values
+fn does_signed_sub_with_borrow_overflow(lhs: u64, rhs: u64, borrow: bool, size: Size) -> bool {
+    let difference = sign_extend(lhs, size) as i64 as i128
+        - sign_extend(rhs, size) as i64 as i128
+        - borrow as i128;
 
-        Ok(())
-    }
+    difference != sign_extend(difference as u64, size) as i64 as i128
+}
 
-    pub(super) fn MOV(&mut self) -> InstructionResult {
-        let fetched_byte = self.fetch_byte();
+// The shift and rotate operations take the truncated value, the shift count and the carry flag.
+// They return the result and the last bit shifted out, which becomes the new carry flag.
+// The shift count is never 0 and is at most 63
 
-        let src_id: Option<RegisterId> = RegisterId::from_u8(fetched_byte & 0b111);
+fn shift_left(value: u64, count: u32, _carry: bool, size: Size) -> (u64, bool) {
+    let bits = size as u32 * 8;
 
-        let dst_id: RegisterId = match RegisterId::from_u8(fetched_byte >> 3 & 0b111) {
-            Some(reg_id) => reg_id,
-            None => return Err(INVALID_INSTRUCTION),
-        };
+    let result = if count < bits { value << count } else { 0 };
+    let carry = count <= bits && (value >> (bits - count) & 1) == 1;
 
-        // let dst_idx: RegisterId = RegisterId::try_from((fetched_byte >> 3 & 0b111));
-        let size: Size = Size::try_from(1 << (fetched_byte >> 6 & 0b11))
-            .expect("Unrecoverable error. Size is not 1, 2, 4, or 8");
+    (trunucate_value(result, size), carry)
+}
 
-        let move_value: u64;
+fn shift_right(value: u64, count: u32, _carry: bool, size: Size) -> (u64, bool) {
+    let bits = size as u32 * 8;
 
-        if let Some(src_id) = src_id {
-            move_value = self.register(src_id);
-        } else {
-            move_value = self.fetch_sized(size);
-        }
+    let result = if count < bits { value >> count } else { 0 };
+    let carry = count <= bits && (value >> (count - 1) & 1) == 1;
 
-        debug_println!("Moving {} to {:?} with size {:?}", move_value, dst_id, size);
+    (result, carry)
+}
 
-        self.register_assign_sized(dst_id, move_value, size);
+fn shift_right_arithmetic(value: u64, count: u32, _carry: bool, size: Size) -> (u64, bool) {
+    // Once every bit has been shifted out only copies of the sign bit are left
+    let value = sign_extend(value, size) as i64;
 
-        Ok(())
-    }
+    let result = value >> count;
+    let carry = (value >> (count - 1) & 1) == 1;
 
-    pub(super) fn ADD(&mut self) -> InstructionResult {
-        let fetched_byte = self.fetch_byte();
+    (trunucate_value(result as u64, size), carry)
+}
 
-        let src_id: Option<RegisterId> = RegisterId::from_u8(fetched_byte & 0b111);
+fn rotate_left(value: u64, count: u32, _carry: bool, size: Size) -> (u64, bool) {
+    let bits = size as u32 * 8;
+    let count = count % bits;
 
-        let dst_id: RegisterId = match RegisterId::from_u8(fetched_byte >> 3 & 0b111) {
-            Some(reg_id) => reg_id,
-            None => return Err(INVALID_INSTRUCTION),
-        };
+    let result = if count == 0 {
+        value
+    } else {
+        trunucate_value(value << count | value >> (bits - count), size)
+    };
 
-        // let dst_idx: RegisterId = RegisterId::try_from((fetched_byte >> 3 & 0b111));
-        let size: Size = Size::try_from(1 << (fetched_byte >> 6 & 0b11))
-            .expect("Unrecoverable error. Size is not 1, 2, 4, or 8");
+    // The carry is the bit that was rotated from the top into the lowest bit
+    (result, result & 1 == 1)
+}
 
-        let rhs_value: u64;
+fn rotate_right(value: u64, count: u32, _carry: bool, size: Size) -> (u64, bool) {
+    let bits = size as u32 * 8;
+    let count = count % bits;
 
-        if let Some(src_id) = src_id {
-            rhs_value = self.register(src_id);
-        } else {
-            rhs_value = self.fetch_sized(size);
-        }
+    let result = if count == 0 {
+        value
+    } else {
+        trunucate_value(value >> count | value << (bits - count), size)
+    };
 
-        debug_println!("Adding {:?} with {}", dst_id, rhs_value);
+    // The carry is the bit that was rotated from the bottom into the highest bit
+    (result, get_sign_bit(result, size))
+}
 
-        let result = self.register(dst_id).wrapping_add(rhs_value);
+fn rotate_left_through_carry(
+    mut value: u64,
+    count: u32,
+    mut carry: bool,
+    size: Size,
+) -> (u64, bool) {
+    // The carry flag acts as an extra bit above the highest bit of the value
+    let count = count % (size as u32 * 8 + 1);
+
+    for _ in 0..count {
+        let shifted_out = get_sign_bit(value, size);
+        value = trunucate_value(value << 1 | carry as u64, size);
+        carry = shifted_out;
+    }
 
-        self.set_flag(CpuFlag::Zero, trunucate_value(result, size) == 0);
-        self.set_flag(CpuFlag::Negative, get_sign_bit(result, size));
+    (value, carry)
+}
 
-        self.set_flag(
-            CpuFlag::Carry,
-            trunucate_value(self.register(dst_id), size) > trunucate_value(result, size),
-        );
+fn rotate_right_through_carry(
+    mut value: u64,
+    count: u32,
+    mut carry: bool,
+    size: Size,
+) -> (u64, bool) {
+    // The carry flag acts as an extra bit below the lowest bit of the value
+    let count = count % (size as u32 * 8 + 1);
+
+    for _ in 0..count {
+        let shifted_out = value & 1 == 1;
+        value = value >> 1 | (carry as u64) << (size as u32 * 8 - 1);
+        carry = shifted_out;
+    }
 
-        self.set_flag(
-            CpuFlag::Overflow,
-            does_signed_add_overflow(self.register(dst_id), rhs_value, size),
-        );
+    (value, carry)
+}
 
-        // self.set_flag(
-        //     CpuFlag::Overflow,
-        //     get_sign_bit(self.register(dst_id), size) == get_sign_bit(rhs_value, size)
-        //         && get_sign_bit(rhs_value, size) != get_sign_bit(result, size),
-        // );
+fn population_count(value: u64, _size: Size) -> u64 {
+    value.count_ones() as u64
+}
 
-        self.register_assign_sized(dst_id, result, size);
+fn count_leading_zeros(value: u64, size: Size) -> u64 {
+    // The value is zero extended to 64 bits, so the extra leading zeros have to be subtracted
+    (value.leading_zeros() - (64 - size as u32 * 8)) as u64
+}
 
-        Ok(())
+fn count_trailing_zeros(value: u64, size: Size) -> u64 {
+    if value == 0 {
+        size as u64 * 8
+    } else {
+        value.trailing_zeros() as u64
     }
+}
 
-    pub(super) fn SUB(&mut self) -> InstructionResult {
-        let fetched_byte = self.fetch_byte();
+/// The register fields an instruction can contain. The value of each variant is where the two
+/// extension bits for that field are located in the byte following the `EXT` prefix
+#[derive(Debug, Clone, Copy)]
+enum RegisterField {
+    Low = 0,
+    High = 2,
+    Base = 4,
+    Index = 6,
+}
+
+// Returns the raw number in a register field, including the extension bits given by the `EXT` prefix
+fn get_register_number(cpu: &Cpu, fetched_byte: u8, field: RegisterField) -> u8 {
+    let field_bits = match field {
+        RegisterField::Low | RegisterField::Base => fetched_byte & 0b111,
+        RegisterField::High | RegisterField::Index => fetched_byte >> 3 & 0b111,
+    };
 
-        let src_id: Option<RegisterId> = RegisterId::from_u8(fetched_byte & 0b111);
+    let extension_bits = cpu.register_extension.unwrap_or(0) >> field as u8 & 0b11;
 
-        let dst_id: RegisterId = match RegisterId::from_u8(fetched_byte >> 3 & 0b111) {
-            Some(reg_id) => reg_id,
-            None => return Err(INVALID_INSTRUCTION),
-        };
+    extension_bits << 3 | field_bits
+}
 
-        // let dst_idx: RegisterId = RegisterId::try_from((fetched_byte >> 3 & 0b111));
-        let size: Size = Size::try_from(1 << (fetched_byte >> 6 & 0b11))
-            .expect("Unrecoverable error. Size is not 1, 2, 4, or 8");
+// Decodes a register field, returning None if the register id is 0, which means an immediate value or no register
+fn get_optional_register(
+    cpu: &Cpu,
+    fetched_byte: u8,
+    field: RegisterField,
+) -> Result<Option<RegisterId>, u8> {
+    match get_register_number(cpu, fetched_byte, field) {
+        0 => Ok(None),
+        id => match RegisterId::from_u8(id) {
+            Some(reg_id) => Ok(Some(reg_id)),
+            None => Err(INVALID_INSTRUCTION),
+        },
+    }
+}
 
-        let rhs_value: u64;
+// Decodes a register field that has to name a register
+fn get_register(cpu: &Cpu, fetched_byte: u8, field: RegisterField) -> Result<RegisterId, u8> {
+    match get_optional_register(cpu, fetched_byte, field)? {
+        Some(reg_id) => Ok(reg_id),
+        None => Err(INVALID_INSTRUCTION),
+    }
+}
 
-        if let Some(src_id) = src_id {
-            rhs_value = self.register(src_id);
-        } else {
-            rhs_value = self.fetch_sized(size);
-        }
+// Decodes the operands of instructions in the form of "dst, src" where src can be a register or an immediate value.
+// Returns the destination register, the value of the source operand and the size of the operation
+fn get_binary_operands(cpu: &mut Cpu) -> Result<(RegisterId, u64, Size), u8> {
+    if let Some(DecodedOperands::Binary { dst, src, size }) = cpu.cached_operands() {
+        return Ok((dst, source_value(cpu, src), size));
+    }
 
-        debug_println!("Subtracting {} from {:?}", rhs_value, dst_id);
+    let start = cpu.register(RegisterId::Ip);
+    let fetched_byte = cpu.fetch_byte();
 
-        let result = self.register(dst_id).wrapping_sub(rhs_value);
+    let src_id = get_optional_register(cpu, fetched_byte, RegisterField::Low)?;
+    let dst_id = get_register(cpu, fetched_byte, RegisterField::High)?;
 
-        self.set_flag(CpuFlag::Zero, result == 0);
-        self.set_flag(CpuFlag::Negative, get_sign_bit(result, size));
+    let size: Size = Size::try_from(1 << (fetched_byte >> 6 & 0b11))
+        .expect("Unrecoverable error. Size is not 1, 2, 4, or 8");
 
-        self.set_flag(
-            CpuFlag::Carry,
-            trunucate_value(self.register(dst_id), size) < trunucate_value(result, size),
-        );
+    let src = if let Some(src_id) = src_id {
+        Source::Register(src_id)
+    } else {
+        Source::Immediate(cpu.fetch_sized(size))
+    };
 
-        self.set_flag(
-            CpuFlag::Overflow,
-            does_signed_sub_overflow(self.register(dst_id), rhs_value, size),
-        );
+    cpu.record_operands(
+        start,
+        DecodedOperands::Binary {
+            dst: dst_id,
+            src,
+            size,
+        },
+    );
 
-        self.register_assign_sized(dst_id, result, size);
+    Ok((dst_id, source_value(cpu, src), size))
+}
 
-        Ok(())
+fn source_value(cpu: &Cpu, src: Source) -> u64 {
+    match src {
+        Source::Register(src_id) => cpu.register(src_id),
+        Source::Immediate(value) => value,
     }
+}
 
-    pub(super) fn MUL(&mut self) -> InstructionResult {
-        let fetched_byte = self.fetch_byte();
+// Decodes the operands of the instructions that can take a memory operand through the MEMD and MEMS prefixes.
+// Without a prefix this is the same as get_binary_operands. With MEMD the destination register field must be 0, and
+// the destination is the address that follows the operand byte and any immediate value. With MEMS the source register
+// field must be 0, and the source operand is read from the address that follows the operand byte
+fn get_binary_memory_operands(cpu: &mut Cpu) -> Result<(Operand, u64, Size), u8> {
+    let memory_operand = match cpu.memory_operand {
+        Some(memory_operand) => memory_operand,
+        None => {
+            let (dst_id, src_value, size) = get_binary_operands(cpu)?;
+            return Ok((Operand::Register(dst_id), src_value, size));
+        }
+    };
 
-        let src_id: Option<RegisterId> = RegisterId::from_u8(fetched_byte & 0b111);
+    let fetched_byte = cpu.fetch_byte();
 
-        let dst_id: RegisterId = match RegisterId::from_u8(fetched_byte >> 3 & 0b111) {
-            Some(reg_id) => reg_id,
-            None => return Err(INVALID_INSTRUCTION),
-        };
+    let size: Size = Size::try_from(1 << (fetched_byte >> 6 & 0b11))
+        .expect("Unrecoverable error. Size is not 1, 2, 4, or 8");
 
-        // let dst_idx: RegisterId = RegisterId::try_from((fetched_byte >> 3 & 0b111));
-        let size: Size = Size::try_from(1 << (fetched_byte >> 6 & 0b11))
-            .expect("Unrecoverable error. Size is not 1, 2, 4, or 8");
+    match memory_operand {
+        MemoryOperand::Destination => {
+            if get_register_number(cpu, fetched_byte, RegisterField::High) != 0 {
+                return Err(INVALID_INSTRUCTION);
+            }
 
-        let rhs_value: u64;
+            let src_id = get_optional_register(cpu, fetched_byte, RegisterField::Low)?;
 
-        if let Some(src_id) = src_id {
-            rhs_value = self.register(src_id);
-        } else {
-            rhs_value = self.fetch_sized(size);
-        }
+            let src_value = if let Some(src_id) = src_id {
+                cpu.register(src_id)
+            } else {
+                cpu.fetch_sized(size)
+            };
 
-        debug_println!("Multiplying {:?} with {}", dst_id, rhs_value);
+            let address = get_effective_address(cpu)?;
+            cpu.check_alignment(address, size as u64)?;
 
-        let result = self.register(dst_id).wrapping_mul(rhs_value);
+            Ok((Operand::Memory(address), src_value, size))
+        }
 
-        self.set_flag(CpuFlag::Zero, result == 0);
-        self.set_flag(CpuFlag::Zero, get_sign_bit(result, size));
+        MemoryOperand::Source => {
+            if get_register_number(cpu, fetched_byte, RegisterField::Low) != 0 {
+                return Err(INVALID_INSTRUCTION);
+            }
 
-        self.set_flag(
-            CpuFlag::Carry,
-            does_unsigned_mul_overflow(self.register(dst_id), rhs_value, size),
-        );
-        self.set_flag(
-            CpuFlag::Overflow,
-            does_signed_mul_overflow(self.register(dst_id), rhs_value, size),
-        );
+            let dst_id = get_register(cpu, fetched_byte, RegisterField::High)?;
 
-        self.register_assign_sized(dst_id, result, size);
+            let address = get_effective_address(cpu)?;
+            cpu.check_alignment(address, size as u64)?;
+            let src_value = cpu.operand(Operand::Memory(address), size);
 
-        Ok(())
+            Ok((Operand::Register(dst_id), src_value, size))
+        }
     }
+}
 
-    pub(super) fn DIV(&mut self) -> InstructionResult {
-        let fetched_byte = self.fetch_byte();
-
-        let src_id: Option<RegisterId> = RegisterId::from_u8(fetched_byte & 0b111);
+// Decodes the condition byte used by CMOV and SET
+fn get_condition(cpu: &mut Cpu) -> Result<Condition, u8> {
+    match Condition::from_number(cpu.fetch_byte()) {
+        Some(condition) => Ok(condition),
+        None => Err(INVALID_INSTRUCTION),
+    }
+}
 
-        let dst_id: RegisterId = match RegisterId::from_u8(fetched_byte >> 3 & 0b111) {
-            Some(reg_id) => reg_id,
-            None => return Err(INVALID_INSTRUCTION),
-        };
+// Fetches a displacement of `size` bytes, sign extending it to 64 bits
+fn fetch_displacement(cpu: &mut Cpu, size: Option<Size>) -> u64 {
+    match size {
+        Some(size) => sign_extend(cpu.fetch_sized(size), size),
+        None => 0,
+    }
+}
 
-        // let dst_idx: RegisterId = RegisterId::try_from((fetched_byte >> 3 & 0b111));
-        let size: Size = Size::try_from(1 << (fetched_byte >> 6 & 0b11))
-            .expect("Unrecoverable error. Size is not 1, 2, 4, or 8");
+// Decodes an address operand. The address byte holds the base register in the lowest 3 bits, the index register in
+// the next 3 bits and the addressing mode in the highest 2 bits:
+//   0b00: a 64 bit displacement follows
+//   0b01: an 8 bit displacement follows
+//   0b10: a 32 bit displacement follows
+//   0b11: an extension byte follows. Bit 0 makes the address relative to the instruction pointer, bits 1-2
+//         select no displacement, or an 8, 32 or 64 bit one, and bits 3-4 scale the index register by 1, 2, 4
+//         or 8. The other bits are reserved and must be 0.
+// Displacements shorter than 64 bits are sign extended. An IP-relative address is relative to the end of the address
+// operand, which is always the end of the instruction, and can't have a base register
+fn get_effective_address(cpu: &mut Cpu) -> Result<u64, u8> {
+    if let Some(DecodedOperands::Address(address)) = cpu.cached_operands() {
+        return Ok(address_value(cpu, address));
+    }
 
-        let rhs_value: u64;
+    let start = cpu.register(RegisterId::Ip);
+    let fetched_byte = cpu.fetch_byte();
 
-        if let Some(src_id) = src_id {
-            rhs_value = self.register(src_id);
-        } else {
-            rhs_value = self.fetch_sized(size);
+    let base_id = get_optional_register(cpu, fetched_byte, RegisterField::Base)?;
+    let index_id = get_optional_register(cpu, fetched_byte, RegisterField::Index)?;
+
+    let (ip_relative, displacement_size, index_scale) = match fetched_byte >> 6 {
+        0b00 => (false, Some(Size::Eight), 0),
+        0b01 => (false, Some(Size::One), 0),
+        0b10 => (false, Some(Size::Four), 0),
+        _ => {
+            let extension_byte = cpu.fetch_byte();
+
+            if extension_byte & !0b11111 != 0 {
+                return Err(INVALID_INSTRUCTION);
+            }
+
+            let displacement_size = match extension_byte >> 1 & 0b11 {
+                0b00 => None,
+                0b01 => Some(Size::One),
+                0b10 => Some(Size::Four),
+                _ => Some(Size::Eight),
+            };
+
+            (
+                extension_byte & 1 == 1,
+                displacement_size,
+                extension_byte >> 3 & 0b11,
+            )
         }
+    };
 
-        debug_println!("Dividing {} from {:?}", rhs_value, dst_id);
+    if ip_relative && base_id.is_some() {
+        return Err(INVALID_INSTRUCTION);
+    }
 
-        if (rhs_value == 0) {
-            return Err(DIVIDE_BY_ZERO);
-        }
+    let address = DecodedAddress {
+        base: base_id,
+        index: index_id,
+        index_scale,
+        ip_relative,
+        displacement: fetch_displacement(cpu, displacement_size),
+    };
 
-        let result = self.register(dst_id).wrapping_div(rhs_value);
+    cpu.record_operands(start, DecodedOperands::Address(address));
 
-        self.set_flag(CpuFlag::Zero, result == 0);
-        self.set_flag(CpuFlag::Zero, get_sign_bit(result, size));
+    Ok(address_value(cpu, address))
+}
 
-        self.set_flag(
-            CpuFlag::Carry,
-            does_unsigned_div_overflow(self.register(dst_id), rhs_value, size),
-        );
+// Adds up the parts of a decoded address operand. IP has to be at the end of the operand
+fn address_value(cpu: &Cpu, address: DecodedAddress) -> u64 {
+    let base_value = if address.ip_relative {
+        cpu.register(RegisterId::Ip)
+    } else if let Some(base_id) = address.base {
+        cpu.register(base_id)
+    } else {
+        0
+    };
 
-        self.register_assign_sized(dst_id, result, size);
+    let index_value = if let Some(index_id) = address.index {
+        cpu.register(index_id) << address.index_scale
+    } else {
+        0
+    };
 
-        Ok(())
-    }
+    let address = base_value
+        .wrapping_add(index_value)
+        .wrapping_add(address.displacement);
 
-    pub(super) fn OR(&mut self) -> InstructionResult {
-        let fetched_byte = self.fetch_byte();
+    debug_println!("Parsed address: {:#x}", address);
 
-        let src_id: Option<RegisterId> = RegisterId::from_u8(fetched_byte & 0b111);
+    address
+}
 
-        let dst_id: RegisterId = match RegisterId::from_u8(fetched_byte >> 3 & 0b111) {
-            Some(reg_id) => reg_id,
-            None => return Err(INVALID_INSTRUCTION),
-        };
+impl Cpu {
+    // Shared by all of the shift and rotate instructions. They use the same operands as ADD, where the source
+    // operand is the shift count. Only the lowest 6 bits of the count are used, and a count of 0 leaves the
+    // destination and the flags untouched. The Overflow flag is set when the sign of the destination changed
+    fn shift_instruction(
+        &mut self,
+        operation: fn(u64, u32, bool, Size) -> (u64, bool),
+    ) -> InstructionResult {
+        let (dst, rhs_value, size) = get_binary_memory_operands(self)?;
 
-        // let dst_idx: RegisterId = RegisterId::try_from((fetched_byte >> 3 & 0b111));
-        let size: Size = Size::try_from(1 << (fetched_byte >> 6 & 0b11))
-            .expect("Unrecoverable error. Size is not 1, 2, 4, or 8");
+        let count = (rhs_value & 0b111111) as u32;
 
-        let rhs_value: u64;
+        debug_println!("Shifting {:?} by {}", dst, count);
 
-        if let Some(src_id) = src_id {
-            rhs_value = self.register(src_id);
-        } else {
-            rhs_value = self.fetch_sized(size);
+        if count == 0 {
+            return Ok(());
         }
 
-        debug_println!("Or-ing {:?} with {}", dst_id, rhs_value);
-
-        let result = self.register(dst_id) | rhs_value;
+        let value = trunucate_value(self.operand(dst, size), size);
+        let (result, carry) = operation(value, count, self.get_flag(CpuFlag::Carry), size);
 
         self.set_flag(CpuFlag::Zero, result == 0);
-        self.set_flag(CpuFlag::Zero, get_sign_bit(result, size));
+        self.set_flag(CpuFlag::Negative, get_sign_bit(result, size));
+        self.set_flag(CpuFlag::Carry, carry);
+        self.set_overflow_flag(get_sign_bit(value, size) != get_sign_bit(result, size));
 
-        self.register_assign_sized(dst_id, result, size);
+        self.operand_assign_sized(dst, result, size);
 
         Ok(())
     }
 
-    pub(super) fn XOR(&mut self) -> InstructionResult {
-        let fetched_byte = self.fetch_byte();
+    // Shared by POPCNT, CLZ and CTZ, which use the same operands as MOV and write the count of bits in the
+    // source operand to the destination. Zero is set when the result is 0 and Carry is set when the source is 0,
+    // with Negative and Overflow always being cleared
+    fn bit_count_instruction(&mut self, operation: fn(u64, Size) -> u64) -> InstructionResult {
+        let (dst_id, src_value, size) = get_binary_operands(self)?;
 
-        let src_id: Option<RegisterId> = RegisterId::from_u8(fetched_byte & 0b111);
+        let src_value = trunucate_value(src_value, size);
+        let result = operation(src_value, size);
 
-        let dst_id: RegisterId = match RegisterId::from_u8(fetched_byte >> 3 & 0b111) {
-            Some(reg_id) => reg_id,
-            None => return Err(INVALID_INSTRUCTION),
-        };
+        debug_println!("Counted {} bits of {} into {:?}", result, src_value, dst_id);
 
-        // let dst_idx: RegisterId = RegisterId::try_from((fetched_byte >> 3 & 0b111));
-        let size: Size = Size::try_from(1 << (fetched_byte >> 6 & 0b11))
-            .expect("Unrecoverable error. Size is not 1, 2, 4, or 8");
+        self.set_flag(CpuFlag::Zero, result == 0);
+        self.set_flag(CpuFlag::Negative, false);
+        self.set_flag(CpuFlag::Carry, src_value == 0);
+        self.set_flag(CpuFlag::Overflow, false);
 
-        let rhs_value: u64;
+        self.register_assign_sized(dst_id, result, size);
 
-        if let Some(src_id) = src_id {
-            rhs_value = self.register(src_id);
-        } else {
-            rhs_value = self.fetch_sized(size);
-        }
+        Ok(())
+    }
 
-        debug_println!("Xor-ing {:?} with {}", dst_id, rhs_value);
+    // Shared by the bit test instructions. They use the same operands as ADD, where the source operand is the index
+    // of the bit, which wraps around at the size of the operation. Carry is set to the value the bit had before the
+    // instruction and the other flags are left untouched. The operation, if there is one, gets the destination and
+    // a mask of the tested bit and returns the new value of the destination
+    fn bit_test_instruction(
+        &mut self,
+        operation: Option<fn(u64, u64) -> u64>,
+    ) -> InstructionResult {
+        let (dst_id, rhs_value, size) = get_binary_operands(self)?;
 
-        let result = self.register(dst_id) ^ rhs_value;
+        let bit = rhs_value % (size as u64 * 8);
+        let mask = 1 << bit;
 
-        self.set_flag(CpuFlag::Zero, result == 0);
-        self.set_flag(CpuFlag::Zero, get_sign_bit(result, size));
+        debug_println!("Testing bit {} of {:?}", bit, dst_id);
 
-        self.register_assign_sized(dst_id, result, size);
+        let value = self.register(dst_id);
+
+        self.set_flag(CpuFlag::Carry, value & mask != 0);
+
+        if let Some(operation) = operation {
+            self.register_assign_sized(dst_id, operation(value, mask), size);
+        }
 
         Ok(())
     }
 
-    pub(super) fn AND(&mut self) -> InstructionResult {
+    // Raises GENERAL_PROTECTION_FAULT when a privileged instruction is executed by a user
+    fn require_supervisor(&self) -> InstructionResult {
+        if self.is_supervisor() {
+            Ok(())
+        } else {
+            Err(GENERAL_PROTECTION_FAULT)
+        }
+    }
+
+    // Decodes the operands of MFCR and MTCR, which are privileged
+    fn get_control_register_operands(&mut self) -> Result<(RegisterId, ControlRegister), u8> {
         let fetched_byte = self.fetch_byte();
 
-        let src_id: Option<RegisterId> = RegisterId::from_u8(fetched_byte & 0b111);
+        let id = get_register(self, fetched_byte, RegisterField::Low)?;
 
-        let dst_id: RegisterId = match RegisterId::from_u8(fetched_byte >> 3 & 0b111) {
-            Some(reg_id) => reg_id,
+        let register = match ControlRegister::from_number(self.fetch_byte()) {
+            Some(register) => register,
             None => return Err(INVALID_INSTRUCTION),
         };
 
-        // let dst_idx: RegisterId = RegisterId::try_from((fetched_byte >> 3 & 0b111));
-        let size: Size = Size::try_from(1 << (fetched_byte >> 6 & 0b11))
-            .expect("Unrecoverable error. Size is not 1, 2, 4, or 8");
+        self.require_supervisor()?;
 
-        let rhs_value: u64;
+        Ok((id, register))
+    }
 
-        if let Some(src_id) = src_id {
-            rhs_value = self.register(src_id);
-        } else {
-            rhs_value = self.fetch_sized(size);
+    // Shared by the MEMD and MEMS prefixes. The opcode of the prefixed instruction follows the prefix
+    fn memory_operand_prefix(&mut self, memory_operand: MemoryOperand) -> InstructionResult {
+        // Prefixes can't be stacked, except for EXT which can come before MEMD and MEMS
+        if self.memory_operand.is_some() {
+            return Err(INVALID_INSTRUCTION);
         }
 
-        debug_println!("Anding {:?} with {}", dst_id, rhs_value);
-
-        let result = self.register(dst_id) & rhs_value;
+        let opcode = self.fetch_byte();
+        let (instruction, callback) = match self.lookup_instruction(opcode) {
+            Some(LookupEntry {
+                instruction,
+                callback: Some(callback),
+                ..
+            }) if MEMORY_OPERAND_INSTRUCTIONS.contains(instruction) => (instruction, *callback),
+            _ => return Err(INVALID_INSTRUCTION),
+        };
 
-        self.set_flag(CpuFlag::Zero, result == 0);
-        self.set_flag(CpuFlag::Zero, get_sign_bit(result, size));
+        debug_println!(
+            "Executing instruction '{}' {:#x} with a memory {:?}",
+            instruction,
+            opcode,
+            memory_operand
+        );
 
-        self.register_assign_sized(dst_id, result, size);
+        self.memory_operand = Some(memory_operand);
+        let result = callback(self);
+        self.memory_operand = None;
 
-        Ok(())
+        result
     }
 
-    pub(super) fn NOT(&mut self) -> InstructionResult {
+    // Decodes the operands shared by the load instructions and reads exactly `size` bytes from memory, so a byte
+    // load never touches the neighbouring bytes. The operand byte holds the destination register in the lowest
+    // 3 bits and is followed by an address
+    fn get_load_operands(&mut self) -> Result<(RegisterId, u64, Size), u8> {
         let fetched_byte = self.fetch_byte();
 
-        let dst_id: RegisterId = match RegisterId::from_u8(fetched_byte & 0b111) {
-            Some(reg_id) => reg_id,
-            None => return Err(INVALID_INSTRUCTION),
-        };
+        let dst_id = get_register(self, fetched_byte, RegisterField::Low)?;
 
         let size: Size = Size::try_from(1 << (fetched_byte >> 6 & 0b11))
             .expect("Unrecoverable error. Size is not 1, 2, 4, or 8");
 
-        debug_println!("Not-ing {:?}", dst_id);
+        let address = get_effective_address(self)?;
+        self.check_alignment(address, size as u64)?;
 
-        let result = !self.register(dst_id);
+        let mut derefrenced: [u8; 8] = [0; 8];
+        self.read(&mut derefrenced[..size as usize], address);
 
-        self.set_flag(CpuFlag::Zero, result == 0);
-        self.set_flag(CpuFlag::Negative, get_sign_bit(result, size));
+        Ok((dst_id, u64::from_le_bytes(derefrenced), size))
+    }
 
-        self.register_assign_sized(dst_id, result, size);
+    // Decodes the operands shared by the block memory instructions. The first byte holds the destination register
+    // in the lowest 3 bits and the source register in the next 3 bits. The second byte holds the count register
+    // in its lowest 3 bits, which uses the register extension bits of the base register of an address.
+    // The size bits are ignored
+    fn get_block_operands(&mut self) -> Result<(RegisterId, RegisterId, RegisterId), u8> {
+        let fetched_byte = self.fetch_byte();
+
+        let dst_id = get_register(self, fetched_byte, RegisterField::Low)?;
+        let src_id = get_register(self, fetched_byte, RegisterField::High)?;
+
+        let fetched_byte = self.fetch_byte();
+
+        let count_id = get_register(self, fetched_byte, RegisterField::Base)?;
+
+        Ok((dst_id, src_id, count_id))
+    }
+
+    // Advances the registers of a block memory instruction past the bytes that were processed.
+    // If there are bytes left the instruction is restarted instead of looping here, so the CPU gets back to an
+    // instruction boundary after every chunk and large blocks don't hold up interrupts
+    fn advance_block_operation(
+        &mut self,
+        dst_id: RegisterId,
+        src_id: Option<RegisterId>,
+        count_id: RegisterId,
+        processed: u64,
+    ) {
+        self.register_add_assign(dst_id, processed);
+
+        if let Some(src_id) = src_id {
+            self.register_add_assign(src_id, processed);
+        }
+
+        self.register_sub_assign(count_id, processed);
+
+        if self.register(count_id) != 0 {
+            self.register_assign(RegisterId::Ip, self.instruction_start);
+        }
+    }
+
+    fn conditional_jump(&mut self, condition: Condition) -> InstructionResult {
+        let address = get_effective_address(self)?;
+
+        if self.condition_met(condition) {
+            self.branch(address);
+        }
 
         Ok(())
     }
+}
+
+#[allow(non_snake_case)]
+impl Cpu {
+    pub(super) fn HLT(&mut self) -> InstructionResult {
+        self.require_supervisor()?;
+
+        debug_println!("X0:       {} ({0:#x})", self.register(RegisterId::X0));
+        debug_println!("X1:       {} ({0:#x})", self.register(RegisterId::X1));
+        debug_println!("X2:       {} ({0:#x})", self.register(RegisterId::X2));
+        debug_println!("X3:       {} ({0:#x})", self.register(RegisterId::X3));
+        debug_println!("X4:       {} ({0:#x})", self.register(RegisterId::X4));
+        for id in RegisterId::X5 as u8..=RegisterId::X15 as u8 {
+            let id = RegisterId::from_u8(id).unwrap();
+            debug_println!("{:<10}{} ({1:#x})", format!("{:?}:", id), self.register(id));
+        }
+        debug_println!("SP:       {} ({0:#x})", self.register(RegisterId::Sp));
+        debug_println!("IP:       {} ({0:#x})", self.register(RegisterId::Ip));
+        debug_println!("Negative: {}", self.get_flag(CpuFlag::Negative) as u8);
+        debug_println!("Zero:     {}", self.get_flag(CpuFlag::Zero) as u8);
+        debug_println!("Carry:    {}", self.get_flag(CpuFlag::Carry) as u8);
+        debug_println!("Overflow: {}", self.get_flag(CpuFlag::Overflow) as u8);
+
+        self.halted = true;
+
+        Ok(())
+    }
+
+    pub(super) fn MOV(&mut self) -> InstructionResult {
+        let (dst, move_value, size) = get_binary_memory_operands(self)?;
+
+        debug_println!("Moving {} to {:?} with size {:?}", move_value, dst, size);
+
+        self.operand_assign_sized(dst, move_value, size);
+
+        Ok(())
+    }
+
+    // Encoded as a condition byte followed by the same operands as MOV. The source operand is always decoded,
+    // but only moved into the destination if the condition is met
+    pub(super) fn CMOV(&mut self) -> InstructionResult {
+        let condition = get_condition(self)?;
+        let (dst_id, move_value, size) = get_binary_operands(self)?;
+
+        debug_println!("Moving {} to {:?} if {:?}", move_value, dst_id, condition);
+
+        if self.condition_met(condition) {
+            self.register_assign_sized(dst_id, move_value, size);
+        }
+
+        Ok(())
+    }
+
+    // Encoded as a condition byte followed by a register byte in the same form as NOT.
+    // The lowest `size` bytes of the register are set to 1 if the condition is met, and 0 otherwise
+    pub(super) fn SET(&mut self) -> InstructionResult {
+        let condition = get_condition(self)?;
 
-    pub(super) fn NEG(&mut self) -> InstructionResult {
         let fetched_byte = self.fetch_byte();
 
-        let dst_id: RegisterId = match RegisterId::from_u8(fetched_byte & 0b111) {
-            Some(reg_id) => reg_id,
-            None => return Err(INVALID_INSTRUCTION),
-        };
+        let dst_id = get_register(self, fetched_byte, RegisterField::Low)?;
 
         let size: Size = Size::try_from(1 << (fetched_byte >> 6 & 0b11))
             .expect("Unrecoverable error. Size is not 1, 2, 4, or 8");
 
-        debug_println!("Negating {:?}", dst_id);
+        debug_println!("Setting {:?} if {:?}", dst_id, condition);
 
-        let result = self.register(dst_id).wrapping_neg();
+        let value = self.condition_met(condition) as u64;
+        self.register_assign_sized(dst_id, value, size);
+
+        Ok(())
+    }
+
+    pub(super) fn ADD(&mut self) -> InstructionResult {
+        let (dst, rhs_value, size) = get_binary_memory_operands(self)?;
+
+        let lhs_value = self.operand(dst, size);
+
+        debug_println!("Adding {:?} with {}", dst, rhs_value);
+
+        let result = lhs_value.wrapping_add(rhs_value);
+
+        self.set_flag(CpuFlag::Zero, trunucate_value(result, size) == 0);
+        self.set_flag(CpuFlag::Negative, get_sign_bit(result, size));
+
+        self.set_flag(
+            CpuFlag::Carry,
+            trunucate_value(lhs_value, size) > trunucate_value(result, size),
+        );
+
+        self.set_overflow_flag(does_signed_add_overflow(lhs_value, rhs_value, size));
+
+        // self.set_flag(
+        //     CpuFlag::Overflow,
+        //     get_sign_bit(lhs_value, size) == get_sign_bit(rhs_value, size)
+        //         && get_sign_bit(rhs_value, size) != get_sign_bit(result, size),
+        // );
+
+        self.operand_assign_sized(dst, result, size);
+
+        Ok(())
+    }
+
+    pub(super) fn SUB(&mut self) -> InstructionResult {
+        let (dst, rhs_value, size) = get_binary_memory_operands(self)?;
+
+        let lhs_value = self.operand(dst, size);
+
+        debug_println!("Subtracting {} from {:?}", rhs_value, dst);
+
+        let result = lhs_value.wrapping_sub(rhs_value);
+
+        self.set_flag(CpuFlag::Zero, result == 0);
+        self.set_flag(CpuFlag::Negative, get_sign_bit(result, size));
+
+        self.set_flag(
+            CpuFlag::Carry,
+            trunucate_value(lhs_value, size) < trunucate_value(result, size),
+        );
+
+        self.set_overflow_flag(does_signed_sub_overflow(lhs_value, rhs_value, size));
+
+        self.operand_assign_sized(dst, result, size);
+
+        Ok(())
+    }
+
+    // Adds the source operand and the carry flag to the destination, so that additions wider than 64 bits can be
+    // done by chaining an ADD with ADCs
+    pub(super) fn ADC(&mut self) -> InstructionResult {
+        let (dst, rhs_value, size) = get_binary_memory_operands(self)?;
+
+        let lhs_value = self.operand(dst, size);
+
+        debug_println!("Adding {:?} with {} and the carry", dst, rhs_value);
+
+        let carry = self.get_flag(CpuFlag::Carry);
+        let result = lhs_value.wrapping_add(rhs_value).wrapping_add(carry as u64);
+
+        self.set_flag(CpuFlag::Zero, trunucate_value(result, size) == 0);
+        self.set_flag(CpuFlag::Negative, get_sign_bit(result, size));
+
+        self.set_flag(
+            CpuFlag::Carry,
+            does_unsigned_add_with_carry_overflow(lhs_value, rhs_value, carry, size),
+        );
+
+        self.set_overflow_flag(does_signed_add_with_carry_overflow(
+            lhs_value, rhs_value, carry, size,
+        ));
+
+        self.operand_assign_sized(dst, result, size);
+
+        Ok(())
+    }
+
+    // Subtracts the source operand and the carry flag, which holds the borrow of a previous subtraction,
+    // from the destination
+    pub(super) fn SBB(&mut self) -> InstructionResult {
+        let (dst, rhs_value, size) = get_binary_memory_operands(self)?;
+
+        let lhs_value = self.operand(dst, size);
+
+        debug_println!("Subtracting {} and the borrow from {:?}", rhs_value, dst);
+
+        let borrow = self.get_flag(CpuFlag::Carry);
+        let result = lhs_value
+            .wrapping_sub(rhs_value)
+            .wrapping_sub(borrow as u64);
+
+        self.set_flag(CpuFlag::Zero, trunucate_value(result, size) == 0);
+        self.set_flag(CpuFlag::Negative, get_sign_bit(result, size));
+
+        self.set_flag(
+            CpuFlag::Carry,
+            does_unsigned_sub_with_borrow_overflow(lhs_value, rhs_value, borrow, size),
+        );
+
+        self.set_overflow_flag(does_signed_sub_with_borrow_overflow(
+            lhs_value, rhs_value, borrow, size,
+        ));
+
+        self.operand_assign_sized(dst, result, size);
+
+        Ok(())
+    }
+
+    pub(super) fn MUL(&mut self) -> InstructionResult {
+        let (dst_id, rhs_value, size) = get_binary_operands(self)?;
+
+        debug_println!("Multiplying {:?} with {}", dst_id, rhs_value);
+
+        let result = self.register(dst_id).wrapping_mul(rhs_value);
+
+        self.set_flag(CpuFlag::Zero, result == 0);
+        self.set_flag(CpuFlag::Zero, get_sign_bit(result, size));
+
+        self.set_flag(
+            CpuFlag::Carry,
+            does_unsigned_mul_overflow(self.register(dst_id), rhs_value, size),
+        );
+        self.set_overflow_flag(does_signed_mul_overflow(
+            self.register(dst_id),
+            rhs_value,
+            size,
+        ));
+
+        self.register_assign_sized(dst_id, result, size);
+
+        Ok(())
+    }
+
+    pub(super) fn DIV(&mut self) -> InstructionResult {
+        let (dst_id, rhs_value, size) = get_binary_operands(self)?;
+
+        debug_println!("Dividing {} from {:?}", rhs_value, dst_id);
+
+        if (rhs_value == 0) {
+            return Err(DIVIDE_BY_ZERO);
+        }
+
+        let result = self.register(dst_id).wrapping_div(rhs_value);
+
+        self.set_flag(CpuFlag::Zero, result == 0);
+        self.set_flag(CpuFlag::Zero, get_sign_bit(result, size));
+
+        self.set_flag(
+            CpuFlag::Carry,
+            does_unsigned_div_overflow(self.register(dst_id), rhs_value, size),
+        );
+
+        self.register_assign_sized(dst_id, result, size);
+
+        Ok(())
+    }
+
+    pub(super) fn IMUL(&mut self) -> InstructionResult {
+        let (dst_id, rhs_value, size) = get_binary_operands(self)?;
+
+        debug_println!("Signed multiplying {:?} with {}", dst_id, rhs_value);
+
+        let lhs_value = self.register(dst_id);
+        let result = sign_extend(lhs_value, size).wrapping_mul(sign_extend(rhs_value, size));
+
+        let overflow = does_signed_mul_overflow(lhs_value, rhs_value, size);
+
+        self.set_flag(CpuFlag::Zero, trunucate_value(result, size) == 0);
+        self.set_flag(CpuFlag::Negative, get_sign_bit(result, size));
+        self.set_flag(CpuFlag::Carry, overflow);
+        self.set_overflow_flag(overflow);
+
+        self.register_assign_sized(dst_id, result, size);
+
+        Ok(())
+    }
+
+    pub(super) fn IDIV(&mut self) -> InstructionResult {
+        let (dst_id, rhs_value, size) = get_binary_operands(self)?;
+
+        debug_println!("Signed dividing {:?} by {}", dst_id, rhs_value);
+
+        let lhs_value = self.register(dst_id);
+
+        if trunucate_value(rhs_value, size) == 0 {
+            return Err(DIVIDE_BY_ZERO);
+        }
+
+        // The only signed division that overflows is dividing the most negative value by -1
+        if does_signed_div_overflow(lhs_value, rhs_value, size) {
+            return Err(DIVIDE_OVERFLOW);
+        }
+
+        let result =
+            (sign_extend(lhs_value, size) as i64 / sign_extend(rhs_value, size) as i64) as u64;
+
+        self.set_flag(CpuFlag::Zero, trunucate_value(result, size) == 0);
+        self.set_flag(CpuFlag::Negative, get_sign_bit(result, size));
+        self.set_flag(CpuFlag::Carry, false);
+        self.set_flag(CpuFlag::Overflow, false);
+
+        self.register_assign_sized(dst_id, result, size);
+
+        Ok(())
+    }
+
+    // Unsigned remainder
+    pub(super) fn MOD(&mut self) -> InstructionResult {
+        let (dst_id, rhs_value, size) = get_binary_operands(self)?;
+
+        debug_println!(
+            "Taking the remainder of {:?} divided by {}",
+            dst_id,
+            rhs_value
+        );
+
+        let lhs_value = trunucate_value(self.register(dst_id), size);
+        let rhs_value = trunucate_value(rhs_value, size);
+
+        if rhs_value == 0 {
+            return Err(DIVIDE_BY_ZERO);
+        }
+
+        let result = lhs_value % rhs_value;
+
+        self.set_flag(CpuFlag::Zero, result == 0);
+        self.set_flag(CpuFlag::Negative, get_sign_bit(result, size));
+        self.set_flag(CpuFlag::Carry, false);
+        self.set_flag(CpuFlag::Overflow, false);
+
+        self.register_assign_sized(dst_id, result, size);
+
+        Ok(())
+    }
+
+    // Signed remainder. The result has the same sign as the dividend
+    pub(super) fn IMOD(&mut self) -> InstructionResult {
+        let (dst_id, rhs_value, size) = get_binary_operands(self)?;
+
+        debug_println!(
+            "Taking the signed remainder of {:?} divided by {}",
+            dst_id,
+            rhs_value
+        );
+
+        if trunucate_value(rhs_value, size) == 0 {
+            return Err(DIVIDE_BY_ZERO);
+        }
+
+        // Unlike the quotient, the remainder of the most negative value divided by -1 fits, since it is 0
+        let result = (sign_extend(self.register(dst_id), size) as i64)
+            .wrapping_rem(sign_extend(rhs_value, size) as i64) as u64;
+
+        self.set_flag(CpuFlag::Zero, trunucate_value(result, size) == 0);
+        self.set_flag(CpuFlag::Negative, get_sign_bit(result, size));
+        self.set_flag(CpuFlag::Carry, false);
+        self.set_flag(CpuFlag::Overflow, false);
+
+        self.register_assign_sized(dst_id, result, size);
+
+        Ok(())
+    }
+
+    // Unsigned widening multiply. Uses the same operands as MUL, followed by a byte whose lowest 3 bits
+    // name the register that receives the upper half of the double width product. That register can't be the
+    // destination, which would lose one of the halves.
+    // The register extension bits for that register are the ones for the base register of an address
+    pub(super) fn MULW(&mut self) -> InstructionResult {
+        let (dst_id, rhs_value, size) = get_binary_operands(self)?;
+
+        let fetched_byte = self.fetch_byte();
+        let high_id = get_register(self, fetched_byte, RegisterField::Base)?;
+
+        if high_id == dst_id {
+            return Err(INVALID_INSTRUCTION);
+        }
+
+        debug_println!(
+            "Widening multiplying {:?} with {} into {:?}:{:?}",
+            dst_id,
+            rhs_value,
+            high_id,
+            dst_id
+        );
+
+        let product = trunucate_value(self.register(dst_id), size) as u128
+            * trunucate_value(rhs_value, size) as u128;
+
+        let low = trunucate_value(product as u64, size);
+        let high = trunucate_value((product >> (size as u32 * 8)) as u64, size);
+
+        self.set_flag(CpuFlag::Zero, product == 0);
+        self.set_flag(CpuFlag::Negative, get_sign_bit(high, size));
+        self.set_flag(CpuFlag::Carry, high != 0);
+        self.set_overflow_flag(high != 0);
+
+        self.register_assign_sized(dst_id, low, size);
+        self.register_assign_sized(high_id, high, size);
+
+        Ok(())
+    }
+
+    // Signed widening multiply, encoded the same way as MULW
+    pub(super) fn IMULW(&mut self) -> InstructionResult {
+        let (dst_id, rhs_value, size) = get_binary_operands(self)?;
+
+        let fetched_byte = self.fetch_byte();
+        let high_id = get_register(self, fetched_byte, RegisterField::Base)?;
+
+        if high_id == dst_id {
+            return Err(INVALID_INSTRUCTION);
+        }
+
+        debug_println!(
+            "Signed widening multiplying {:?} with {} into {:?}:{:?}",
+            dst_id,
+            rhs_value,
+            high_id,
+            dst_id
+        );
+
+        let product = sign_extend(self.register(dst_id), size) as i64 as i128
+            * sign_extend(rhs_value, size) as i64 as i128;
+
+        let low = trunucate_value(product as u64, size);
+        let high = trunucate_value((product >> (size as u32 * 8)) as u64, size);
+
+        // The upper half is only significant if it is more than the sign extension of the lower half
+        let significant_high = sign_extend(low, size) as i64 as i128 != product;
+
+        self.set_flag(CpuFlag::Zero, product == 0);
+        self.set_flag(CpuFlag::Negative, product < 0);
+        self.set_flag(CpuFlag::Carry, significant_high);
+        self.set_overflow_flag(significant_high);
+
+        self.register_assign_sized(dst_id, low, size);
+        self.register_assign_sized(high_id, high, size);
+
+        Ok(())
+    }
+
+    pub(super) fn OR(&mut self) -> InstructionResult {
+        let (dst, rhs_value, size) = get_binary_memory_operands(self)?;
+
+        debug_println!("Or-ing {:?} with {}", dst, rhs_value);
+
+        let result = self.operand(dst, size) | rhs_value;
+
+        self.set_flag(CpuFlag::Zero, result == 0);
+        self.set_flag(CpuFlag::Zero, get_sign_bit(result, size));
+
+        self.operand_assign_sized(dst, result, size);
+
+        Ok(())
+    }
+
+    pub(super) fn XOR(&mut self) -> InstructionResult {
+        let (dst, rhs_value, size) = get_binary_memory_operands(self)?;
+
+        debug_println!("Xor-ing {:?} with {}", dst, rhs_value);
+
+        let result = self.operand(dst, size) ^ rhs_value;
+
+        self.set_flag(CpuFlag::Zero, result == 0);
+        self.set_flag(CpuFlag::Zero, get_sign_bit(result, size));
+
+        self.operand_assign_sized(dst, result, size);
+
+        Ok(())
+    }
+
+    pub(super) fn AND(&mut self) -> InstructionResult {
+        let (dst, rhs_value, size) = get_binary_memory_operands(self)?;
+
+        debug_println!("Anding {:?} with {}", dst, rhs_value);
+
+        let result = self.operand(dst, size) & rhs_value;
+
+        self.set_flag(CpuFlag::Zero, result == 0);
+        self.set_flag(CpuFlag::Zero, get_sign_bit(result, size));
+
+        self.operand_assign_sized(dst, result, size);
+
+        Ok(())
+    }
+
+    pub(super) fn SHL(&mut self) -> InstructionResult {
+        self.shift_instruction(shift_left)
+    }
+
+    pub(super) fn SHR(&mut self) -> InstructionResult {
+        self.shift_instruction(shift_right)
+    }
+
+    pub(super) fn SAR(&mut self) -> InstructionResult {
+        self.shift_instruction(shift_right_arithmetic)
+    }
+
+    pub(super) fn ROL(&mut self) -> InstructionResult {
+        self.shift_instruction(rotate_left)
+    }
+
+    pub(super) fn ROR(&mut self) -> InstructionResult {
+        self.shift_instruction(rotate_right)
+    }
+
+    pub(super) fn RCL(&mut self) -> InstructionResult {
+        self.shift_instruction(rotate_left_through_carry)
+    }
+
+    pub(super) fn RCR(&mut self) -> InstructionResult {
+        self.shift_instruction(rotate_right_through_carry)
+    }
+
+    pub(super) fn NOT(&mut self) -> InstructionResult {
+        let fetched_byte = self.fetch_byte();
+
+        let dst_id = get_register(self, fetched_byte, RegisterField::Low)?;
+
+        let size: Size = Size::try_from(1 << (fetched_byte >> 6 & 0b11))
+            .expect("Unrecoverable error. Size is not 1, 2, 4, or 8");
+
+        debug_println!("Not-ing {:?}", dst_id);
+
+        let result = !self.register(dst_id);
+
+        self.set_flag(CpuFlag::Zero, result == 0);
+        self.set_flag(CpuFlag::Negative, get_sign_bit(result, size));
+
+        self.register_assign_sized(dst_id, result, size);
+
+        Ok(())
+    }
+
+    pub(super) fn NEG(&mut self) -> InstructionResult {
+        let fetched_byte = self.fetch_byte();
+
+        let dst_id = get_register(self, fetched_byte, RegisterField::Low)?;
+
+        let size: Size = Size::try_from(1 << (fetched_byte >> 6 & 0b11))
+            .expect("Unrecoverable error. Size is not 1, 2, 4, or 8");
+
+        debug_println!("Negating {:?}", dst_id);
+
+        let result = self.register(dst_id).wrapping_neg();
+
+        self.set_flag(CpuFlag::Zero, result == 0);
+        self.set_flag(CpuFlag::Negative, get_sign_bit(result, size));
+
+        self.register_assign_sized(dst_id, result, size);
+
+        Ok(())
+    }
+
+    // Ands the operands without writing the result back, and sets the flags the same way AND does
+    pub(super) fn TEST(&mut self) -> InstructionResult {
+        let (dst, rhs_value, size) = get_binary_memory_operands(self)?;
+
+        debug_println!("Testing {:?} with {}", dst, rhs_value);
+
+        let result = trunucate_value(self.operand(dst, size) & rhs_value, size);
+
+        self.set_flag(CpuFlag::Zero, result == 0);
+        self.set_flag(CpuFlag::Negative, get_sign_bit(result, size));
+        self.set_flag(CpuFlag::Carry, false);
+        self.set_flag(CpuFlag::Overflow, false);
+
+        Ok(())
+    }
+
+    pub(super) fn POPCNT(&mut self) -> InstructionResult {
+        self.bit_count_instruction(population_count)
+    }
+
+    // The result is the size of the operation in bits if the source is 0
+    pub(super) fn CLZ(&mut self) -> InstructionResult {
+        self.bit_count_instruction(count_leading_zeros)
+    }
+
+    // The result is the size of the operation in bits if the source is 0
+    pub(super) fn CTZ(&mut self) -> InstructionResult {
+        self.bit_count_instruction(count_trailing_zeros)
+    }
+
+    // Reverses the order of the bytes in the lowest `size` bytes of a register. Flags are left untouched
+    pub(super) fn BSWAP(&mut self) -> InstructionResult {
+        let fetched_byte = self.fetch_byte();
+
+        let dst_id = get_register(self, fetched_byte, RegisterField::Low)?;
+
+        let size: Size = Size::try_from(1 << (fetched_byte >> 6 & 0b11))
+            .expect("Unrecoverable error. Size is not 1, 2, 4, or 8");
+
+        debug_println!("Byte swapping {:?}", dst_id);
+
+        let value = self.register(dst_id);
+
+        let result = match size {
+            Size::One => value,
+            Size::Two => (value as u16).swap_bytes() as u64,
+            Size::Four => (value as u32).swap_bytes() as u64,
+            Size::Eight => value.swap_bytes(),
+        };
+
+        self.register_assign_sized(dst_id, result, size);
+
+        Ok(())
+    }
+
+    pub(super) fn BT(&mut self) -> InstructionResult {
+        self.bit_test_instruction(None)
+    }
+
+    pub(super) fn BTS(&mut self) -> InstructionResult {
+        self.bit_test_instruction(Some(|value, mask| value | mask))
+    }
+
+    pub(super) fn BTR(&mut self) -> InstructionResult {
+        self.bit_test_instruction(Some(|value, mask| value & !mask))
+    }
+
+    pub(super) fn BTC(&mut self) -> InstructionResult {
+        self.bit_test_instruction(Some(|value, mask| value ^ mask))
+    }
+
+    pub(super) fn CMP(&mut self) -> InstructionResult {
+        let (dst, rhs_value, size) = get_binary_memory_operands(self)?;
+
+        let lhs_value = self.operand(dst, size);
+
+        debug_println!("Comparing {:?} with {}", dst, rhs_value);
+
+        let result = lhs_value.wrapping_sub(rhs_value);
+
+        self.set_flag(CpuFlag::Zero, result == 0);
+        self.set_flag(CpuFlag::Negative, get_sign_bit(result, size));
+
+        self.set_flag(
+            CpuFlag::Carry,
+            trunucate_value(lhs_value, size) < trunucate_value(result, size),
+        );
+
+        self.set_flag(
+            CpuFlag::Overflow,
+            does_signed_sub_overflow(lhs_value, rhs_value, size),
+        );
+
+        Ok(())
+    }
+
+    pub(super) fn PUSH(&mut self) -> InstructionResult {
+        let fetched_byte = self.fetch_byte();
+
+        let src_id = get_register(self, fetched_byte, RegisterField::Low)?;
+
+        debug_println!("Pushing register {:?}", src_id);
+
+        self.push_qword(self.register(src_id));
+
+        Ok(())
+    }
+
+    pub(super) fn POP(&mut self) -> InstructionResult {
+        let fetched_byte = self.fetch_byte();
+
+        let dst_id = get_register(self, fetched_byte, RegisterField::Low)?;
+
+        debug_println!("Popping stack into register {:?}", dst_id);
+
+        let popped = self.pop_qword();
+        self.register_assign(dst_id, popped);
+
+        Ok(())
+    }
+
+    pub(super) fn PUSHF(&mut self) -> InstructionResult {
+        self.push_flags();
+
+        Ok(())
+    }
+
+    pub(super) fn POPF(&mut self) -> InstructionResult {
+        self.pop_flags();
+
+        Ok(())
+    }
+
+    // Writes the lowest `size` bytes of a register to memory. Bytes past `size` are left untouched
+    pub(super) fn STR(&mut self) -> InstructionResult {
+        let fetched_byte = self.fetch_byte();
+
+        let src_id = get_register(self, fetched_byte, RegisterField::Low)?;
+
+        let size: Size = Size::try_from(1 << (fetched_byte >> 6 & 0b11))
+            .expect("Unrecoverable error. Size is not 1, 2, 4, or 8");
+
+        let address = get_effective_address(self)?;
+        self.check_alignment(address, size as u64)?;
+
+        self.write(
+            &self.register(src_id).to_le_bytes()[..size as usize],
+            address,
+        );
+
+        Ok(())
+    }
+
+    // Reads `size` bytes from memory into the lowest `size` bytes of a register, leaving the upper bytes untouched
+    pub(super) fn LDR(&mut self) -> InstructionResult {
+        let (dst_id, value, size) = self.get_load_operands()?;
+
+        self.register_assign_sized(dst_id, value, size);
+
+        Ok(())
+    }
+
+    // Reads `size` bytes from memory into a register, clearing the upper bytes. Encoded the same way as LDR
+    pub(super) fn LDZX(&mut self) -> InstructionResult {
+        let (dst_id, value, _) = self.get_load_operands()?;
+
+        self.register_assign(dst_id, value);
+
+        Ok(())
+    }
+
+    // Reads `size` bytes from memory into a register, copying the sign bit into the upper bytes.
+    // Encoded the same way as LDR
+    pub(super) fn LDSX(&mut self) -> InstructionResult {
+        let (dst_id, value, size) = self.get_load_operands()?;
+
+        self.register_assign(dst_id, sign_extend(value, size));
+
+        Ok(())
+    }
+
+    // Copies the number of bytes in the count register from the address in the source register to the address
+    // in the destination register. The result is the same as copying one byte at a time from the lowest address up,
+    // so if the destination starts inside the source the bytes before it are repeated. The copy is done in chunks
+    // of up to BLOCK_CHUNK_SIZE bytes. When the instruction finishes the source and destination registers point past
+    // the copied bytes and the count register is 0. Flags are left untouched
+    pub(super) fn MCPY(&mut self) -> InstructionResult {
+        let (dst_id, src_id, count_id) = self.get_block_operands()?;
+
+        let length = self.register(count_id).min(BLOCK_CHUNK_SIZE);
+        let src = self.register(src_id);
+        let dst = self.register(dst_id);
+
+        debug_println!("Copying {} bytes from {:#x} to {:#x}", length, src, dst);
+
+        let mut buffer = vec![0u8; length as usize];
+        self.read(&mut buffer, src);
+
+        // A byte at a time copy would read the bytes it already wrote to the destination again
+        let distance = dst.wrapping_sub(src);
+
+        if distance != 0 && distance < length {
+            for index in distance as usize..length as usize {
+                buffer[index] = buffer[index - distance as usize];
+            }
+        }
+
+        self.write(&buffer, dst);
+
+        self.advance_block_operation(dst_id, Some(src_id), count_id, length);
+
+        Ok(())
+    }
+
+    // Fills the number of bytes in the count register, starting at the address in the destination register,
+    // with the lowest byte of the source register. Processed the same way as MCPY, except the source register
+    // isn't changed
+    pub(super) fn MSET(&mut self) -> InstructionResult {
+        let (dst_id, src_id, count_id) = self.get_block_operands()?;
+
+        let length = self.register(count_id).min(BLOCK_CHUNK_SIZE);
+        let value = self.register(src_id) as u8;
+
+        debug_println!(
+            "Filling {} bytes at {:#x} with {:#x}",
+            length,
+            self.register(dst_id),
+            value
+        );
+
+        let buffer = vec![value; length as usize];
+        self.write(&buffer, self.register(dst_id));
+
+        self.advance_block_operation(dst_id, None, count_id, length);
+
+        Ok(())
+    }
+
+    // Compares the number of bytes in the count register at the addresses in the destination and source registers.
+    // Processed the same way as MCPY, except it stops at the first pair of bytes that differ, leaving the
+    // destination and source registers pointing at them. The flags are set as if the differing bytes were
+    // compared with a one byte CMP, or with just Zero set if every byte was equal
+    pub(super) fn MCMP(&mut self) -> InstructionResult {
+        let (dst_id, src_id, count_id) = self.get_block_operands()?;
+
+        let length = self.register(count_id).min(BLOCK_CHUNK_SIZE);
+
+        debug_println!(
+            "Comparing {} bytes at {:#x} and {:#x}",
+            length,
+            self.register(dst_id),
+            self.register(src_id)
+        );
+
+        let mut lhs = vec![0u8; length as usize];
+        let mut rhs = vec![0u8; length as usize];
+        self.read(&mut lhs, self.register(dst_id));
+        self.read(&mut rhs, self.register(src_id));
+
+        match lhs.iter().zip(rhs.iter()).position(|(lhs, rhs)| lhs != rhs) {
+            Some(position) => {
+                let (lhs, rhs) = (lhs[position] as u64, rhs[position] as u64);
+                let result = lhs.wrapping_sub(rhs);
+
+                self.set_flag(CpuFlag::Zero, false);
+                self.set_flag(CpuFlag::Negative, get_sign_bit(result, Size::One));
+                self.set_flag(CpuFlag::Carry, lhs < rhs);
+                self.set_flag(
+                    CpuFlag::Overflow,
+                    does_signed_sub_overflow(lhs, rhs, Size::One),
+                );
+
+                self.register_add_assign(dst_id, position as u64);
+                self.register_add_assign(src_id, position as u64);
+                self.register_sub_assign(count_id, position as u64);
+            }
+
+            None => {
+                self.set_flag(CpuFlag::Zero, true);
+                self.set_flag(CpuFlag::Negative, false);
+                self.set_flag(CpuFlag::Carry, false);
+                self.set_flag(CpuFlag::Overflow, false);
+
+                self.advance_block_operation(dst_id, Some(src_id), count_id, length);
+            }
+        }
+
+        Ok(())
+    }
+
+    // The atomic instructions are encoded as a register byte in the same form as STR followed by an address.
+    // They access exactly `size` bytes of memory with a single locked read-modify-write
+
+    // Swaps the value of the register and the value in memory. Flags are left untouched
+    pub(super) fn XCHG(&mut self) -> InstructionResult {
+        let fetched_byte = self.fetch_byte();
+
+        let reg_id = get_register(self, fetched_byte, RegisterField::Low)?;
+
+        let size: Size = Size::try_from(1 << (fetched_byte >> 6 & 0b11))
+            .expect("Unrecoverable error. Size is not 1, 2, 4, or 8");
+
+        let address = get_effective_address(self)?;
+        self.check_alignment(address, size as u64)?;
+
+        debug_println!("Exchanging {:?} with memory at {:#x}", reg_id, address);
+
+        let new_value = self.register(reg_id);
+        let old_value = self.atomic_read_modify_write(address, size, |_| Some(new_value));
+
+        self.register_assign_sized(reg_id, old_value, size);
+
+        Ok(())
+    }
+
+    // Compares the value in memory with the register in bits 3-5 of the register byte. If they are equal the register
+    // in the lowest 3 bits is stored to memory, otherwise the value in memory is loaded into the compared register.
+    // The flags are set as if the compared register was compared with the value in memory using CMP,
+    // so Zero is set if the store happened
+    pub(super) fn CMPXCHG(&mut self) -> InstructionResult {
+        let fetched_byte = self.fetch_byte();
+
+        let new_id = get_register(self, fetched_byte, RegisterField::Low)?;
+        let expected_id = get_register(self, fetched_byte, RegisterField::High)?;
+
+        let size: Size = Size::try_from(1 << (fetched_byte >> 6 & 0b11))
+            .expect("Unrecoverable error. Size is not 1, 2, 4, or 8");
+
+        let address = get_effective_address(self)?;
+        self.check_alignment(address, size as u64)?;
+
+        debug_println!(
+            "Compare exchanging memory at {:#x} with {:?} if it equals {:?}",
+            address,
+            new_id,
+            expected_id
+        );
+
+        let expected = trunucate_value(self.register(expected_id), size);
+        let new_value = self.register(new_id);
+
+        let old_value = self.atomic_read_modify_write(address, size, |value| {
+            if value == expected {
+                Some(new_value)
+            } else {
+                None
+            }
+        });
+
+        let result = trunucate_value(expected.wrapping_sub(old_value), size);
+
+        self.set_flag(CpuFlag::Zero, result == 0);
+        self.set_flag(CpuFlag::Negative, get_sign_bit(result, size));
+        self.set_flag(CpuFlag::Carry, expected < old_value);
+        self.set_flag(
+            CpuFlag::Overflow,
+            does_signed_sub_overflow(expected, old_value, size),
+        );
+
+        if old_value != expected {
+            self.register_assign_sized(expected_id, old_value, size);
+        }
+
+        Ok(())
+    }
+
+    // Adds the register to the value in memory, and loads the previous value in memory into the register.
+    // The flags are set the same way ADD sets them for the addition
+    pub(super) fn XADD(&mut self) -> InstructionResult {
+        let fetched_byte = self.fetch_byte();
+
+        let reg_id = get_register(self, fetched_byte, RegisterField::Low)?;
+
+        let size: Size = Size::try_from(1 << (fetched_byte >> 6 & 0b11))
+            .expect("Unrecoverable error. Size is not 1, 2, 4, or 8");
+
+        let address = get_effective_address(self)?;
+        self.check_alignment(address, size as u64)?;
+
+        debug_println!("Fetch and adding {:?} to memory at {:#x}", reg_id, address);
+
+        let rhs_value = trunucate_value(self.register(reg_id), size);
+        let old_value = self
+            .atomic_read_modify_write(address, size, |value| Some(value.wrapping_add(rhs_value)));
+
+        let result = trunucate_value(old_value.wrapping_add(rhs_value), size);
+
+        self.set_flag(CpuFlag::Zero, result == 0);
+        self.set_flag(CpuFlag::Negative, get_sign_bit(result, size));
+        self.set_flag(CpuFlag::Carry, old_value > result);
+        self.set_overflow_flag(does_signed_add_overflow(old_value, rhs_value, size));
+
+        self.register_assign_sized(reg_id, old_value, size);
+
+        Ok(())
+    }
+
+    pub(super) fn LEA(&mut self) -> InstructionResult {
+        let fetched_byte = self.fetch_byte();
+
+        let dst_id = get_register(self, fetched_byte, RegisterField::Low)?;
+
+        let size: Size = Size::try_from(1 << (fetched_byte >> 6 & 0b11))
+            .expect("Unrecoverable error. Size is not 1, 2, 4, or 8");
+
+        let address = get_effective_address(self)?;
+
+        self.register_assign_sized(dst_id, address, size);
+
+        Ok(())
+    }
+
+    pub(super) fn JMP(&mut self) -> InstructionResult {
+        let address = get_effective_address(self)?;
+
+        self.branch(address);
+
+        Ok(())
+    }
+
+    pub(super) fn JZ(&mut self) -> InstructionResult {
+        self.conditional_jump(Condition::Zero)
+    }
+
+    pub(super) fn JNZ(&mut self) -> InstructionResult {
+        self.conditional_jump(Condition::NotZero)
+    }
+
+    pub(super) fn JO(&mut self) -> InstructionResult {
+        self.conditional_jump(Condition::Overflow)
+    }
+
+    pub(super) fn JNO(&mut self) -> InstructionResult {
+        self.conditional_jump(Condition::NotOverflow)
+    }
+
+    pub(super) fn JS(&mut self) -> InstructionResult {
+        self.conditional_jump(Condition::Negative)
+    }
+
+    pub(super) fn JNS(&mut self) -> InstructionResult {
+        self.conditional_jump(Condition::NotNegative)
+    }
+
+    pub(super) fn JC(&mut self) -> InstructionResult {
+        self.conditional_jump(Condition::Carry)
+    }
+
+    pub(super) fn JNC(&mut self) -> InstructionResult {
+        self.conditional_jump(Condition::NotCarry)
+    }
+
+    pub(super) fn JBE(&mut self) -> InstructionResult {
+        self.conditional_jump(Condition::BelowOrEqual)
+    }
+
+    pub(super) fn JA(&mut self) -> InstructionResult {
+        self.conditional_jump(Condition::Above)
+    }
+
+    pub(super) fn JL(&mut self) -> InstructionResult {
+        self.conditional_jump(Condition::Less)
+    }
+
+    pub(super) fn JGE(&mut self) -> InstructionResult {
+        self.conditional_jump(Condition::GreaterOrEqual)
+    }
+
+    pub(super) fn JLE(&mut self) -> InstructionResult {
+        self.conditional_jump(Condition::LessOrEqual)
+    }
+
+    pub(super) fn JG(&mut self) -> InstructionResult {
+        self.conditional_jump(Condition::Greater)
+    }
+
+    pub(super) fn CALL(&mut self) -> InstructionResult {
+        let address = get_effective_address(self)?;
+
+        self.push_qword(self.register(RegisterId::Ip));
+
+        self.branch(address);
+
+        Ok(())
+    }
+
+    pub(super) fn RET(&mut self) -> InstructionResult {
+        let return_address = self.pop_qword();
+
+        self.branch(return_address);
+
+        Ok(())
+    }
+
+    // Sets the IdtBase control register. Kept as a shorter form of MTCR
+    pub(super) fn LIDT(&mut self) -> InstructionResult {
+        let address = get_effective_address(self)?;
+
+        self.require_supervisor()?;
+
+        self.control_register_assign(ControlRegister::IdtBase, address);
+
+        Ok(())
+    }
+
+    // Returns from an interrupt handler, popping the frame described in reserved_idt_entries.rs
+    pub(super) fn RETI(&mut self) -> InstructionResult {
+        self.require_supervisor()?;
+
+        let _fault_address = self.pop_qword();
+        let _error_code = self.pop_qword();
+        let address = self.pop_qword();
+
+        // The flags are restored before the mode, since users can't change InterruptEnable
+        self.pop_flags();
+
+        let mode = self.pop_qword() & MODE_WRITABLE_BITS;
+        let sp = self.pop_qword();
+
+        self.control_register_assign(ControlRegister::Mode, mode);
+        self.register_assign(RegisterId::Sp, sp);
+
+        self.branch(address);
+
+        Ok(())
+    }
+
+    pub(super) fn INT(&mut self) -> InstructionResult {
+        let idt_entry = self.fetch_byte();
+        self.interrupt_request(idt_entry);
+
+        Ok(())
+    }
+
+    // Raises the DEBUG trap, for debuggers to place over the first byte of an instruction
+    pub(super) fn BRK(&mut self) -> InstructionResult {
+        self.debug_trap(DebugTrap::Breakpoint);
+
+        Ok(())
+    }
+
+    // Copies a control register to a general purpose register. Encoded as a register byte with the general purpose
+    // register in the lowest 3 bits, followed by a byte with the number of the control register
+    pub(super) fn MFCR(&mut self) -> InstructionResult {
+        let (id, register) = self.get_control_register_operands()?;
+
+        self.register_assign(id, self.control_register(register));
+
+        Ok(())
+    }
+
+    // Copies a general purpose register to a control register. Encoded the same way as MFCR.
+    // Setting a mode bit that isn't supported raises GENERAL_PROTECTION_FAULT
+    pub(super) fn MTCR(&mut self) -> InstructionResult {
+        let (id, register) = self.get_control_register_operands()?;
+
+        let value = self.register(id);
+
+        if let ControlRegister::Mode = register {
+            if value & MODE_PAGING_ENABLE != 0 && self.features() & MEMORY_MANAGEMENT_UNIT == 0 {
+                debug_println!("Paging is not supported");
+                return Err(GENERAL_PROTECTION_FAULT);
+            }
+
+            if value & !MODE_WRITABLE_BITS != 0 {
+                return Err(GENERAL_PROTECTION_FAULT);
+            }
+        }
+
+        debug_println!("Setting {:?} to {:#x}", register, value);
+
+        self.control_register_assign(register, value);
+
+        Ok(())
+    }
+
+    // Reads a performance counter. Encoded the same way as MOV, where the source operand is the index of the
+    // counter, as listed in performance_counters.rs. Reading a counter that doesn't exist is invalid
+    pub(super) fn RDPMC(&mut self) -> InstructionResult {
+        let (dst_id, index, size) = get_binary_operands(self)?;
+
+        let value = match self.performance_counters.read(index) {
+            Some(value) => value,
+            None => return Err(INVALID_INSTRUCTION),
+        };
+
+        debug_println!("Read performance counter {} = {}", index, value);
+
+        self.register_assign_sized(dst_id, value, size);
+
+        Ok(())
+    }
+
+    // Enables, disables and resets the performance counters with the value of the register in the lowest 3 bits,
+    // as described by `PerformanceCounters::control`. Only the supervisor can control the counters
+    pub(super) fn PMCTL(&mut self) -> InstructionResult {
+        let fetched_byte = self.fetch_byte();
+
+        let src_id = get_register(self, fetched_byte, RegisterField::Low)?;
+
+        self.require_supervisor()?;
+
+        self.performance_counters.control(self.register(src_id));
+
+        Ok(())
+    }
+
+    // Identifies the CPU. The leaf to read is taken from X0 and the result is written to X0-X3:
+    //   Leaf 0: X0 is the highest supported leaf and X1-X2 hold the 16 byte vendor string
+    //   Leaf 1: X0 is the emulator version, with the major version in bits 32-47, the minor version in bits 16-31
+    //           and the patch version in bits 0-15. X1 is the feature bitmap that FEAT returns. X2 holds the number
+    //           of general purpose registers in bits 0-7, floating point registers in bits 8-15 and vector
+    //           registers in bits 16-23
+    //   Leaf 2: X0 is the ID of the core executing CPUID, counting from 0, and X1 is the number of cores
+    // Every register is set to 0 for leaves that don't exist. Registers a leaf doesn't use are set to 0 as well
+    pub(super) fn CPUID(&mut self) -> InstructionResult {
+        let leaf = self.register(RegisterId::X0);
+
+        debug_println!("Reading CPUID leaf {}", leaf);
+
+        let result = match leaf {
+            CPUID_VENDOR_LEAF => [
+                CPUID_TOPOLOGY_LEAF,
+                u64::from_le_bytes(CPUID_VENDOR[..8].try_into().unwrap()),
+                u64::from_le_bytes(CPUID_VENDOR[8..].try_into().unwrap()),
+                0,
+            ],
+
+            CPUID_FEATURE_LEAF => {
+                let version_part = |part: &str| part.parse::<u64>().unwrap_or(0) & 0xffff;
+
+                let version = version_part(env!("CARGO_PKG_VERSION_MAJOR")) << 32
+                    | version_part(env!("CARGO_PKG_VERSION_MINOR")) << 16
+                    | version_part(env!("CARGO_PKG_VERSION_PATCH"));
+
+                // SP and IP aren't general purpose registers
+                let register_counts = (REGISTER_COUNT - 2) as u64
+                    | (FLOAT_REGISTER_COUNT as u64) << 8
+                    | (VECTOR_REGISTER_COUNT as u64) << 16;
+
+                [version, self.features(), register_counts, 0]
+            }
+
+            CPUID_TOPOLOGY_LEAF => [self.core_id, self.core_count, 0, 0],
+
+            _ => [0; 4],
+        };
+
+        let ids = [
+            RegisterId::X0,
+            RegisterId::X1,
+            RegisterId::X2,
+            RegisterId::X3,
+        ];
+
+        for (id, value) in ids.into_iter().zip(result) {
+            self.register_assign(id, value);
+        }
+
+        Ok(())
+    }
+
+    // Requests a service from the system. In hosted mode the emulator runs the system call described in
+    // system_call_host.rs, taking the number from X0 and the arguments from X1-X4 and returning the result in X0.
+    // Otherwise the SYSTEM_CALL interrupt is raised, returning to the next instruction, so an operating system
+    // can handle it
+    pub(super) fn SYSCALL(&mut self) -> InstructionResult {
+        let system_call_host = match &self.system_call_host {
+            Some(system_call_host) => Rc::clone(system_call_host),
+            None => {
+                self.non_maskable_interrupt_request(SYSTEM_CALL);
+                return Ok(());
+            }
+        };
+
+        let number = self.register(RegisterId::X0);
+        let args = [
+            self.register(RegisterId::X1),
+            self.register(RegisterId::X2),
+            self.register(RegisterId::X3),
+            self.register(RegisterId::X4),
+        ];
+
+        let mut system_call_host = system_call_host.borrow_mut();
+
+        let result = system_call_host.system_call(&mut self.address_bus.borrow_mut(), number, args);
+
+        if system_call_host.exit_status().is_some() {
+            self.halted = true;
+        }
+
+        self.register_assign(RegisterId::X0, result);
+
+        Ok(())
+    }
+
+    pub(super) fn CLI(&mut self) -> InstructionResult {
+        self.require_supervisor()?;
+
+        self.set_flag(CpuFlag::InterruptEnable, false);
+
+        Ok(())
+    }
+
+    pub(super) fn STI(&mut self) -> InstructionResult {
+        self.require_supervisor()?;
+
+        self.set_flag(CpuFlag::InterruptEnable, true);
+
+        Ok(())
+    }
+
+    pub(super) fn CLC(&mut self) -> InstructionResult {
+        self.set_flag(CpuFlag::Carry, false);
+
+        Ok(())
+    }
+
+    pub(super) fn STC(&mut self) -> InstructionResult {
+        self.set_flag(CpuFlag::Carry, true);
+
+        Ok(())
+    }
+
+    pub(super) fn CMC(&mut self) -> InstructionResult {
+        self.set_flag(CpuFlag::Carry, !self.get_flag(CpuFlag::Carry));
+
+        Ok(())
+    }
+
+    // Loads the feature bitmap into the register in the lowest 3 bits. The bits are listed in features.rs
+    pub(super) fn FEAT(&mut self) -> InstructionResult {
+        let fetched_byte = self.fetch_byte();
+
+        let dst_id = get_register(self, fetched_byte, RegisterField::Low)?;
+
+        self.register_assign(dst_id, self.features());
+
+        Ok(())
+    }
+
+    pub(super) fn IN(&mut self) -> InstructionResult {
+        self.require_supervisor()?;
+
+        let fetched_byte = self.fetch_byte();
+
+        let dst_id = get_register(self, fetched_byte, RegisterField::Low)?;
+
+        let port = self.fetch_word();
+
+        let value = self.port_bus_read(port);
+
+        self.register_assign(dst_id, value);
+
+        Ok(())
+    }
+
+    pub(super) fn OUT(&mut self) -> InstructionResult {
+        self.require_supervisor()?;
+
+        let fetched_byte = self.fetch_byte();
+
+        let src_id = get_register(self, fetched_byte, RegisterField::Low)?;
+
+        let port = self.fetch_word();
+
+        self.port_bus_write(port, self.register(src_id));
+
+        Ok(())
+    }
+
+    pub(super) fn NOP(&mut self) -> InstructionResult {
+        Ok(())
+    }
+
+    // Prefix that extends every register field of the next instruction by two bits, making X5-X15 addressable.
+    // The byte following the prefix holds the extension bits for each field, laid out as described by `RegisterField`
+    pub(super) fn EXT(&mut self) -> InstructionResult {
+        // Prefixes can't be stacked
+        if self.register_extension.is_some() {
+            return Err(INVALID_INSTRUCTION);
+        }
+
+        let extension = self.fetch_byte();
+        let opcode = self.fetch_byte();
+
+        let (instruction, callback) = match self.lookup_instruction(opcode) {
+            Some(LookupEntry {
+                instruction,
+                callback: Some(callback),
+                ..
+            }) => (instruction, *callback),
+            _ => return Err(INVALID_INSTRUCTION),
+        };
+
+        debug_println!(
+            "Executing instruction '{}' {:#x} with register extension {:#010b}",
+            instruction,
+            opcode,
+            extension
+        );
+
+        self.register_extension = Some(extension);
+        let result = callback(self);
+        self.register_extension = None;
+
+        result
+    }
+
+    // Prefix that makes the destination of the next instruction a memory location. Only instructions listed in
+    // MEMORY_OPERAND_INSTRUCTIONS can be prefixed, and their operands are decoded by get_binary_memory_operands
+    pub(super) fn MEMD(&mut self) -> InstructionResult {
+        self.memory_operand_prefix(MemoryOperand::Destination)
+    }
+
+    // Prefix that makes the source of the next instruction a memory location. Encoded the same way as MEMD
+    pub(super) fn MEMS(&mut self) -> InstructionResult {
+        self.memory_operand_prefix(MemoryOperand::Source)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cpu::features::REGISTER_EXTENSION;
+    use crate::cpu::performance_counters::{PerformanceCounter, PERFORMANCE_COUNTER_COUNT};
+    use crate::cpu::test_cpu::{execute, test_cpu, CODE_ADDRESS};
+    use crate::cpu::DebugHook;
+
+    use std::cell::RefCell;
+
+    // The operand byte of the instructions that take the same operands as ADD
+    fn operands(size: Size, dst: RegisterId, src: Option<RegisterId>) -> u8 {
+        let size_bits = (size as u8).trailing_zeros() as u8;
+        size_bits << 6 | (dst as u8) << 3 | src.map_or(0, |src| src as u8)
+    }
+
+    fn flags(cpu: &Cpu) -> [bool; 4] {
+        [
+            cpu.get_flag(CpuFlag::Negative),
+            cpu.get_flag(CpuFlag::Overflow),
+            cpu.get_flag(CpuFlag::Zero),
+            cpu.get_flag(CpuFlag::Carry),
+        ]
+    }
+
+    #[test]
+    fn shift_carry_out_at_and_past_the_width() {
+        assert_eq!(shift_left(0x81, 1, false, Size::One), (0x02, true));
+        assert_eq!(shift_left(0x81, 8, false, Size::One), (0, true));
+        assert_eq!(shift_left(0x81, 9, false, Size::One), (0, false));
+        assert_eq!(shift_left(0b11, 63, false, Size::Eight), (1 << 63, true));
+
+        assert_eq!(shift_right(0x81, 1, false, Size::One), (0x40, true));
+        assert_eq!(shift_right(0x81, 8, false, Size::One), (0, true));
+        assert_eq!(shift_right(0x81, 9, false, Size::One), (0, false));
+
+        assert_eq!(
+            shift_right_arithmetic(0x80, 8, false, Size::One),
+            (0xff, true)
+        );
+        assert_eq!(
+            shift_right_arithmetic(0x80, 63, false, Size::One),
+            (0xff, true)
+        );
+        assert_eq!(
+            shift_right_arithmetic(0x40, 63, false, Size::One),
+            (0, false)
+        );
+    }
+
+    #[test]
+    fn rotate_carry_out() {
+        assert_eq!(rotate_left(0x81, 1, false, Size::One), (0x03, true));
+        assert_eq!(rotate_left(0x81, 8, false, Size::One), (0x81, true));
+        assert_eq!(rotate_right(0x81, 1, false, Size::One), (0xc0, true));
+        assert_eq!(rotate_right(0x01, 16, false, Size::Two), (0x01, false));
+    }
+
+    #[test]
+    fn rotate_through_carry() {
+        assert_eq!(
+            rotate_left_through_carry(0x80, 1, false, Size::One),
+            (0x00, true)
+        );
+        assert_eq!(
+            rotate_left_through_carry(0x80, 1, true, Size::One),
+            (0x01, true)
+        );
+        assert_eq!(
+            rotate_left_through_carry(0x00, 8, true, Size::One),
+            (0x80, false)
+        );
+        // The carry makes the rotation 9 bits wide for a byte
+        assert_eq!(
+            rotate_left_through_carry(0x80, 9, false, Size::One),
+            (0x80, false)
+        );
+
+        assert_eq!(
+            rotate_right_through_carry(0x01, 1, false, Size::One),
+            (0x00, true)
+        );
+        assert_eq!(
+            rotate_right_through_carry(0x00, 1, true, Size::One),
+            (0x80, false)
+        );
+        assert_eq!(
+            rotate_right_through_carry(0x01, 9, true, Size::One),
+            (0x01, true)
+        );
+        assert_eq!(
+            rotate_right_through_carry(0x01, 1, true, Size::Eight),
+            (1 << 63, true)
+        );
+    }
+
+    #[test]
+    fn shift_by_zero_leaves_the_destination_and_flags() {
+        // SHL X0, 0 and SHL X0, 64, which is masked to 0
+        let mut code = vec![0x07, operands(Size::Eight, RegisterId::X0, None)];
+        code.extend_from_slice(&0u64.to_le_bytes());
+        code.extend_from_slice(&[0x07, operands(Size::Eight, RegisterId::X0, None)]);
+        code.extend_from_slice(&64u64.to_le_bytes());
+
+        let mut cpu = test_cpu(&code);
+        cpu.register_assign(RegisterId::X0, 0x8000_0000_0000_0001);
+        cpu.set_flag(CpuFlag::Carry, true);
+        cpu.set_flag(CpuFlag::Zero, true);
+
+        cpu.step(2);
+
+        assert_eq!(cpu.register(RegisterId::X0), 0x8000_0000_0000_0001);
+        assert_eq!(flags(&cpu), [false, false, true, true]);
+    }
+
+    #[test]
+    fn shift_by_the_width_through_the_instruction() {
+        // SHR X0, X1 on a byte
+        let code = [
+            0x17,
+            operands(Size::One, RegisterId::X0, Some(RegisterId::X1)),
+        ];
+
+        let mut cpu = test_cpu(&code);
+        cpu.register_assign(RegisterId::X0, 0x1281);
+        cpu.register_assign(RegisterId::X1, 8);
+
+        cpu.step(1);
+
+        assert_eq!(cpu.register(RegisterId::X0), 0x1200);
+        // The last bit shifted out was bit 7, and the sign bit changed
+        assert_eq!(flags(&cpu), [false, true, true, true]);
+    }
+
+    #[test]
+    fn add_with_carry_overflow() {
+        assert!(!does_unsigned_add_with_carry_overflow(
+            0xfe,
+            0,
+            true,
+            Size::One
+        ));
+        assert!(does_unsigned_add_with_carry_overflow(
+            0xff,
+            0,
+            true,
+            Size::One
+        ));
+        assert!(does_unsigned_add_with_carry_overflow(
+            u64::MAX,
+            u64::MAX,
+            true,
+            Size::Eight
+        ));
+
+        assert!(!does_signed_add_with_carry_overflow(
+            0x7e,
+            0,
+            true,
+            Size::One
+        ));
+        assert!(does_signed_add_with_carry_overflow(
+            0x7f,
+            0,
+            true,
+            Size::One
+        ));
+        assert!(!does_signed_add_with_carry_overflow(
+            0xff,
+            0,
+            true,
+            Size::One
+        ));
+    }
+
+    #[test]
+    fn subtract_with_borrow_overflow() {
+        assert!(!does_unsigned_sub_with_borrow_overflow(
+            1,
+            0,
+            true,
+            Size::One
+        ));
+        assert!(does_unsigned_sub_with_borrow_overflow(
+            0,
+            0,
+            true,
+            Size::One
+        ));
+        assert!(does_unsigned_sub_with_borrow_overflow(
+            0,
+            u64::MAX,
+            false,
+            Size::Eight
+        ));
+
+        assert!(!does_signed_sub_with_borrow_overflow(
+            0x81,
+            0,
+            true,
+            Size::One
+        ));
+        assert!(does_signed_sub_with_borrow_overflow(
+            0x80,
+            0,
+            true,
+            Size::One
+        ));
+    }
+
+    #[test]
+    fn add_with_carry_chains_a_128_bit_addition() {
+        // ADD X0, X2 and ADC X1, X3 add X3:X2 to X1:X0
+        let code = [
+            0x03,
+            operands(Size::Eight, RegisterId::X0, Some(RegisterId::X2)),
+            0x43,
+            operands(Size::Eight, RegisterId::X1, Some(RegisterId::X3)),
+        ];
+
+        let mut cpu = test_cpu(&code);
+        cpu.register_assign(RegisterId::X0, u64::MAX);
+        cpu.register_assign(RegisterId::X1, 1);
+        cpu.register_assign(RegisterId::X2, 1);
+        cpu.register_assign(RegisterId::X3, 2);
+
+        cpu.step(1);
+        assert!(cpu.get_flag(CpuFlag::Carry));
+
+        cpu.step(1);
+        assert_eq!(cpu.register(RegisterId::X0), 0);
+        assert_eq!(cpu.register(RegisterId::X1), 4);
+        assert_eq!(flags(&cpu), [false, false, false, false]);
+    }
+
+    #[test]
+    fn add_with_carry_sets_the_carry_out() {
+        // ADC X0, X1 with the carry set
+        let code = [
+            0x43,
+            operands(Size::Eight, RegisterId::X0, Some(RegisterId::X1)),
+        ];
+
+        let mut cpu = test_cpu(&code);
+        cpu.register_assign(RegisterId::X0, u64::MAX);
+        cpu.register_assign(RegisterId::X1, u64::MAX);
+        cpu.set_flag(CpuFlag::Carry, true);
+
+        cpu.step(1);
+
+        assert_eq!(cpu.register(RegisterId::X0), u64::MAX);
+        assert_eq!(flags(&cpu), [true, false, false, true]);
+    }
+
+    #[test]
+    fn subtract_with_borrow_chains_a_128_bit_subtraction() {
+        // SUB X0, X2 and SBB X1, X3 subtract X3:X2 from X1:X0
+        let code = [
+            0x13,
+            operands(Size::Eight, RegisterId::X0, Some(RegisterId::X2)),
+            0x53,
+            operands(Size::Eight, RegisterId::X1, Some(RegisterId::X3)),
+        ];
+
+        let mut cpu = test_cpu(&code);
+        cpu.register_assign(RegisterId::X0, 0);
+        cpu.register_assign(RegisterId::X1, 5);
+        cpu.register_assign(RegisterId::X2, 1);
+        cpu.register_assign(RegisterId::X3, 2);
+
+        cpu.step(1);
+        assert!(cpu.get_flag(CpuFlag::Carry));
+
+        cpu.step(1);
+        assert_eq!(cpu.register(RegisterId::X0), u64::MAX);
+        assert_eq!(cpu.register(RegisterId::X1), 2);
+        assert!(!cpu.get_flag(CpuFlag::Carry));
+    }
+
+    const SIZES: [Size; 4] = [Size::One, Size::Two, Size::Four, Size::Eight];
+
+    // Runs IDIV or IMOD X0, X1 with the given size
+    fn signed_division(opcode: u8, size: Size, lhs: u64, rhs: u64) -> (InstructionResult, Cpu) {
+        let code = [opcode, operands(size, RegisterId::X0, Some(RegisterId::X1))];
+
+        let mut cpu = test_cpu(&code);
+        cpu.register_assign(RegisterId::X0, lhs);
+        cpu.register_assign(RegisterId::X1, rhs);
+
+        (execute(&mut cpu), cpu)
+    }
+
+    #[test]
+    fn signed_division_of_the_most_negative_value_by_minus_one() {
+        for size in SIZES {
+            let min = 1 << (size as u64 * 8 - 1);
+            let minus_one = trunucate_value(u64::MAX, size);
 
-        self.set_flag(CpuFlag::Zero, result == 0);
-        self.set_flag(CpuFlag::Negative, get_sign_bit(result, size));
+            let (result, cpu) = signed_division(0x73, size, min, minus_one);
+            assert_eq!(result, Err(DIVIDE_OVERFLOW), "IDIV {:?}", size);
+            assert_eq!(cpu.register(RegisterId::X0), min);
 
-        self.register_assign_sized(dst_id, result, size);
+            let (result, cpu) = signed_division(0x93, size, min, minus_one);
+            assert_eq!(result, Ok(()), "IMOD {:?}", size);
+            assert_eq!(trunucate_value(cpu.register(RegisterId::X0), size), 0);
+            assert!(cpu.get_flag(CpuFlag::Zero));
+        }
+    }
 
-        Ok(())
+    #[test]
+    fn signed_division_by_zero() {
+        for size in SIZES {
+            // Only the lowest size bytes of the divisor count
+            let zero = if let Size::Eight = size {
+                0
+            } else {
+                1 << (size as u64 * 8)
+            };
+
+            for opcode in [0x73, 0x93] {
+                let (result, cpu) = signed_division(opcode, size, 7, zero);
+                assert_eq!(result, Err(DIVIDE_BY_ZERO), "{:#x} {:?}", opcode, size);
+                assert_eq!(cpu.register(RegisterId::X0), 7);
+            }
+        }
     }
 
-    pub(super) fn CMP(&mut self) -> InstructionResult {
-        let fetched_byte = self.fetch_byte();
+    #[test]
+    fn signed_division_rounds_toward_zero() {
+        for size in SIZES {
+            let minus_seven = trunucate_value(-7i64 as u64, size);
+
+            let (_, cpu) = signed_division(0x73, size, minus_seven, 2);
+            assert_eq!(
+                cpu.register(RegisterId::X0),
+                trunucate_value(-3i64 as u64, size)
+            );
+
+            let (_, cpu) = signed_division(0x93, size, minus_seven, 2);
+            assert_eq!(
+                cpu.register(RegisterId::X0),
+                trunucate_value(-1i64 as u64, size)
+            );
+        }
+    }
 
-        let src_id: Option<RegisterId> = RegisterId::from_u8(fetched_byte & 0b111);
+    // Runs MCPY X0, X1, X2 until every byte is copied and returns the memory from `start` to `end`, along with the
+    // same memory after copying one byte at a time
+    fn block_copy(dst: u64, src: u64, length: u64, start: u64, end: u64) -> (Vec<u8>, Vec<u8>) {
+        let code = [
+            0x0a,
+            (RegisterId::X1 as u8) << 3 | RegisterId::X0 as u8,
+            RegisterId::X2 as u8,
+        ];
 
-        let dst_id: RegisterId = match RegisterId::from_u8(fetched_byte >> 3 & 0b111) {
-            Some(reg_id) => reg_id,
-            None => return Err(INVALID_INSTRUCTION),
-        };
+        let mut cpu = test_cpu(&code);
 
-        let size: Size = Size::try_from(1 << (fetched_byte >> 6 & 0b11))
-            .expect("Unrecoverable error. Size is not 1, 2, 4, or 8");
+        let mut memory: Vec<u8> = (0..end - start)
+            .map(|byte| (byte * 7 % 251) as u8)
+            .collect();
+        cpu.write(&memory, start);
 
-        let rhs_value: u64;
+        cpu.register_assign(RegisterId::X0, dst);
+        cpu.register_assign(RegisterId::X1, src);
+        cpu.register_assign(RegisterId::X2, length);
 
-        if let Some(src_id) = src_id {
-            rhs_value = self.register(src_id);
-        } else {
-            rhs_value = self.fetch_sized(size);
+        while cpu.register(RegisterId::X2) != 0 {
+            cpu.step(1);
         }
 
-        debug_println!("Comparing {:?} with {}", dst_id, rhs_value);
+        assert_eq!(cpu.register(RegisterId::X0), dst + length);
+        assert_eq!(cpu.register(RegisterId::X1), src + length);
 
-        let result = self.register(dst_id).wrapping_sub(rhs_value);
+        let mut copied = vec![0u8; memory.len()];
+        cpu.read(&mut copied, start);
 
-        self.set_flag(CpuFlag::Zero, result == 0);
-        self.set_flag(CpuFlag::Negative, get_sign_bit(result, size));
+        for offset in 0..length {
+            memory[(dst + offset - start) as usize] = memory[(src + offset - start) as usize];
+        }
 
-        self.set_flag(
-            CpuFlag::Carry,
-            trunucate_value(self.register(dst_id), size) < trunucate_value(result, size),
-        );
+        (copied, memory)
+    }
 
-        self.set_flag(
-            CpuFlag::Overflow,
-            does_signed_sub_overflow(self.register(dst_id), rhs_value, size),
-        );
+    #[test]
+    fn block_copy_into_the_source_repeats_bytes_across_chunks() {
+        let (copied, expected) = block_copy(0x400a, 0x4000, 0x2000, 0x4000, 0x600a);
+        assert!(copied == expected);
+    }
 
-        Ok(())
+    #[test]
+    fn block_copy_below_the_source() {
+        let (copied, expected) = block_copy(0x4000, 0x400a, 0x2000, 0x4000, 0x600a);
+        assert!(copied == expected);
     }
 
-    pub(super) fn PUSH(&mut self) -> InstructionResult {
-        let fetched_byte = self.fetch_byte();
+    #[test]
+    fn block_copy_without_overlap() {
+        let (copied, expected) = block_copy(0x8000, 0x4000, 0x1234, 0x4000, 0x9234);
+        assert!(copied == expected);
+    }
 
-        let src_id: RegisterId = match RegisterId::from_u8(fetched_byte & 0b111) {
-            Some(reg_id) => reg_id,
-            None => return Err(INVALID_INSTRUCTION),
-        };
+    // Decodes the address at CODE_ADDRESS, returning it along with the address right after it
+    fn effective_address(bytes: &[u8], registers: &[(RegisterId, u64)]) -> (Result<u64, u8>, u64) {
+        let mut cpu = test_cpu(bytes);
 
-        debug_println!("Pushing register {:?}", src_id);
+        for &(id, value) in registers {
+            cpu.register_assign(id, value);
+        }
 
-        self.push_qword(self.register(src_id));
+        let address = get_effective_address(&mut cpu);
 
-        Ok(())
+        (address, cpu.register(RegisterId::Ip))
     }
 
-    pub(super) fn POP(&mut self) -> InstructionResult {
-        let fetched_byte = self.fetch_byte();
+    #[test]
+    fn short_displacements_are_sign_extended() {
+        // [X0 - 0x10] with an 8 bit displacement
+        let (address, end) = effective_address(&[0b01_000_001, 0xf0], &[(RegisterId::X0, 0x2000)]);
+        assert_eq!(address, Ok(0x1ff0));
+        assert_eq!(end, CODE_ADDRESS + 2);
 
-        let dst_id: RegisterId = match RegisterId::from_u8(fetched_byte & 0b111) {
-            Some(reg_id) => reg_id,
-            None => return Err(INVALID_INSTRUCTION),
-        };
+        // [X0 - 0x100] with a 32 bit displacement
+        let mut bytes = vec![0b10_000_001];
+        bytes.extend_from_slice(&(-0x100i32).to_le_bytes());
 
-        debug_println!("Popping stack into register {:?}", dst_id);
+        let (address, end) = effective_address(&bytes, &[(RegisterId::X0, 0x2000)]);
+        assert_eq!(address, Ok(0x1f00));
+        assert_eq!(end, CODE_ADDRESS + 5);
+    }
 
-        let popped = self.pop_qword();
-        self.register_assign(dst_id, popped);
+    #[test]
+    fn ip_relative_addresses_are_relative_to_the_end_of_the_address() {
+        // [IP + 0x10] with an 8 bit displacement
+        let (address, _) = effective_address(&[0b11_000_000, 0b011, 0x10], &[]);
+        assert_eq!(address, Ok(CODE_ADDRESS + 3 + 0x10));
 
-        Ok(())
+        // [IP - 0x20] with a 32 bit displacement
+        let mut bytes = vec![0b11_000_000, 0b101];
+        bytes.extend_from_slice(&(-0x20i32).to_le_bytes());
+
+        let (address, _) = effective_address(&bytes, &[]);
+        assert_eq!(address, Ok(CODE_ADDRESS + 6 - 0x20));
     }
 
-    pub(super) fn PUSHF(&mut self) -> InstructionResult {
-        self.push_flags();
+    #[test]
+    fn invalid_extension_bytes() {
+        // IP-relative with a base register
+        let (address, _) = effective_address(&[0b11_000_001, 0b001], &[]);
+        assert_eq!(address, Err(INVALID_INSTRUCTION));
 
-        Ok(())
+        // A reserved bit
+        let (address, _) = effective_address(&[0b11_000_001, 0b100000], &[]);
+        assert_eq!(address, Err(INVALID_INSTRUCTION));
     }
 
-    pub(super) fn POPF(&mut self) -> InstructionResult {
-        self.pop_flags();
+    #[test]
+    fn scaled_index() {
+        // [X0 + X1 * 8]
+        let (address, _) = effective_address(
+            &[0b11_010_001, 3 << 3],
+            &[(RegisterId::X0, 0x1000), (RegisterId::X1, 3)],
+        );
+        assert_eq!(address, Ok(0x1018));
 
-        Ok(())
+        // [X1 * 4 + 4] with an 8 bit displacement
+        let (address, _) =
+            effective_address(&[0b11_010_000, 2 << 3 | 0b010, 4], &[(RegisterId::X1, 5)]);
+        assert_eq!(address, Ok(24));
+
+        // [IP + X1 * 2]
+        let (address, _) =
+            effective_address(&[0b11_010_000, 1 << 3 | 0b001], &[(RegisterId::X1, 4)]);
+        assert_eq!(address, Ok(CODE_ADDRESS + 2 + 8));
     }
 
-    pub(super) fn STR(&mut self) -> InstructionResult {
-        let fetched_byte = self.fetch_byte();
+    #[test]
+    fn only_the_extension_byte_scales_the_index() {
+        // [X0 + X1 + 0x10] with an 8 bit displacement
+        let (address, _) = effective_address(
+            &[0b01_010_001, 0x10],
+            &[(RegisterId::X0, 0x1000), (RegisterId::X1, 3)],
+        );
+        assert_eq!(address, Ok(0x1013));
+    }
 
-        let src_id: RegisterId = match RegisterId::from_u8(fetched_byte & 0b111) {
-            Some(reg_id) => reg_id,
-            None => return Err(INVALID_INSTRUCTION),
-        };
+    #[test]
+    fn no_memory_management_unit_is_reported() {
+        // FEAT X0
+        let mut cpu = test_cpu(&[0x88, RegisterId::X0 as u8]);
+        cpu.boot_parameters.features = u64::MAX;
 
-        let size: Size = Size::try_from(1 << (fetched_byte >> 6 & 0b11))
-            .expect("Unrecoverable error. Size is not 1, 2, 4, or 8");
+        execute(&mut cpu).unwrap();
 
-        let address = get_effective_address(self);
+        assert_eq!(cpu.register(RegisterId::X0) & MEMORY_MANAGEMENT_UNIT, 0);
+    }
 
-        self.write(&self.register(src_id).to_le_bytes(), address);
+    // Runs the instruction with trap-on-overflow enabled and returns whether the OVERFLOW trap is pending
+    fn overflow_trap(code: &[u8], registers: &[(RegisterId, u64)]) -> bool {
+        let mut cpu = test_cpu(code);
+        cpu.control_register_assign(ControlRegister::Mode, MODE_TRAP_ON_OVERFLOW);
 
-        Ok(())
+        for &(id, value) in registers {
+            cpu.register_assign(id, value);
+        }
+
+        execute(&mut cpu).unwrap();
+
+        cpu.overflow_trap_pending
     }
 
-    pub(super) fn LDR(&mut self) -> InstructionResult {
-        let fetched_byte = self.fetch_byte();
+    #[test]
+    fn widening_multiplies_and_shifts_trap_on_overflow() {
+        let operands = operands(Size::Eight, RegisterId::X0, Some(RegisterId::X1));
+        let large = [(RegisterId::X0, 1 << 40), (RegisterId::X1, 1 << 40)];
+        let small = [(RegisterId::X0, 3), (RegisterId::X1, 4)];
+
+        // MULW and IMULW X0, X1 with the upper half in X2
+        for opcode in [0xa3, 0xb3] {
+            assert!(overflow_trap(
+                &[opcode, operands, RegisterId::X2 as u8],
+                &large
+            ));
+            assert!(!overflow_trap(
+                &[opcode, operands, RegisterId::X2 as u8],
+                &small
+            ));
+        }
 
-        let dst_id: RegisterId = match RegisterId::from_u8(fetched_byte & 0b111) {
-            Some(reg_id) => reg_id,
-            None => return Err(INVALID_INSTRUCTION),
-        };
+        // SHL X0, X1 changing the sign bit and keeping it
+        assert!(overflow_trap(
+            &[0x07, operands],
+            &[(RegisterId::X0, 1 << 62), (RegisterId::X1, 1)]
+        ));
+        assert!(!overflow_trap(
+            &[0x07, operands],
+            &[(RegisterId::X0, 1), (RegisterId::X1, 1)]
+        ));
+    }
 
-        let size: Size = Size::try_from(1 << (fetched_byte >> 6 & 0b11))
-            .expect("Unrecoverable error. Size is not 1, 2, 4, or 8");
+    #[test]
+    fn widening_multiplies_need_two_destination_registers() {
+        let operands = operands(Size::Eight, RegisterId::X0, Some(RegisterId::X1));
 
-        let address = get_effective_address(self);
+        // MULW and IMULW X0, X1 with the upper half in X0 and in X2
+        for opcode in [0xa3, 0xb3] {
+            let mut cpu = test_cpu(&[opcode, operands, RegisterId::X0 as u8]);
+            cpu.register_assign(RegisterId::X0, 1 << 40);
+            cpu.register_assign(RegisterId::X1, 1 << 40);
 
-        let mut derefrenced: [u8; 8] = [0; 8];
-        self.read(&mut derefrenced, address);
+            let flags = cpu.flags;
 
-        self.register_assign_sized(dst_id, u64::from_le_bytes(derefrenced), size);
+            assert_eq!(execute(&mut cpu), Err(INVALID_INSTRUCTION));
+            assert_eq!(cpu.register(RegisterId::X0), 1 << 40);
+            assert_eq!(cpu.flags, flags);
 
-        Ok(())
+            let mut cpu = test_cpu(&[opcode, operands, RegisterId::X2 as u8]);
+            cpu.register_assign(RegisterId::X0, 1 << 40);
+            cpu.register_assign(RegisterId::X1, 1 << 40);
+
+            assert_eq!(execute(&mut cpu), Ok(()));
+            assert_eq!(cpu.register(RegisterId::X0), 0);
+            assert_eq!(cpu.register(RegisterId::X2), 1 << 16);
+        }
     }
 
-    pub(super) fn LEA(&mut self) -> InstructionResult {
-        let fetched_byte = self.fetch_byte();
+    // Runs one instruction that only uses X0 and X1
+    fn run_with(code: &[u8], x0: u64, x1: u64) -> Cpu {
+        let mut cpu = test_cpu(code);
+        cpu.register_assign(RegisterId::X0, x0);
+        cpu.register_assign(RegisterId::X1, x1);
 
-        let dst_id: RegisterId = match RegisterId::from_u8(fetched_byte & 0b111) {
-            Some(reg_id) => reg_id,
-            None => return Err(INVALID_INSTRUCTION),
-        };
+        assert_eq!(execute(&mut cpu), Ok(()), "{:x?}", code);
 
-        let size: Size = Size::try_from(1 << (fetched_byte >> 6 & 0b11))
-            .expect("Unrecoverable error. Size is not 1, 2, 4, or 8");
+        cpu
+    }
 
-        let address = get_effective_address(self);
+    #[test]
+    fn bit_counts_of_zero() {
+        for size in SIZES {
+            let operands = operands(size, RegisterId::X0, Some(RegisterId::X1));
+            let bits = size as u64 * 8;
+            // Only the bits above the size of the operation are set
+            let source = 1u64.checked_shl(bits as u32).unwrap_or(0);
+            // The bytes of X0 past the size of the operation are kept
+            let upper = !(u64::MAX >> (64 - bits));
+
+            // CLZ and CTZ X0, X1
+            for opcode in [0x74, 0x84] {
+                let cpu = run_with(&[opcode, operands], u64::MAX, source);
+                assert_eq!(cpu.register(RegisterId::X0), upper | bits, "{:?}", size);
+                assert_eq!(flags(&cpu), [false, false, false, true]);
+            }
+
+            // POPCNT X0, X1
+            let cpu = run_with(&[0x64, operands], u64::MAX, source);
+            assert_eq!(cpu.register(RegisterId::X0), upper);
+            assert_eq!(flags(&cpu), [false, false, true, true]);
+        }
+    }
 
-        self.register_assign_sized(dst_id, address, size);
+    #[test]
+    fn bit_counts() {
+        let operands = operands(Size::Four, RegisterId::X0, Some(RegisterId::X1));
 
-        Ok(())
+        // POPCNT, CLZ and CTZ X0, X1
+        for (opcode, result) in [(0x64, 3), (0x74, 11), (0x84, 4)] {
+            let cpu = run_with(&[opcode, operands], 0, 0xff_0010_0110);
+            assert_eq!(cpu.register(RegisterId::X0), result);
+            assert_eq!(flags(&cpu), [false, false, false, false]);
+        }
     }
 
-    pub(super) fn JMP(&mut self) -> InstructionResult {
-        let address = get_effective_address(self);
+    #[test]
+    fn test_only_sets_flags() {
+        let operands = operands(Size::Two, RegisterId::X0, Some(RegisterId::X1));
 
-        self.register_assign(RegisterId::Ip, address);
+        // TEST X0, X1
+        let cpu = run_with(&[0x54, operands], 0x1_8001, 0x8000);
+        assert_eq!(cpu.register(RegisterId::X0), 0x1_8001);
+        assert_eq!(flags(&cpu), [true, false, false, false]);
 
-        Ok(())
+        let cpu = run_with(&[0x54, operands], 0x1_0001, 0x1_0000);
+        assert_eq!(flags(&cpu), [false, false, true, false]);
     }
 
-    pub(super) fn JZ(&mut self) -> InstructionResult {
-        let address = get_effective_address(self);
+    #[test]
+    fn byte_swaps() {
+        let value = 0x0102_0304_0506_0708;
+
+        for (size_bits, result) in [
+            (0, value),
+            (1, 0x0102_0304_0506_0807),
+            (2, 0x0102_0304_0807_0605),
+            (3, 0x0807_0605_0403_0201),
+        ] {
+            // BSWAP X0
+            let cpu = run_with(&[0x94, size_bits << 6 | RegisterId::X0 as u8], value, 0);
+            assert_eq!(cpu.register(RegisterId::X0), result, "{}", size_bits);
+        }
+    }
 
-        if self.get_flag(CpuFlag::Zero) {
-            self.register_assign(RegisterId::Ip, address);
+    #[test]
+    fn bit_tests_set_carry_to_the_old_bit() {
+        let register_byte = operands(Size::Eight, RegisterId::X0, None);
+
+        // BT, BTS, BTR and BTC X0 with an immediate index
+        for (opcode, bit, carry, result) in [
+            (0xa4, 1, true, 0b1010),
+            (0xa4, 2, false, 0b1010),
+            (0xb4, 2, false, 0b1110),
+            (0xb4, 3, true, 0b1010),
+            (0xc4, 3, true, 0b0010),
+            (0xc4, 0, false, 0b1010),
+            (0xd4, 0, false, 0b1011),
+            (0xd4, 1, true, 0b1000),
+        ] {
+            let mut code = vec![opcode, register_byte];
+            code.extend_from_slice(&(bit as u64).to_le_bytes());
+
+            let cpu = run_with(&code, 0b1010, 0);
+            assert_eq!(cpu.get_flag(CpuFlag::Carry), carry, "{:x?}", code);
+            assert_eq!(cpu.register(RegisterId::X0), result, "{:x?}", code);
         }
 
-        Ok(())
+        // BTS X0, X1 with 1 byte, where the index wraps around at 8 bits and the upper bytes are kept
+        let code = [
+            0xb4,
+            operands(Size::One, RegisterId::X0, Some(RegisterId::X1)),
+        ];
+        let cpu = run_with(&code, 0xff00, 9);
+        assert!(!cpu.get_flag(CpuFlag::Carry));
+        assert_eq!(cpu.register(RegisterId::X0), 0xff02);
     }
 
-    pub(super) fn JNZ(&mut self) -> InstructionResult {
-        let address = get_effective_address(self);
+    const CMOV: u8 = 0x09;
+    const SET: u8 = 0x19;
+
+    // Every condition with flags ([N, O, Z, C]) it is met with and flags it isn't met with
+    const CONDITIONS: [(Condition, [bool; 4], [bool; 4]); 14] = [
+        (
+            Condition::Zero,
+            [false, false, true, false],
+            [true, true, false, true],
+        ),
+        (
+            Condition::NotZero,
+            [true, true, false, true],
+            [false, false, true, false],
+        ),
+        (
+            Condition::Overflow,
+            [false, true, false, false],
+            [true, false, true, true],
+        ),
+        (
+            Condition::NotOverflow,
+            [true, false, true, true],
+            [false, true, false, false],
+        ),
+        (
+            Condition::Negative,
+            [true, false, false, false],
+            [false, true, true, true],
+        ),
+        (
+            Condition::NotNegative,
+            [false, true, true, true],
+            [true, false, false, false],
+        ),
+        (
+            Condition::Carry,
+            [false, false, false, true],
+            [true, true, true, false],
+        ),
+        (
+            Condition::NotCarry,
+            [true, true, true, false],
+            [false, false, false, true],
+        ),
+        (
+            Condition::BelowOrEqual,
+            [false, false, true, false],
+            [true, true, false, false],
+        ),
+        (
+            Condition::Above,
+            [true, true, false, false],
+            [false, false, false, true],
+        ),
+        (
+            Condition::Less,
+            [false, true, false, false],
+            [true, true, false, false],
+        ),
+        (
+            Condition::GreaterOrEqual,
+            [true, true, false, false],
+            [true, false, false, false],
+        ),
+        (
+            Condition::LessOrEqual,
+            [false, false, true, false],
+            [true, true, false, false],
+        ),
+        (
+            Condition::Greater,
+            [true, true, false, false],
+            [false, true, true, false],
+        ),
+    ];
+
+    fn set_flags(cpu: &mut Cpu, [negative, overflow, zero, carry]: [bool; 4]) {
+        cpu.set_flag(CpuFlag::Negative, negative);
+        cpu.set_flag(CpuFlag::Overflow, overflow);
+        cpu.set_flag(CpuFlag::Zero, zero);
+        cpu.set_flag(CpuFlag::Carry, carry);
+    }
 
-        if self.get_flag(CpuFlag::Zero) == false {
-            self.register_assign(RegisterId::Ip, address);
+    #[test]
+    fn conditional_moves_and_sets() {
+        for (condition, met, not_met) in CONDITIONS {
+            for (condition_flags, taken) in [(met, true), (not_met, false)] {
+                // CMOV X0, X1 with 4 bytes
+                let code = [
+                    CMOV,
+                    condition as u8,
+                    operands(Size::Four, RegisterId::X0, Some(RegisterId::X1)),
+                ];
+                let mut cpu = test_cpu(&code);
+                set_flags(&mut cpu, condition_flags);
+                cpu.register_assign(RegisterId::X0, 0x1111_1111_1111_1111);
+                cpu.register_assign(RegisterId::X1, 0x2222_2222_2222_2222);
+
+                assert_eq!(execute(&mut cpu), Ok(()));
+                let expected = if taken {
+                    0x1111_1111_2222_2222
+                } else {
+                    0x1111_1111_1111_1111
+                };
+                assert_eq!(cpu.register(RegisterId::X0), expected, "{:?}", condition);
+
+                // SET X0 with 2 bytes
+                let mut cpu = test_cpu(&[SET, condition as u8, 0b01 << 6 | RegisterId::X0 as u8]);
+                set_flags(&mut cpu, condition_flags);
+                cpu.register_assign(RegisterId::X0, 0x1111_1111_1111_1111);
+
+                assert_eq!(execute(&mut cpu), Ok(()));
+                assert_eq!(
+                    cpu.register(RegisterId::X0),
+                    0x1111_1111_1111_0000 | taken as u64,
+                    "{:?}",
+                    condition
+                );
+                assert_eq!(flags(&cpu), condition_flags);
+            }
         }
+    }
 
-        Ok(())
+    #[test]
+    fn invalid_conditions() {
+        for condition in [Condition::Greater as u8 + 1, u8::MAX] {
+            let mut cpu = test_cpu(&[
+                CMOV,
+                condition,
+                operands(Size::Eight, RegisterId::X0, Some(RegisterId::X1)),
+            ]);
+            cpu.register_assign(RegisterId::X1, 1);
+            assert_eq!(execute(&mut cpu), Err(INVALID_INSTRUCTION));
+            assert_eq!(cpu.register(RegisterId::X0), 0);
+
+            let mut cpu = test_cpu(&[SET, condition, RegisterId::X0 as u8]);
+            set_flags(&mut cpu, [true; 4]);
+            assert_eq!(execute(&mut cpu), Err(INVALID_INSTRUCTION));
+            assert_eq!(cpu.register(RegisterId::X0), 0);
+        }
     }
 
-    pub(super) fn JO(&mut self) -> InstructionResult {
-        let address = get_effective_address(self);
+    const MEMD: u8 = 0x12;
+    const MEMS: u8 = 0x22;
+    const DATA_ADDRESS: u64 = 0x3000;
 
-        if self.get_flag(CpuFlag::Overflow) {
-            self.register_assign(RegisterId::Ip, address);
-        }
+    // The address byte and the 64 bit address of an absolute address
+    fn absolute(address: u64) -> Vec<u8> {
+        let mut bytes = vec![0x00];
+        bytes.extend_from_slice(&address.to_le_bytes());
+        bytes
+    }
 
-        Ok(())
+    fn read_qword(cpu: &mut Cpu, address: u64) -> u64 {
+        let mut bytes = [0u8; 8];
+        cpu.read(&mut bytes, address);
+        u64::from_le_bytes(bytes)
     }
 
-    pub(super) fn JNO(&mut self) -> InstructionResult {
-        let address = get_effective_address(self);
+    #[test]
+    fn memory_destinations() {
+        // MEMD ADD [DATA_ADDRESS], X1 with 4 bytes
+        let mut code = vec![MEMD, 0x03, 0b10 << 6 | RegisterId::X1 as u8];
+        code.extend(absolute(DATA_ADDRESS));
+
+        let mut cpu = test_cpu(&code);
+        cpu.write(&0xaaaa_aaaa_ffff_fffeu64.to_le_bytes(), DATA_ADDRESS);
+        cpu.register_assign(RegisterId::X1, 3);
+
+        assert_eq!(execute(&mut cpu), Ok(()));
+        assert_eq!(read_qword(&mut cpu, DATA_ADDRESS), 0xaaaa_aaaa_0000_0001);
+        assert_eq!(flags(&cpu), [false, false, false, true]);
+        assert_eq!(
+            cpu.register(RegisterId::Ip),
+            CODE_ADDRESS + code.len() as u64
+        );
 
-        if self.get_flag(CpuFlag::Overflow) == false {
-            self.register_assign(RegisterId::Ip, address);
-        }
+        // MEMD MOV [DATA_ADDRESS], 0x1234 with 2 bytes. The immediate comes before the address
+        let mut code = vec![MEMD, 0x01, 0b01 << 6];
+        code.extend(0x1234u16.to_le_bytes());
+        code.extend(absolute(DATA_ADDRESS));
 
-        Ok(())
+        let mut cpu = test_cpu(&code);
+        cpu.write(&u64::MAX.to_le_bytes(), DATA_ADDRESS);
+
+        assert_eq!(execute(&mut cpu), Ok(()));
+        assert_eq!(read_qword(&mut cpu, DATA_ADDRESS), 0xffff_ffff_ffff_1234);
+        assert_eq!(
+            cpu.register(RegisterId::Ip),
+            CODE_ADDRESS + code.len() as u64
+        );
+
+        // MEMD CMP [DATA_ADDRESS], X1 only sets the flags
+        let mut code = vec![MEMD, 0x11, 0b11 << 6 | RegisterId::X1 as u8];
+        code.extend(absolute(DATA_ADDRESS));
+
+        let mut cpu = test_cpu(&code);
+        cpu.write(&5u64.to_le_bytes(), DATA_ADDRESS);
+        cpu.register_assign(RegisterId::X1, 5);
+
+        assert_eq!(execute(&mut cpu), Ok(()));
+        assert_eq!(read_qword(&mut cpu, DATA_ADDRESS), 5);
+        assert!(cpu.get_flag(CpuFlag::Zero));
     }
 
-    pub(super) fn JS(&mut self) -> InstructionResult {
-        let address = get_effective_address(self);
+    #[test]
+    fn memory_sources() {
+        // MEMS SUB X0, [DATA_ADDRESS] with 1 byte
+        let mut code = vec![MEMS, 0x13, operands(Size::One, RegisterId::X0, None)];
+        code.extend(absolute(DATA_ADDRESS));
+
+        let mut cpu = test_cpu(&code);
+        cpu.write(&0xff03u16.to_le_bytes(), DATA_ADDRESS);
+        cpu.register_assign(RegisterId::X0, 0x1110);
+
+        assert_eq!(execute(&mut cpu), Ok(()));
+        assert_eq!(cpu.register(RegisterId::X0), 0x110d);
+        assert_eq!(read_qword(&mut cpu, DATA_ADDRESS), 0xff03);
+        assert_eq!(
+            cpu.register(RegisterId::Ip),
+            CODE_ADDRESS + code.len() as u64
+        );
+
+        // EXT MEMS MOV X5, [DATA_ADDRESS] with 8 bytes, X5 being 0b01_000
+        let mut code = vec![
+            0x02,
+            0b01 << RegisterField::High as u8,
+            MEMS,
+            0x01,
+            0b11 << 6,
+        ];
+        code.extend(absolute(DATA_ADDRESS));
+
+        let mut cpu = test_cpu(&code);
+        cpu.write(&0x1234_5678u64.to_le_bytes(), DATA_ADDRESS);
+
+        assert_eq!(execute(&mut cpu), Ok(()));
+        assert_eq!(cpu.register(RegisterId::X5), 0x1234_5678);
+    }
 
-        if self.get_flag(CpuFlag::Negative) {
-            self.register_assign(RegisterId::Ip, address);
+    #[test]
+    fn invalid_memory_operand_prefixes() {
+        let add_operands = operands(Size::Eight, RegisterId::X0, Some(RegisterId::X1));
+
+        for code in [
+            // A register in the field the memory operand replaces
+            vec![MEMD, 0x03, add_operands],
+            vec![MEMS, 0x03, add_operands],
+            // Stacked prefixes, including EXT after the prefix
+            vec![MEMD, MEMD, 0x03, RegisterId::X1 as u8],
+            vec![MEMD, MEMS, 0x03, RegisterId::X1 as u8],
+            vec![MEMD, 0x02, 0, 0x03, RegisterId::X1 as u8],
+            // Instructions without memory operand forms
+            vec![MEMD, 0x23, RegisterId::X1 as u8],
+            vec![MEMS, 0x05, 0x00],
+            vec![MEMD, 0x90],
+            vec![MEMD, 0xff],
+        ] {
+            let mut extended = code.clone();
+            extended.extend(absolute(DATA_ADDRESS));
+
+            let mut cpu = test_cpu(&extended);
+
+            assert_eq!(execute(&mut cpu), Err(INVALID_INSTRUCTION), "{:x?}", code);
+            assert_eq!(read_qword(&mut cpu, DATA_ADDRESS), 0);
         }
+    }
 
-        Ok(())
+    const XCHG: u8 = 0x0b;
+    const CMPXCHG: u8 = 0x1b;
+    const XADD: u8 = 0x2b;
+
+    // Runs an atomic instruction on DATA_ADDRESS with the given value in memory and registers
+    fn atomic(
+        opcode: u8,
+        register_byte: u8,
+        memory: u64,
+        registers: &[(RegisterId, u64)],
+    ) -> (InstructionResult, Cpu) {
+        let mut code = vec![opcode, register_byte];
+        code.extend(absolute(DATA_ADDRESS));
+
+        let mut cpu = test_cpu(&code);
+        cpu.write(&memory.to_le_bytes(), DATA_ADDRESS);
+
+        for &(id, value) in registers {
+            cpu.register_assign(id, value);
+        }
+
+        (execute(&mut cpu), cpu)
     }
 
-    pub(super) fn JNS(&mut self) -> InstructionResult {
-        let address = get_effective_address(self);
+    #[test]
+    fn compare_exchange_stores_only_if_equal() {
+        // CMPXCHG [DATA_ADDRESS], X2 if it equals X1, with 4 bytes
+        let register_byte = operands(Size::Four, RegisterId::X1, Some(RegisterId::X2));
 
-        if self.get_flag(CpuFlag::Negative) == false {
-            self.register_assign(RegisterId::Ip, address);
-        }
+        let (result, mut cpu) = atomic(
+            CMPXCHG,
+            register_byte,
+            0xaaaa_aaaa_0000_0005,
+            &[(RegisterId::X1, 0xffff_ffff_0000_0005), (RegisterId::X2, 9)],
+        );
+        assert_eq!(result, Ok(()));
+        assert_eq!(read_qword(&mut cpu, DATA_ADDRESS), 0xaaaa_aaaa_0000_0009);
+        assert_eq!(cpu.register(RegisterId::X1), 0xffff_ffff_0000_0005);
+        assert_eq!(flags(&cpu), [false, false, true, false]);
+
+        let (result, mut cpu) = atomic(
+            CMPXCHG,
+            register_byte,
+            0xaaaa_aaaa_0000_0007,
+            &[(RegisterId::X1, 0xffff_ffff_0000_0005), (RegisterId::X2, 9)],
+        );
+        assert_eq!(result, Ok(()));
+        assert_eq!(read_qword(&mut cpu, DATA_ADDRESS), 0xaaaa_aaaa_0000_0007);
+        assert_eq!(cpu.register(RegisterId::X1), 0xffff_ffff_0000_0007);
+        assert_eq!(flags(&cpu), [true, false, false, true]);
+    }
 
-        Ok(())
+    #[test]
+    fn exchange_add_returns_the_old_value() {
+        // XADD [DATA_ADDRESS], X1 with 8 and with 1 byte
+        let (result, mut cpu) = atomic(
+            XADD,
+            0b11 << 6 | RegisterId::X1 as u8,
+            10,
+            &[(RegisterId::X1, 3)],
+        );
+        assert_eq!(result, Ok(()));
+        assert_eq!(read_qword(&mut cpu, DATA_ADDRESS), 13);
+        assert_eq!(cpu.register(RegisterId::X1), 10);
+
+        let (result, mut cpu) = atomic(
+            XADD,
+            RegisterId::X1 as u8,
+            0x11ff,
+            &[(RegisterId::X1, 0x2201)],
+        );
+        assert_eq!(result, Ok(()));
+        assert_eq!(read_qword(&mut cpu, DATA_ADDRESS), 0x1100);
+        assert_eq!(cpu.register(RegisterId::X1), 0x22ff);
+        assert_eq!(flags(&cpu), [false, false, true, true]);
+
+        // XCHG [DATA_ADDRESS], X1 with 2 bytes
+        let (result, mut cpu) = atomic(
+            XCHG,
+            0b01 << 6 | RegisterId::X1 as u8,
+            0x1111_2222,
+            &[(RegisterId::X1, 0x3333_4444)],
+        );
+        assert_eq!(result, Ok(()));
+        assert_eq!(read_qword(&mut cpu, DATA_ADDRESS), 0x1111_4444);
+        assert_eq!(cpu.register(RegisterId::X1), 0x3333_2222);
     }
 
-    pub(super) fn JC(&mut self) -> InstructionResult {
-        let address = get_effective_address(self);
+    #[test]
+    fn atomics_fault_on_unaligned_addresses() {
+        let register_byte = operands(Size::Four, RegisterId::X1, Some(RegisterId::X2));
+
+        for opcode in [XCHG, CMPXCHG, XADD] {
+            let mut code = vec![opcode, register_byte];
+            code.extend(absolute(DATA_ADDRESS + 2));
 
-        if self.get_flag(CpuFlag::Carry) {
-            self.register_assign(RegisterId::Ip, address);
+            let mut cpu = test_cpu(&code);
+            cpu.control_register_assign(ControlRegister::Mode, MODE_ALIGNMENT_CHECK);
+            cpu.register_assign(RegisterId::X1, 1);
+            cpu.register_assign(RegisterId::X2, 1);
+
+            assert_eq!(execute(&mut cpu), Err(ALIGNMENT_CHECK));
+            assert_eq!((cpu.fault_address, cpu.error_code), (DATA_ADDRESS + 2, 4));
+            assert_eq!(read_qword(&mut cpu, DATA_ADDRESS), 0);
+            assert_eq!(cpu.register(RegisterId::X1), 1);
+            assert_eq!(cpu.register(RegisterId::X2), 1);
         }
+    }
 
-        Ok(())
+    const RDPMC: u8 = 0xb8;
+    const PMCTL: u8 = 0xc8;
+
+    // RDPMC into the register with the counter index as an immediate
+    fn read_counter(dst: RegisterId, counter: PerformanceCounter) -> Vec<u8> {
+        let mut code = vec![RDPMC, operands(Size::Eight, dst, None)];
+        code.extend((counter as u64).to_le_bytes());
+        code
     }
 
-    pub(super) fn JNC(&mut self) -> InstructionResult {
-        let address = get_effective_address(self);
+    #[test]
+    fn counters_are_selected_by_index() {
+        let mut code = vec![NOP, NOP];
+        code.extend(read_counter(
+            RegisterId::X0,
+            PerformanceCounter::RetiredInstructions,
+        ));
+        code.extend(read_counter(RegisterId::X1, PerformanceCounter::Cycles));
+        code.extend(read_counter(
+            RegisterId::X2,
+            PerformanceCounter::BusAccesses,
+        ));
+        // RDPMC X3, X4
+        code.extend([
+            RDPMC,
+            operands(Size::Eight, RegisterId::X3, Some(RegisterId::X4)),
+        ]);
+
+        let mut cpu = test_cpu(&code);
+        cpu.register_assign(RegisterId::X4, PerformanceCounter::TakenBranches as u64);
+
+        cpu.step(6);
+
+        // Each counter is read before the RDPMC reading it retires, but after it was fetched
+        assert_eq!(cpu.register(RegisterId::X0), 2);
+        assert_eq!(cpu.register(RegisterId::X1), 4);
+        assert_eq!(cpu.register(RegisterId::X2), 2 + 3 * 3);
+        assert_eq!(cpu.register(RegisterId::X3), 0);
+    }
+
+    #[test]
+    fn reading_a_missing_counter_is_invalid() {
+        for index in [PERFORMANCE_COUNTER_COUNT as u64, u64::MAX] {
+            let mut code = vec![RDPMC, operands(Size::Eight, RegisterId::X0, None)];
+            code.extend(index.to_le_bytes());
+
+            let mut cpu = test_cpu(&code);
+            cpu.register_assign(RegisterId::X0, 42);
 
-        if self.get_flag(CpuFlag::Carry) == false {
-            self.register_assign(RegisterId::Ip, address);
+            assert_eq!(execute(&mut cpu), Err(INVALID_INSTRUCTION));
+            assert_eq!(cpu.register(RegisterId::X0), 42);
         }
+    }
 
-        Ok(())
+    #[test]
+    fn counter_control_enables_and_resets_counters() {
+        // PMCTL X0, two NOPs and reading both counters
+        let mut code = vec![PMCTL, RegisterId::X0 as u8, NOP, NOP];
+        code.extend(read_counter(
+            RegisterId::X1,
+            PerformanceCounter::RetiredInstructions,
+        ));
+        code.extend(read_counter(RegisterId::X2, PerformanceCounter::Cycles));
+
+        // Only Cycles stays enabled, and RetiredInstructions is reset
+        let mut cpu = test_cpu(&code);
+        cpu.register_assign(
+            RegisterId::X0,
+            1 << PerformanceCounter::Cycles as u64
+                | 1 << (8 + PerformanceCounter::RetiredInstructions as u64),
+        );
+
+        cpu.step(5);
+
+        assert_eq!(cpu.register(RegisterId::X1), 0);
+        assert_eq!(cpu.register(RegisterId::X2), 5);
+        assert_eq!(
+            cpu.performance_counters
+                .read(PerformanceCounter::RetiredInstructions as u64),
+            Some(0)
+        );
+
+        // Every counter enabled again, resetting Cycles
+        let mut cpu = test_cpu(&code);
+        cpu.register_assign(
+            RegisterId::X0,
+            (1 << PERFORMANCE_COUNTER_COUNT) - 1 | 1 << (8 + PerformanceCounter::Cycles as u64),
+        );
+
+        cpu.step(5);
+
+        assert_eq!(cpu.register(RegisterId::X1), 3);
+        assert_eq!(cpu.register(RegisterId::X2), 4);
     }
 
-    pub(super) fn JBE(&mut self) -> InstructionResult {
-        let address = get_effective_address(self);
+    #[test]
+    fn only_the_supervisor_controls_counters() {
+        // PMCTL X0 with X0 clear would disable every counter
+        let mut cpu = test_cpu(&[PMCTL, RegisterId::X0 as u8]);
+        cpu.control_register_assign(ControlRegister::Mode, MODE_USER);
 
-        if self.get_flag(CpuFlag::Carry) || self.get_flag(CpuFlag::Zero) {
-            self.register_assign(RegisterId::Ip, address);
-        }
+        assert_eq!(execute(&mut cpu), Err(GENERAL_PROTECTION_FAULT));
 
-        Ok(())
+        cpu.count(PerformanceCounter::Cycles);
+        assert_eq!(
+            cpu.performance_counters
+                .read(PerformanceCounter::Cycles as u64),
+            Some(1)
+        );
+
+        // Users can still read them
+        let mut cpu = test_cpu(&read_counter(RegisterId::X0, PerformanceCounter::Cycles));
+        cpu.control_register_assign(ControlRegister::Mode, MODE_USER);
+
+        assert_eq!(execute(&mut cpu), Ok(()));
     }
 
-    pub(super) fn JA(&mut self) -> InstructionResult {
-        let address = get_effective_address(self);
+    const EXT: u8 = 0x02;
+    const LEA: u8 = 0x61;
+
+    // Runs the code and returns the given register, with X1 set to 0x1000
+    fn extended(code: &[u8], registers: &[(RegisterId, u64)], result: RegisterId) -> u64 {
+        let mut cpu = test_cpu(code);
+        cpu.register_assign(RegisterId::X1, 0x1000);
 
-        if self.get_flag(CpuFlag::Carry) == false || self.get_flag(CpuFlag::Zero) == false {
-            self.register_assign(RegisterId::Ip, address);
+        for &(id, value) in registers {
+            cpu.register_assign(id, value);
         }
 
-        Ok(())
+        assert_eq!(execute(&mut cpu), Ok(()), "{:x?}", code);
+
+        cpu.register(result)
     }
 
-    pub(super) fn JL(&mut self) -> InstructionResult {
-        let address = get_effective_address(self);
+    #[test]
+    fn extended_registers_are_reachable_through_every_field() {
+        for id in RegisterId::X5 as u8..=RegisterId::X15 as u8 {
+            let register = RegisterId::from_u8(id).unwrap();
+            let (extension, field) = (id >> 3, id & 0b111);
+
+            // EXT MOV Xn, X1
+            let code = [
+                EXT,
+                extension << RegisterField::High as u8,
+                0x01,
+                0b11 << 6 | field << 3 | RegisterId::X1 as u8,
+            ];
+            assert_eq!(extended(&code, &[], register), 0x1000);
+
+            // EXT MOV X1, Xn
+            let code = [
+                EXT,
+                extension << RegisterField::Low as u8,
+                0x01,
+                0b11 << 6 | (RegisterId::X1 as u8) << 3 | field,
+            ];
+            assert_eq!(
+                extended(&code, &[(register, id as u64)], RegisterId::X1),
+                id as u64
+            );
+
+            // EXT LEA X0, [Xn + X1]
+            let code = [
+                EXT,
+                extension << RegisterField::Base as u8,
+                LEA,
+                0b11 << 6 | RegisterId::X0 as u8,
+                0b11 << 6 | (RegisterId::X1 as u8) << 3 | field,
+                0,
+            ];
+            assert_eq!(
+                extended(&code, &[(register, id as u64)], RegisterId::X0),
+                0x1000 + id as u64
+            );
+
+            // EXT LEA X0, [X1 + Xn * 4]
+            let code = [
+                EXT,
+                extension << RegisterField::Index as u8,
+                LEA,
+                0b11 << 6 | RegisterId::X0 as u8,
+                0b11 << 6 | field << 3 | RegisterId::X1 as u8,
+                2 << 3,
+            ];
+            assert_eq!(
+                extended(&code, &[(register, id as u64)], RegisterId::X0),
+                0x1000 + 4 * id as u64
+            );
+        }
+    }
 
-        if self.get_flag(CpuFlag::Negative) != self.get_flag(CpuFlag::Overflow) {
-            self.register_assign(RegisterId::Ip, address);
+    #[test]
+    fn invalid_register_extensions() {
+        let mov = 0b11 << 6 | (RegisterId::X0 as u8) << 3;
+
+        for code in [
+            // Register numbers past X15
+            vec![EXT, 0b10, 0x01, mov | 0b011],
+            vec![EXT, 0b11, 0x01, mov | 0b111],
+            vec![EXT, 0b11 << RegisterField::High as u8, 0x01, mov | 1],
+            // Stacked prefixes
+            vec![EXT, 0, EXT, 0, 0x01, mov | 1],
+            // An opcode that doesn't exist
+            vec![EXT, 0, 0xff],
+        ] {
+            let mut cpu = test_cpu(&code);
+            cpu.register_assign(RegisterId::X0, 42);
+
+            assert_eq!(execute(&mut cpu), Err(INVALID_INSTRUCTION), "{:x?}", code);
+            assert_eq!(cpu.register(RegisterId::X0), 42);
         }
 
-        Ok(())
-    }
+        // The prefix itself is invalid without the feature
+        let mut cpu = test_cpu(&[EXT, 0, 0x01, mov | 1]);
+        cpu.boot_parameters.features &= !REGISTER_EXTENSION;
 
-    pub(super) fn JGE(&mut self) -> InstructionResult {
-        let address = get_effective_address(self);
+        assert!(cpu.lookup_instruction(EXT).is_none());
+    }
 
-        if self.get_flag(CpuFlag::Negative) == self.get_flag(CpuFlag::Overflow) {
-            self.register_assign(RegisterId::Ip, address);
+    #[test]
+    fn stores_leave_the_neighbouring_bytes_alone() {
+        for (size_bits, stored) in [
+            (0, 0xaaaa_aaaa_aaaa_aa08),
+            (1, 0xaaaa_aaaa_aaaa_0708),
+            (2, 0xaaaa_aaaa_0506_0708),
+            (3, 0x0102_0304_0506_0708),
+        ] {
+            // STR [DATA_ADDRESS + 8], X1
+            let mut code = vec![0x41, size_bits << 6 | RegisterId::X1 as u8];
+            code.extend(absolute(DATA_ADDRESS + 8));
+
+            let mut cpu = test_cpu(&code);
+            cpu.write(&[0xaa; 24], DATA_ADDRESS);
+            cpu.register_assign(RegisterId::X1, 0x0102_0304_0506_0708);
+
+            assert_eq!(execute(&mut cpu), Ok(()));
+            assert_eq!(read_qword(&mut cpu, DATA_ADDRESS), 0xaaaa_aaaa_aaaa_aaaa);
+            assert_eq!(read_qword(&mut cpu, DATA_ADDRESS + 8), stored);
+            assert_eq!(
+                read_qword(&mut cpu, DATA_ADDRESS + 16),
+                0xaaaa_aaaa_aaaa_aaaa
+            );
         }
+    }
 
-        Ok(())
+    // Runs LDR, LDZX or LDSX X1, [DATA_ADDRESS] with X1 starting out as 0x5555_5555_5555_5555
+    fn load(opcode: u8, size_bits: u8, value: u64) -> u64 {
+        let mut code = vec![opcode, size_bits << 6 | RegisterId::X1 as u8];
+        code.extend(absolute(DATA_ADDRESS));
+
+        let mut cpu = test_cpu(&code);
+        cpu.write(&value.to_le_bytes(), DATA_ADDRESS);
+        cpu.register_assign(RegisterId::X1, 0x5555_5555_5555_5555);
+
+        assert_eq!(execute(&mut cpu), Ok(()));
+
+        cpu.register(RegisterId::X1)
     }
 
-    pub(super) fn JLE(&mut self) -> InstructionResult {
-        let address = get_effective_address(self);
+    #[test]
+    fn extending_loads() {
+        let negative = 0x8182_8384_8586_8788;
+        let positive = 0x7172_7374_7576_7778;
+
+        for (size_bits, zero_extended, sign_extended) in [
+            (0, 0x88, 0xffff_ffff_ffff_ff88),
+            (1, 0x8788, 0xffff_ffff_ffff_8788),
+            (2, 0x8586_8788, 0xffff_ffff_8586_8788),
+            (3, negative, negative),
+        ] {
+            assert_eq!(load(0x91, size_bits, negative), zero_extended);
+            assert_eq!(load(0xa1, size_bits, negative), sign_extended);
+        }
 
-        if self.get_flag(CpuFlag::Zero)
-            || self.get_flag(CpuFlag::Negative) != self.get_flag(CpuFlag::Overflow)
-        {
-            self.register_assign(RegisterId::Ip, address);
+        for (size_bits, extended, loaded) in [
+            (0, 0x78, 0x5555_5555_5555_5578),
+            (1, 0x7778, 0x5555_5555_5555_7778),
+            (2, 0x7576_7778, 0x5555_5555_7576_7778),
+            (3, positive, positive),
+        ] {
+            assert_eq!(load(0x91, size_bits, positive), extended);
+            assert_eq!(load(0xa1, size_bits, positive), extended);
+            assert_eq!(load(0x51, size_bits, positive), loaded);
         }
+    }
 
-        Ok(())
+    const IDT_BASE: u64 = 0x100;
+    const HANDLER_ADDRESS: u64 = 0x2000;
+    const STACK: u64 = 0x8000;
+
+    // Runs DIV X0, X1 with a divisor of 0. The handler of DIVIDE_BY_ZERO sets X1 to 1 and returns with RETI
+    fn divide_by_zero_cpu() -> Cpu {
+        let mut cpu = test_cpu(&[
+            0x33,
+            operands(Size::Eight, RegisterId::X0, Some(RegisterId::X1)),
+        ]);
+
+        cpu.control_register_assign(ControlRegister::IdtBase, IDT_BASE);
+        cpu.write(
+            &HANDLER_ADDRESS.to_le_bytes(),
+            IDT_BASE + DIVIDE_BY_ZERO as u64 * 8,
+        );
+
+        let mut handler = vec![0x01, operands(Size::Eight, RegisterId::X1, None)];
+        handler.extend_from_slice(&1u64.to_le_bytes());
+        handler.push(0x28);
+        cpu.write(&handler, HANDLER_ADDRESS);
+
+        cpu.register_assign(RegisterId::X0, 10);
+        cpu.register_assign(RegisterId::X1, 0);
+        cpu.register_assign(RegisterId::Sp, STACK);
+
+        cpu
     }
 
-    pub(super) fn JG(&mut self) -> InstructionResult {
-        let address = get_effective_address(self);
+    // The fault address, error code, IP, flags, mode and SP pushed on interrupt entry
+    fn interrupt_frame(cpu: &mut Cpu) -> [u64; 6] {
+        let mut frame = [0u64; 6];
 
-        if self.get_flag(CpuFlag::Zero) == false
-            && self.get_flag(CpuFlag::Negative) == self.get_flag(CpuFlag::Overflow)
-        {
-            self.register_assign(RegisterId::Ip, address);
+        for (index, value) in frame.iter_mut().enumerate() {
+            let mut bytes = [0u8; 8];
+            cpu.read(&mut bytes, cpu.register(RegisterId::Sp) + index as u64 * 8);
+            *value = u64::from_le_bytes(bytes);
         }
 
-        Ok(())
+        frame
     }
 
-    pub(super) fn CALL(&mut self) -> InstructionResult {
-        let address = get_effective_address(self);
+    #[test]
+    fn fault_frame_round_trip() {
+        let mut cpu = divide_by_zero_cpu();
+        cpu.control_register_assign(ControlRegister::Mode, MODE_ALIGNMENT_CHECK);
+        cpu.set_flag(CpuFlag::Carry, true);
+        cpu.set_flag(CpuFlag::Negative, true);
 
-        self.push_qword(self.register(RegisterId::Ip));
+        let flags = cpu.flags;
 
-        self.register_assign(RegisterId::Ip, address);
+        cpu.step(1);
 
-        Ok(())
-    }
+        assert_eq!(cpu.register(RegisterId::Ip), HANDLER_ADDRESS);
+        assert_eq!(cpu.register(RegisterId::Sp), STACK - 48);
+        assert_eq!(
+            interrupt_frame(&mut cpu),
+            [0, 0, CODE_ADDRESS, flags, MODE_ALIGNMENT_CHECK, STACK]
+        );
+        assert!(!cpu.get_flag(CpuFlag::InterruptEnable));
 
-    pub(super) fn RET(&mut self) -> InstructionResult {
-        let return_address = self.pop_qword();
+        // The handler fixes the divisor and RETI retries the division
+        cpu.step(2);
+
+        assert_eq!(cpu.register(RegisterId::Ip), CODE_ADDRESS);
+        assert_eq!(cpu.register(RegisterId::Sp), STACK);
+        assert_eq!(cpu.flags, flags);
+        assert_eq!(
+            cpu.control_register(ControlRegister::Mode),
+            MODE_ALIGNMENT_CHECK
+        );
 
-        self.register_assign(RegisterId::Ip, return_address);
+        cpu.step(1);
 
-        Ok(())
+        assert_eq!(cpu.register(RegisterId::X0), 10);
+        assert_eq!(cpu.register(RegisterId::Ip), CODE_ADDRESS + 2);
     }
 
-    pub(super) fn LIDT(&mut self) -> InstructionResult {
-        let address = get_effective_address(self);
+    #[test]
+    fn alignment_fault_pushes_the_size_and_address() {
+        // LDR X0, [X1] with 8 bytes
+        let mut cpu = test_cpu(&[0x51, 0b11_000_001, 0b11_000_010, 0]);
+
+        cpu.control_register_assign(ControlRegister::IdtBase, IDT_BASE);
+        cpu.write(
+            &HANDLER_ADDRESS.to_le_bytes(),
+            IDT_BASE + ALIGNMENT_CHECK as u64 * 8,
+        );
+        cpu.control_register_assign(ControlRegister::Mode, MODE_ALIGNMENT_CHECK);
+        cpu.register_assign(RegisterId::X1, 0x3003);
+        cpu.register_assign(RegisterId::Sp, STACK);
 
-        self.idt = address;
+        cpu.step(1);
 
-        Ok(())
+        let frame = interrupt_frame(&mut cpu);
+        assert_eq!(frame[..3], [0x3003, 8, CODE_ADDRESS]);
     }
 
-    pub(super) fn RETI(&mut self) -> InstructionResult {
-        let address = self.pop_qword();
-        self.pop_flags();
-        self.register_assign(RegisterId::Ip, address);
+    #[test]
+    fn faults_from_users_switch_to_the_kernel_stack() {
+        let mut cpu = divide_by_zero_cpu();
+        cpu.control_register_assign(ControlRegister::Mode, MODE_USER);
+        cpu.control_register_assign(ControlRegister::KernelStack, 0x9000);
 
-        Ok(())
+        cpu.step(1);
+
+        assert_eq!(cpu.register(RegisterId::Sp), 0x9000 - 48);
+        assert_eq!(interrupt_frame(&mut cpu)[4..], [MODE_USER, STACK]);
+        assert!(cpu.is_supervisor());
+
+        cpu.step(2);
+
+        assert_eq!(cpu.register(RegisterId::Ip), CODE_ADDRESS);
+        assert_eq!(cpu.register(RegisterId::Sp), STACK);
+        assert_eq!(cpu.control_register(ControlRegister::Mode), MODE_USER);
     }
 
-    pub(super) fn INT(&mut self) -> InstructionResult {
-        let idt_entry = self.fetch_byte();
-        self.interrupt_request(idt_entry);
+    #[test]
+    fn interrupt_stack_vectors_switch_to_the_interrupt_stack() {
+        for mode in [0, MODE_USER] {
+            let mut cpu = divide_by_zero_cpu();
+            cpu.control_register_assign(ControlRegister::Mode, mode);
+            cpu.control_register_assign(ControlRegister::KernelStack, 0x9000);
+            cpu.control_register_assign(ControlRegister::InterruptStack, 0xa000);
+            cpu.control_register_assign(
+                ControlRegister::InterruptStackVectors,
+                1 << DIVIDE_BY_ZERO,
+            );
 
-        Ok(())
+            cpu.step(1);
+
+            assert_eq!(cpu.register(RegisterId::Sp), 0xa000 - 48);
+            assert_eq!(interrupt_frame(&mut cpu)[4..], [mode, STACK]);
+
+            cpu.step(2);
+
+            assert_eq!(cpu.register(RegisterId::Sp), STACK);
+            assert_eq!(cpu.control_register(ControlRegister::Mode), mode);
+        }
     }
 
-    pub(super) fn CLI(&mut self) -> InstructionResult {
-        self.set_flag(CpuFlag::InterruptEnable, false);
+    #[test]
+    fn supervisor_faults_stay_on_the_current_stack() {
+        let mut cpu = divide_by_zero_cpu();
+        cpu.control_register_assign(ControlRegister::KernelStack, 0x9000);
 
-        Ok(())
+        cpu.step(1);
+
+        assert_eq!(cpu.register(RegisterId::Sp), STACK - 48);
     }
 
-    pub(super) fn STI(&mut self) -> InstructionResult {
-        self.set_flag(CpuFlag::InterruptEnable, true);
+    const BRK: u8 = 0xf8;
+    const NOP: u8 = 0x90;
 
-        Ok(())
+    // Runs the code with a handler for DEBUG
+    fn debug_cpu(code: &[u8]) -> Cpu {
+        let mut cpu = test_cpu(code);
+
+        cpu.control_register_assign(ControlRegister::IdtBase, IDT_BASE);
+        cpu.write(&HANDLER_ADDRESS.to_le_bytes(), IDT_BASE + DEBUG as u64 * 8);
+        cpu.write(&[NOP], HANDLER_ADDRESS);
+        cpu.register_assign(RegisterId::Sp, STACK);
+
+        cpu
     }
 
-    pub(super) fn IN(&mut self) -> InstructionResult {
-        let fetched_byte = self.fetch_byte();
+    // A debug hook that records the IP and the trap it is called with, and returns `handled`
+    fn recording_hook(handled: bool) -> (DebugHook, Rc<RefCell<Vec<(u64, u64)>>>) {
+        let traps = Rc::new(RefCell::new(Vec::new()));
+        let recorded = Rc::clone(&traps);
 
-        let dst_id: RegisterId = match RegisterId::from_u8(fetched_byte & 0b111) {
-            Some(reg_id) => reg_id,
-            None => return Err(INVALID_INSTRUCTION),
-        };
+        let hook: DebugHook = Rc::new(RefCell::new(move |cpu: &Cpu, trap: DebugTrap| {
+            recorded
+                .borrow_mut()
+                .push((cpu.register(RegisterId::Ip), trap as u64));
+            handled
+        }));
 
-        let port = self.fetch_word();
+        (hook, traps)
+    }
 
-        let value = self.port_bus_read(port);
+    #[test]
+    fn breakpoints_raise_debug_after_the_breakpoint() {
+        let mut cpu = debug_cpu(&[BRK, NOP]);
 
-        self.register_assign(dst_id, value);
+        cpu.step(1);
 
-        Ok(())
+        assert_eq!(cpu.register(RegisterId::Ip), HANDLER_ADDRESS);
+        assert_eq!(
+            interrupt_frame(&mut cpu)[..3],
+            [0, DebugTrap::Breakpoint as u64, CODE_ADDRESS + 1]
+        );
     }
 
-    pub(super) fn OUT(&mut self) -> InstructionResult {
-        let fetched_byte = self.fetch_byte();
+    #[test]
+    fn the_trap_flag_single_steps() {
+        let mut cpu = debug_cpu(&[NOP, NOP]);
+        cpu.set_flag(CpuFlag::Trap, true);
 
-        let src_id: RegisterId = match RegisterId::from_u8(fetched_byte & 0b111) {
-            Some(reg_id) => reg_id,
-            None => return Err(INVALID_INSTRUCTION),
-        };
+        let flags = cpu.flags;
 
-        let port = self.fetch_word();
+        cpu.step(1);
 
-        self.port_bus_write(port, self.register(src_id));
+        assert_eq!(cpu.register(RegisterId::Ip), HANDLER_ADDRESS);
+        assert_eq!(
+            interrupt_frame(&mut cpu)[..4],
+            [0, DebugTrap::SingleStep as u64, CODE_ADDRESS + 1, flags]
+        );
 
-        Ok(())
+        // The handler isn't single stepped
+        assert!(!cpu.get_flag(CpuFlag::Trap));
+
+        cpu.step(1);
+
+        assert_eq!(cpu.register(RegisterId::Ip), HANDLER_ADDRESS + 1);
     }
 
-    pub(super) fn NOP(&mut self) -> InstructionResult {
-        Ok(())
+    #[test]
+    fn single_stepping_a_breakpoint_traps_once() {
+        let mut cpu = debug_cpu(&[BRK, NOP]);
+        cpu.set_flag(CpuFlag::Trap, true);
+
+        cpu.step(1);
+
+        assert_eq!(cpu.register(RegisterId::Sp), STACK - 48);
+        assert_eq!(interrupt_frame(&mut cpu)[1], DebugTrap::Breakpoint as u64);
+    }
+
+    #[test]
+    fn debug_hooks_handle_traps_instead_of_the_guest() {
+        let (hook, traps) = recording_hook(true);
+
+        let mut cpu = debug_cpu(&[BRK, NOP, NOP]);
+        cpu.set_debug_hook(hook);
+
+        cpu.step(1);
+        cpu.set_flag(CpuFlag::Trap, true);
+        cpu.step(1);
+
+        assert_eq!(cpu.register(RegisterId::Ip), CODE_ADDRESS + 2);
+        assert_eq!(cpu.register(RegisterId::Sp), STACK);
+        assert_eq!(
+            *traps.borrow(),
+            [
+                (CODE_ADDRESS + 1, DebugTrap::Breakpoint as u64),
+                (CODE_ADDRESS + 2, DebugTrap::SingleStep as u64)
+            ]
+        );
+    }
+
+    #[test]
+    fn declined_traps_raise_debug() {
+        let (hook, traps) = recording_hook(false);
+
+        let mut cpu = debug_cpu(&[BRK, NOP]);
+        cpu.set_debug_hook(hook);
+
+        cpu.step(1);
+
+        assert_eq!(traps.borrow().len(), 1);
+        assert_eq!(cpu.register(RegisterId::Ip), HANDLER_ADDRESS);
+        assert_eq!(interrupt_frame(&mut cpu)[1], DebugTrap::Breakpoint as u64);
     }
 }
@@ -0,0 +1,575 @@
+use super::{
+    get_effective_address, get_register, get_register_number, InstructionResult, RegisterField,
+};
+use crate::cpu::float_control::*;
+use crate::cpu::register_id::FLOAT_REGISTER_COUNT;
+use crate::cpu::reserved_idt_entries::*;
+use crate::cpu::{Cpu, CpuFlag, Size};
+use crate::debug_println;
+
+// Decodes a floating point register field. Unlike the general purpose registers, 0 is a register (F0)
+fn get_float_register(cpu: &Cpu, fetched_byte: u8, field: RegisterField) -> Result<usize, u8> {
+    let number = get_register_number(cpu, fetched_byte, field) as usize;
+
+    if number < FLOAT_REGISTER_COUNT {
+        Ok(number)
+    } else {
+        Err(INVALID_INSTRUCTION)
+    }
+}
+
+// The size bits of a floating point instruction select single (4 bytes) or double (8 bytes) precision
+fn get_float_size(fetched_byte: u8) -> Result<Size, u8> {
+    match fetched_byte >> 6 & 0b11 {
+        2 => Ok(Size::Four),
+        3 => Ok(Size::Eight),
+        _ => Err(INVALID_INSTRUCTION),
+    }
+}
+
+// An operation that produces NaN without any of its operands being NaN, like 0 / 0 or infinity - infinity, is invalid
+fn invalid_operation_status(operands: &[f64], result: f64) -> u64 {
+    if result.is_nan() && !operands.iter().any(|operand| operand.is_nan()) {
+        INVALID_OPERATION_STATUS
+    } else {
+        0
+    }
+}
+
+// The arithmetic operations are done in double precision and rounded to the precision of the instruction afterwards.
+// This gives the same result as doing single precision operations directly, since a double has more than twice the
+// precision of a single
+
+fn float_add(lhs: f64, rhs: f64) -> (f64, u64) {
+    let result = lhs + rhs;
+    (result, invalid_operation_status(&[lhs, rhs], result))
+}
+
+fn float_sub(lhs: f64, rhs: f64) -> (f64, u64) {
+    let result = lhs - rhs;
+    (result, invalid_operation_status(&[lhs, rhs], result))
+}
+
+fn float_mul(lhs: f64, rhs: f64) -> (f64, u64) {
+    let result = lhs * rhs;
+    (result, invalid_operation_status(&[lhs, rhs], result))
+}
+
+fn float_div(lhs: f64, rhs: f64) -> (f64, u64) {
+    let result = lhs / rhs;
+
+    let divide_by_zero = if rhs == 0.0 && lhs.is_finite() && lhs != 0.0 {
+        DIVIDE_BY_ZERO_STATUS
+    } else {
+        0
+    };
+
+    (
+        result,
+        invalid_operation_status(&[lhs, rhs], result) | divide_by_zero,
+    )
+}
+
+impl Cpu {
+    fn float_register(&self, index: usize, size: Size) -> f64 {
+        match size {
+            Size::Four => f32::from_bits(self.float_registers[index] as u32) as f64,
+            _ => f64::from_bits(self.float_registers[index]),
+        }
+    }
+
+    fn float_register_assign(&mut self, index: usize, value: f64, size: Size) {
+        self.float_registers[index] = match size {
+            Size::Four => (value as f32).to_bits() as u64,
+            _ => value.to_bits(),
+        };
+    }
+
+    // Records exceptions in the status bits, and returns an error if any of them are unmasked
+    fn raise_float_exceptions(&mut self, status: u64) -> InstructionResult {
+        self.float_control |= status;
+
+        // Each mask bit is 8 bits above its status bit
+        if status & !(self.float_control >> 8) != 0 {
+            debug_println!("Unmasked floating point exception {:#x}", status);
+            self.error_code = status & !(self.float_control >> 8);
+            Err(FLOATING_POINT_EXCEPTION)
+        } else {
+            Ok(())
+        }
+    }
+
+    // Shared by the two operand arithmetic instructions. The operand byte holds the source register in the lowest
+    // 3 bits, the destination register in the next 3 bits and the precision in the size bits.
+    // The destination is left untouched if an unmasked exception is raised
+    fn float_arithmetic_instruction(
+        &mut self,
+        operation: fn(f64, f64) -> (f64, u64),
+    ) -> InstructionResult {
+        let fetched_byte = self.fetch_byte();
+
+        let src = get_float_register(self, fetched_byte, RegisterField::Low)?;
+        let dst = get_float_register(self, fetched_byte, RegisterField::High)?;
+        let size = get_float_size(fetched_byte)?;
+
+        let lhs = self.float_register(dst, size);
+        let rhs = self.float_register(src, size);
+
+        let (result, status) = operation(lhs, rhs);
+
+        debug_println!("Float operation on F{} and F{} = {}", dst, src, result);
+
+        self.raise_float_exceptions(status)?;
+
+        self.float_register_assign(dst, result, size);
+
+        Ok(())
+    }
+}
+
+#[allow(non_snake_case)]
+impl Cpu {
+    // Loads `size` bytes from memory into a floating point register.
+    // Encoded as a register byte in the same form as LDR followed by an address
+    pub(in crate::cpu) fn FLD(&mut self) -> InstructionResult {
+        let fetched_byte = self.fetch_byte();
+
+        let dst = get_float_register(self, fetched_byte, RegisterField::Low)?;
+        let size = get_float_size(fetched_byte)?;
+
+        let address = get_effective_address(self)?;
+        self.check_alignment(address, size as u64)?;
+
+        debug_println!("Loading F{} from {:#x}", dst, address);
+
+        let mut value = [0u8; 8];
+        self.read(&mut value[..size as usize], address);
+
+        self.float_registers[dst] = u64::from_le_bytes(value);
+
+        Ok(())
+    }
+
+    // Stores `size` bytes of a floating point register to memory. Encoded the same way as FLD
+    pub(in crate::cpu) fn FST(&mut self) -> InstructionResult {
+        let fetched_byte = self.fetch_byte();
+
+        let src = get_float_register(self, fetched_byte, RegisterField::Low)?;
+        let size = get_float_size(fetched_byte)?;
+
+        let address = get_effective_address(self)?;
+        self.check_alignment(address, size as u64)?;
+
+        debug_println!("Storing F{} to {:#x}", src, address);
+
+        let value = self.float_registers[src].to_le_bytes();
+        self.write(&value[..size as usize], address);
+
+        Ok(())
+    }
+
+    // Copies one floating point register to another. Encoded the same way as FADD
+    pub(in crate::cpu) fn FMOV(&mut self) -> InstructionResult {
+        let fetched_byte = self.fetch_byte();
+
+        let src = get_float_register(self, fetched_byte, RegisterField::Low)?;
+        let dst = get_float_register(self, fetched_byte, RegisterField::High)?;
+        let size = get_float_size(fetched_byte)?;
+
+        debug_println!("Moving F{} to F{}", src, dst);
+
+        let value = self.float_register(src, size);
+        self.float_register_assign(dst, value, size);
+
+        Ok(())
+    }
+
+    pub(in crate::cpu) fn FADD(&mut self) -> InstructionResult {
+        self.float_arithmetic_instruction(float_add)
+    }
+
+    pub(in crate::cpu) fn FSUB(&mut self) -> InstructionResult {
+        self.float_arithmetic_instruction(float_sub)
+    }
+
+    pub(in crate::cpu) fn FMUL(&mut self) -> InstructionResult {
+        self.float_arithmetic_instruction(float_mul)
+    }
+
+    pub(in crate::cpu) fn FDIV(&mut self) -> InstructionResult {
+        self.float_arithmetic_instruction(float_div)
+    }
+
+    // Writes the square root of the source register to the destination register. Encoded the same way as FADD.
+    // The square root of a negative number is an invalid operation
+    pub(in crate::cpu) fn FSQRT(&mut self) -> InstructionResult {
+        let fetched_byte = self.fetch_byte();
+
+        let src = get_float_register(self, fetched_byte, RegisterField::Low)?;
+        let dst = get_float_register(self, fetched_byte, RegisterField::High)?;
+        let size = get_float_size(fetched_byte)?;
+
+        let value = self.float_register(src, size);
+        let result = value.sqrt();
+
+        debug_println!("Square root of F{} = {}", src, result);
+
+        self.raise_float_exceptions(invalid_operation_status(&[value], result))?;
+
+        self.float_register_assign(dst, result, size);
+
+        Ok(())
+    }
+
+    // Compares the destination register with the source register. Encoded the same way as FADD.
+    // Zero is set if they are equal and Carry if the destination is less than the source, so the unsigned
+    // conditions (JC, JBE, JA, ...) can be used on the result. If either is NaN the operands are unordered, which
+    // sets Zero, Carry and Overflow and is an invalid operation. Negative is always cleared
+    pub(in crate::cpu) fn FCMP(&mut self) -> InstructionResult {
+        let fetched_byte = self.fetch_byte();
+
+        let src = get_float_register(self, fetched_byte, RegisterField::Low)?;
+        let dst = get_float_register(self, fetched_byte, RegisterField::High)?;
+        let size = get_float_size(fetched_byte)?;
+
+        let lhs = self.float_register(dst, size);
+        let rhs = self.float_register(src, size);
+
+        debug_println!("Comparing F{} ({}) with F{} ({})", dst, lhs, src, rhs);
+
+        let unordered = lhs.is_nan() || rhs.is_nan();
+
+        if unordered {
+            self.raise_float_exceptions(INVALID_OPERATION_STATUS)?;
+        }
+
+        self.set_flag(CpuFlag::Zero, unordered || lhs == rhs);
+        self.set_flag(CpuFlag::Carry, unordered || lhs < rhs);
+        self.set_flag(CpuFlag::Overflow, unordered);
+        self.set_flag(CpuFlag::Negative, false);
+
+        Ok(())
+    }
+
+    // Converts the signed 64 bit integer in a general purpose register to floating point.
+    // The operand byte holds the integer register in the lowest 3 bits, the floating point register in the next
+    // 3 bits and the precision of the result in the size bits
+    pub(in crate::cpu) fn FCVTIF(&mut self) -> InstructionResult {
+        let fetched_byte = self.fetch_byte();
+
+        let src_id = get_register(self, fetched_byte, RegisterField::Low)?;
+        let dst = get_float_register(self, fetched_byte, RegisterField::High)?;
+        let size = get_float_size(fetched_byte)?;
+
+        let value = self.register(src_id) as i64;
+
+        debug_println!("Converting {:?} ({}) to F{}", src_id, value, dst);
+
+        // Converting straight to the target precision avoids rounding twice
+        self.float_registers[dst] = match size {
+            Size::Four => (value as f32).to_bits() as u64,
+            _ => (value as f64).to_bits(),
+        };
+
+        Ok(())
+    }
+
+    // Converts a floating point register to a signed 64 bit integer, rounding towards zero.
+    // The operand byte holds the floating point register in the lowest 3 bits, the integer register in the next
+    // 3 bits and the precision of the source in the size bits. NaN and values that don't fit are an invalid
+    // operation and give the most negative integer
+    pub(in crate::cpu) fn FCVTFI(&mut self) -> InstructionResult {
+        let fetched_byte = self.fetch_byte();
+
+        let src = get_float_register(self, fetched_byte, RegisterField::Low)?;
+        let dst_id = get_register(self, fetched_byte, RegisterField::High)?;
+        let size = get_float_size(fetched_byte)?;
+
+        let value = self.float_register(src, size).trunc();
+
+        debug_println!("Converting F{} ({}) to {:?}", src, value, dst_id);
+
+        // i64::MIN is exactly representable, but i64::MAX rounds up to 2^63 which doesn't fit
+        let result = if value >= i64::MIN as f64 && value < i64::MAX as f64 {
+            value as i64
+        } else {
+            self.raise_float_exceptions(INVALID_OPERATION_STATUS)?;
+            i64::MIN
+        };
+
+        self.register_assign(dst_id, result as u64);
+
+        Ok(())
+    }
+
+    // Sets the floating point control and status register from the general purpose register in the lowest 3 bits
+    pub(in crate::cpu) fn FSCSR(&mut self) -> InstructionResult {
+        let fetched_byte = self.fetch_byte();
+
+        let src_id = get_register(self, fetched_byte, RegisterField::Low)?;
+
+        self.float_control = self.register(src_id);
+
+        Ok(())
+    }
+
+    // Reads the floating point control and status register into the general purpose register in the lowest 3 bits
+    pub(in crate::cpu) fn FRCSR(&mut self) -> InstructionResult {
+        let fetched_byte = self.fetch_byte();
+
+        let dst_id = get_register(self, fetched_byte, RegisterField::Low)?;
+
+        self.register_assign(dst_id, self.float_control);
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cpu::test_cpu::{execute, test_cpu};
+    use crate::cpu::RegisterId;
+
+    const FADD: u8 = 0x2c;
+    const FSUB: u8 = 0x3c;
+    const FDIV: u8 = 0x5c;
+    const FSQRT: u8 = 0x6c;
+    const FCMP: u8 = 0x7c;
+    const FCVTIF: u8 = 0x8c;
+    const FCVTFI: u8 = 0x9c;
+    const FMOV: u8 = 0xac;
+
+    // The operand byte of the floating point instructions. `dst` and `src` are register numbers, which are general
+    // purpose register IDs for the conversions
+    fn operands(size: Size, dst: u8, src: u8) -> u8 {
+        let size_bits = (size as u8).trailing_zeros() as u8;
+        size_bits << 6 | dst << 3 | src
+    }
+
+    // Runs an instruction on F0 and F1 with the given control register, returning the result and the CPU
+    fn float_operation(
+        code: &[u8],
+        float_control: u64,
+        lhs: f64,
+        rhs: f64,
+        size: Size,
+    ) -> (InstructionResult, Cpu) {
+        let mut cpu = test_cpu(code);
+        cpu.float_control = float_control;
+        cpu.float_register_assign(0, lhs, size);
+        cpu.float_register_assign(1, rhs, size);
+
+        (execute(&mut cpu), cpu)
+    }
+
+    fn flags(cpu: &Cpu) -> [bool; 4] {
+        [
+            cpu.get_flag(CpuFlag::Negative),
+            cpu.get_flag(CpuFlag::Overflow),
+            cpu.get_flag(CpuFlag::Zero),
+            cpu.get_flag(CpuFlag::Carry),
+        ]
+    }
+
+    #[test]
+    fn masked_exceptions_set_the_status_and_write_the_result() {
+        let fdiv = [FDIV, operands(Size::Eight, 0, 1)];
+
+        let (result, cpu) = float_operation(&fdiv, FLOAT_CONTROL_RESET, 1.0, 0.0, Size::Eight);
+        assert_eq!(result, Ok(()));
+        assert_eq!(cpu.float_register(0, Size::Eight), f64::INFINITY);
+        assert_eq!(
+            cpu.float_control,
+            FLOAT_CONTROL_RESET | DIVIDE_BY_ZERO_STATUS
+        );
+
+        // 0 / 0 is invalid, but not a division by zero
+        let (result, cpu) = float_operation(&fdiv, FLOAT_CONTROL_RESET, 0.0, 0.0, Size::Eight);
+        assert_eq!(result, Ok(()));
+        assert!(cpu.float_register(0, Size::Eight).is_nan());
+        assert_eq!(
+            cpu.float_control,
+            FLOAT_CONTROL_RESET | INVALID_OPERATION_STATUS
+        );
+
+        // NaN operands propagate without raising anything
+        let (result, cpu) = float_operation(&fdiv, 0, f64::NAN, 0.0, Size::Eight);
+        assert_eq!(result, Ok(()));
+        assert!(cpu.float_register(0, Size::Eight).is_nan());
+        assert_eq!(cpu.float_control, 0);
+
+        // The square root of -1
+        let (result, cpu) = float_operation(
+            &[FSQRT, operands(Size::Eight, 0, 1)],
+            FLOAT_CONTROL_RESET,
+            0.0,
+            -1.0,
+            Size::Eight,
+        );
+        assert_eq!(result, Ok(()));
+        assert!(cpu.float_register(0, Size::Eight).is_nan());
+        assert_eq!(
+            cpu.float_control,
+            FLOAT_CONTROL_RESET | INVALID_OPERATION_STATUS
+        );
+    }
+
+    #[test]
+    fn unmasked_exceptions_fault_without_writing_the_result() {
+        // Infinity - infinity with only invalid operations unmasked
+        let (result, cpu) = float_operation(
+            &[FSUB, operands(Size::Eight, 0, 1)],
+            DIVIDE_BY_ZERO_MASK,
+            f64::INFINITY,
+            f64::INFINITY,
+            Size::Eight,
+        );
+        assert_eq!(result, Err(FLOATING_POINT_EXCEPTION));
+        assert_eq!(cpu.error_code, INVALID_OPERATION_STATUS);
+        assert_eq!(cpu.float_register(0, Size::Eight), f64::INFINITY);
+        assert_eq!(
+            cpu.float_control,
+            DIVIDE_BY_ZERO_MASK | INVALID_OPERATION_STATUS
+        );
+
+        let fdiv = [FDIV, operands(Size::Four, 0, 1)];
+
+        // The division by zero is still masked
+        let (result, cpu) = float_operation(&fdiv, DIVIDE_BY_ZERO_MASK, 1.0, 0.0, Size::Four);
+        assert_eq!(result, Ok(()));
+        assert_eq!(cpu.error_code, 0);
+        assert_eq!(cpu.float_register(0, Size::Four), f64::INFINITY);
+
+        let (result, cpu) = float_operation(&fdiv, INVALID_OPERATION_MASK, 1.0, 0.0, Size::Four);
+        assert_eq!(result, Err(FLOATING_POINT_EXCEPTION));
+        assert_eq!(cpu.error_code, DIVIDE_BY_ZERO_STATUS);
+        assert_eq!(cpu.float_register(0, Size::Four), 1.0);
+    }
+
+    #[test]
+    fn unordered_comparisons() {
+        let fcmp = [FCMP, operands(Size::Eight, 0, 1)];
+
+        // Negative, Overflow, Zero, Carry
+        for (lhs, rhs, expected) in [
+            (1.0, 2.0, [false, false, false, true]),
+            (2.0, 1.0, [false, false, false, false]),
+            (-0.0, 0.0, [false, false, true, false]),
+            (f64::NAN, 1.0, [false, true, true, true]),
+            (1.0, f64::NAN, [false, true, true, true]),
+        ] {
+            let mut cpu = test_cpu(&fcmp);
+            cpu.set_flag(CpuFlag::Negative, true);
+            cpu.float_register_assign(0, lhs, Size::Eight);
+            cpu.float_register_assign(1, rhs, Size::Eight);
+
+            assert_eq!(execute(&mut cpu), Ok(()));
+            assert_eq!(flags(&cpu), expected, "{} and {}", lhs, rhs);
+            assert_eq!(
+                cpu.float_control & INVALID_OPERATION_STATUS != 0,
+                lhs.is_nan() || rhs.is_nan()
+            );
+        }
+
+        // Unmasked, the flags are left alone
+        let (result, cpu) = float_operation(&fcmp, 0, f64::NAN, 1.0, Size::Eight);
+        assert_eq!(result, Err(FLOATING_POINT_EXCEPTION));
+        assert_eq!(flags(&cpu), [false; 4]);
+    }
+
+    #[test]
+    fn conversion_to_integer_bounds() {
+        let fcvtfi = [FCVTFI, operands(Size::Eight, RegisterId::X0 as u8, 0)];
+        let two_to_the_63 = 9223372036854775808.0;
+
+        for (value, expected, invalid) in [
+            (i64::MIN as f64, i64::MIN, false),
+            (-1.9, -1, false),
+            (9223372036854774784.0, 9223372036854774784, false),
+            (two_to_the_63, i64::MIN, true),
+            (-two_to_the_63 * 2.0, i64::MIN, true),
+            (f64::NAN, i64::MIN, true),
+            (f64::INFINITY, i64::MIN, true),
+        ] {
+            let (result, cpu) =
+                float_operation(&fcvtfi, FLOAT_CONTROL_RESET, value, 0.0, Size::Eight);
+
+            assert_eq!(result, Ok(()));
+            assert_eq!(cpu.register(RegisterId::X0) as i64, expected, "{}", value);
+            assert_eq!(
+                cpu.float_control & INVALID_OPERATION_STATUS != 0,
+                invalid,
+                "{}",
+                value
+            );
+        }
+
+        // Unmasked, the destination is left alone
+        let mut cpu = test_cpu(&fcvtfi);
+        cpu.float_control = 0;
+        cpu.float_register_assign(0, two_to_the_63, Size::Eight);
+        cpu.register_assign(RegisterId::X0, 42);
+
+        assert_eq!(execute(&mut cpu), Err(FLOATING_POINT_EXCEPTION));
+        assert_eq!(cpu.error_code, INVALID_OPERATION_STATUS);
+        assert_eq!(cpu.register(RegisterId::X0), 42);
+    }
+
+    #[test]
+    fn single_precision_results_are_rounded() {
+        let fadd = [FADD, operands(Size::Four, 0, 1)];
+        let epsilon = f32::EPSILON as f64;
+
+        // Halfway between 1 and the next single rounds to even, anything above it rounds up
+        let (_, cpu) = float_operation(&fadd, FLOAT_CONTROL_RESET, 1.0, epsilon / 2.0, Size::Four);
+        assert_eq!(cpu.float_registers[0], 1.0f32.to_bits() as u64);
+
+        let (_, cpu) = float_operation(
+            &fadd,
+            FLOAT_CONTROL_RESET,
+            1.0,
+            epsilon / 2.0 + epsilon / 64.0,
+            Size::Four,
+        );
+        assert_eq!(
+            cpu.float_registers[0],
+            (1.0 + f32::EPSILON).to_bits() as u64
+        );
+
+        // Finite in double precision, but too large for a single
+        let (_, cpu) = float_operation(
+            &fadd,
+            FLOAT_CONTROL_RESET,
+            f32::MAX as f64,
+            f32::MAX as f64,
+            Size::Four,
+        );
+        assert_eq!(cpu.float_registers[0], f32::INFINITY.to_bits() as u64);
+
+        // 2^24 + 1 isn't a single
+        let mut cpu = test_cpu(&[FCVTIF, operands(Size::Four, 0, RegisterId::X0 as u8)]);
+        cpu.register_assign(RegisterId::X0, (1 << 24) + 1);
+
+        assert_eq!(execute(&mut cpu), Ok(()));
+        assert_eq!(cpu.float_registers[0], (16777216.0f32).to_bits() as u64);
+
+        // Moving a single only keeps its 32 bits
+        let mut cpu = test_cpu(&[FMOV, operands(Size::Four, 1, 0)]);
+        cpu.float_registers[0] = 0xdead_beef_3f80_0000;
+
+        assert_eq!(execute(&mut cpu), Ok(()));
+        assert_eq!(cpu.float_registers[1], 0x3f80_0000);
+    }
+
+    #[test]
+    fn only_single_and_double_precision_are_valid() {
+        for opcode in [FADD, FSQRT, FCMP, FMOV, FCVTIF, FCVTFI] {
+            for size in [Size::One, Size::Two] {
+                let mut cpu = test_cpu(&[opcode, operands(size, 1, 1)]);
+
+                assert_eq!(execute(&mut cpu), Err(INVALID_INSTRUCTION));
+            }
+        }
+    }
+}
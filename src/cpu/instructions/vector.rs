@@ -0,0 +1,420 @@
+use super::{
+    get_effective_address, get_register, get_register_number, sign_extend, trunucate_value,
+    InstructionResult, RegisterField,
+};
+use crate::cpu::register_id::VECTOR_REGISTER_COUNT;
+use crate::cpu::reserved_idt_entries::*;
+use crate::cpu::{Cpu, Size};
+use crate::debug_println;
+
+const VECTOR_BYTES: usize = 16;
+
+// Decodes a vector register field. Like the floating point registers, 0 is a register (V0)
+fn get_vector_register(cpu: &Cpu, fetched_byte: u8, field: RegisterField) -> Result<usize, u8> {
+    let number = get_register_number(cpu, fetched_byte, field) as usize;
+
+    if number < VECTOR_REGISTER_COUNT {
+        Ok(number)
+    } else {
+        Err(INVALID_INSTRUCTION)
+    }
+}
+
+// The size bits of a packed instruction select the width of each lane
+fn get_lane_size(fetched_byte: u8) -> Size {
+    Size::try_from(1 << (fetched_byte >> 6 & 0b11))
+        .expect("Unrecoverable error. Size is not 1, 2, 4, or 8")
+}
+
+fn lane_count(size: Size) -> u32 {
+    VECTOR_BYTES as u32 / size as u32
+}
+
+fn get_lane(value: u128, lane: u32, size: Size) -> u64 {
+    trunucate_value((value >> (lane * size as u32 * 8)) as u64, size)
+}
+
+fn set_lane(value: u128, lane: u32, lane_value: u64, size: Size) -> u128 {
+    let shift = lane * size as u32 * 8;
+    let mask = (trunucate_value(u64::MAX, size) as u128) << shift;
+
+    value & !mask | (trunucate_value(lane_value, size) as u128) << shift
+}
+
+// Applies an operation to each pair of lanes. The operation gets the truncated lanes and its result is truncated
+fn packed_operation(
+    lhs: u128,
+    rhs: u128,
+    size: Size,
+    operation: fn(u64, u64, Size) -> u64,
+) -> u128 {
+    (0..lane_count(size)).fold(0, |result, lane| {
+        let lane_value = operation(get_lane(lhs, lane, size), get_lane(rhs, lane, size), size);
+        set_lane(result, lane, lane_value, size)
+    })
+}
+
+fn lane_add(lhs: u64, rhs: u64, _size: Size) -> u64 {
+    lhs.wrapping_add(rhs)
+}
+
+fn lane_sub(lhs: u64, rhs: u64, _size: Size) -> u64 {
+    lhs.wrapping_sub(rhs)
+}
+
+fn lane_mul(lhs: u64, rhs: u64, _size: Size) -> u64 {
+    lhs.wrapping_mul(rhs)
+}
+
+fn lane_compare_equal(lhs: u64, rhs: u64, _size: Size) -> u64 {
+    if lhs == rhs {
+        u64::MAX
+    } else {
+        0
+    }
+}
+
+fn lane_compare_greater(lhs: u64, rhs: u64, size: Size) -> u64 {
+    if (sign_extend(lhs, size) as i64) > sign_extend(rhs, size) as i64 {
+        u64::MAX
+    } else {
+        0
+    }
+}
+
+impl Cpu {
+    // Shared by the packed arithmetic and compare instructions. The operand byte holds the source vector register in
+    // the lowest 3 bits, the destination vector register in the next 3 bits and the lane size in the size bits.
+    // Flags are left untouched
+    fn packed_instruction(&mut self, operation: fn(u64, u64, Size) -> u64) -> InstructionResult {
+        let fetched_byte = self.fetch_byte();
+
+        let src = get_vector_register(self, fetched_byte, RegisterField::Low)?;
+        let dst = get_vector_register(self, fetched_byte, RegisterField::High)?;
+        let size = get_lane_size(fetched_byte);
+
+        debug_println!(
+            "Packed operation on V{} and V{} with {:?} lanes",
+            dst,
+            src,
+            size
+        );
+
+        self.vector_registers[dst] = packed_operation(
+            self.vector_registers[dst],
+            self.vector_registers[src],
+            size,
+            operation,
+        );
+
+        Ok(())
+    }
+}
+
+#[allow(non_snake_case)]
+impl Cpu {
+    // Loads 16 bytes from memory into a vector register.
+    // Encoded as a register byte with the vector register in the lowest 3 bits followed by an address.
+    // The size bits are ignored
+    pub(in crate::cpu) fn VLD(&mut self) -> InstructionResult {
+        let fetched_byte = self.fetch_byte();
+
+        let dst = get_vector_register(self, fetched_byte, RegisterField::Low)?;
+
+        let address = get_effective_address(self)?;
+        self.check_alignment(address, VECTOR_BYTES as u64)?;
+
+        debug_println!("Loading V{} from {:#x}", dst, address);
+
+        let mut value = [0u8; VECTOR_BYTES];
+        self.read(&mut value, address);
+
+        self.vector_registers[dst] = u128::from_le_bytes(value);
+
+        Ok(())
+    }
+
+    // Stores the 16 bytes of a vector register to memory. Encoded the same way as VLD
+    pub(in crate::cpu) fn VST(&mut self) -> InstructionResult {
+        let fetched_byte = self.fetch_byte();
+
+        let src = get_vector_register(self, fetched_byte, RegisterField::Low)?;
+
+        let address = get_effective_address(self)?;
+        self.check_alignment(address, VECTOR_BYTES as u64)?;
+
+        debug_println!("Storing V{} to {:#x}", src, address);
+
+        let value = self.vector_registers[src].to_le_bytes();
+        self.write(&value, address);
+
+        Ok(())
+    }
+
+    // Copies one vector register to another. Encoded the same way as VADD, with the size bits ignored
+    pub(in crate::cpu) fn VMOV(&mut self) -> InstructionResult {
+        let fetched_byte = self.fetch_byte();
+
+        let src = get_vector_register(self, fetched_byte, RegisterField::Low)?;
+        let dst = get_vector_register(self, fetched_byte, RegisterField::High)?;
+
+        debug_println!("Moving V{} to V{}", src, dst);
+
+        self.vector_registers[dst] = self.vector_registers[src];
+
+        Ok(())
+    }
+
+    // Copies the lowest `size` bytes of a general purpose register into every lane of a vector register.
+    // The operand byte holds the general purpose register in the lowest 3 bits, the vector register in the next
+    // 3 bits and the lane size in the size bits
+    pub(in crate::cpu) fn VBCST(&mut self) -> InstructionResult {
+        let fetched_byte = self.fetch_byte();
+
+        let src_id = get_register(self, fetched_byte, RegisterField::Low)?;
+        let dst = get_vector_register(self, fetched_byte, RegisterField::High)?;
+        let size = get_lane_size(fetched_byte);
+
+        debug_println!(
+            "Broadcasting {:?} to V{} with {:?} lanes",
+            src_id,
+            dst,
+            size
+        );
+
+        let value = self.register(src_id);
+
+        self.vector_registers[dst] =
+            (0..lane_count(size)).fold(0, |result, lane| set_lane(result, lane, value, size));
+
+        Ok(())
+    }
+
+    pub(in crate::cpu) fn VADD(&mut self) -> InstructionResult {
+        self.packed_instruction(lane_add)
+    }
+
+    pub(in crate::cpu) fn VSUB(&mut self) -> InstructionResult {
+        self.packed_instruction(lane_sub)
+    }
+
+    // Keeps the lower half of each product
+    pub(in crate::cpu) fn VMUL(&mut self) -> InstructionResult {
+        self.packed_instruction(lane_mul)
+    }
+
+    // Sets every bit of a destination lane if it is equal to the source lane, and clears it otherwise
+    pub(in crate::cpu) fn VCMPEQ(&mut self) -> InstructionResult {
+        self.packed_instruction(lane_compare_equal)
+    }
+
+    // Sets every bit of a destination lane if it is greater than the source lane as a signed value,
+    // and clears it otherwise
+    pub(in crate::cpu) fn VCMPGT(&mut self) -> InstructionResult {
+        self.packed_instruction(lane_compare_greater)
+    }
+
+    // Replaces each lane of the destination with the lane of the source that it selects. The lowest bits of the
+    // destination lane are the index of the source lane, wrapping around at the number of lanes.
+    // Encoded the same way as VADD
+    pub(in crate::cpu) fn VSHUF(&mut self) -> InstructionResult {
+        let fetched_byte = self.fetch_byte();
+
+        let src = get_vector_register(self, fetched_byte, RegisterField::Low)?;
+        let dst = get_vector_register(self, fetched_byte, RegisterField::High)?;
+        let size = get_lane_size(fetched_byte);
+
+        debug_println!("Shuffling V{} by V{} with {:?} lanes", src, dst, size);
+
+        let selectors = self.vector_registers[dst];
+        let source = self.vector_registers[src];
+
+        self.vector_registers[dst] = (0..lane_count(size)).fold(0, |result, lane| {
+            let selected = get_lane(selectors, lane, size) as u32 % lane_count(size);
+            set_lane(result, lane, get_lane(source, selected, size), size)
+        });
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cpu::control_register::{ControlRegister, MODE_ALIGNMENT_CHECK};
+    use crate::cpu::test_cpu::{execute, test_cpu};
+    use crate::cpu::RegisterId;
+
+    const VLD: u8 = 0x0d;
+    const VST: u8 = 0x1d;
+    const VBCST: u8 = 0x3d;
+    const VADD: u8 = 0x4d;
+    const VSUB: u8 = 0x5d;
+    const VMUL: u8 = 0x6d;
+    const VCMPGT: u8 = 0x8d;
+    const VSHUF: u8 = 0x9d;
+
+    const SIZES: [Size; 4] = [Size::One, Size::Two, Size::Four, Size::Eight];
+
+    // The operand byte of the vector instructions. `dst` and `src` are register numbers
+    fn operands(size: Size, dst: u8, src: u8) -> u8 {
+        let size_bits = (size as u8).trailing_zeros() as u8;
+        size_bits << 6 | dst << 3 | src
+    }
+
+    fn broadcast(value: u64, size: Size) -> u128 {
+        (0..lane_count(size)).fold(0, |result, lane| set_lane(result, lane, value, size))
+    }
+
+    // Runs a packed instruction on V0 and V1 and returns V0
+    fn packed(opcode: u8, size: Size, lhs: u128, rhs: u128) -> u128 {
+        let mut cpu = test_cpu(&[opcode, operands(size, 0, 1)]);
+        cpu.vector_registers[0] = lhs;
+        cpu.vector_registers[1] = rhs;
+
+        execute(&mut cpu).unwrap();
+
+        cpu.vector_registers[0]
+    }
+
+    #[test]
+    fn lanes_wrap_around_without_carrying_into_their_neighbours() {
+        for size in SIZES {
+            let max = broadcast(u64::MAX, size);
+            let one = broadcast(1, size);
+            let sign_bit = broadcast(1 << (size as u64 * 8 - 1), size);
+
+            assert_eq!(packed(VADD, size, max, one), 0, "{:?}", size);
+            assert_eq!(packed(VSUB, size, 0, one), max, "{:?}", size);
+            assert_eq!(
+                packed(VMUL, size, sign_bit, broadcast(2, size)),
+                0,
+                "{:?}",
+                size
+            );
+            assert_eq!(packed(VMUL, size, max, max), one, "{:?}", size);
+        }
+    }
+
+    #[test]
+    fn compare_greater_is_signed() {
+        let mut lhs = [0u8; 16];
+        let mut rhs = [0u8; 16];
+
+        lhs[..4].copy_from_slice(&[0x80, 0x7f, 0xff, 0x00]);
+        rhs[..4].copy_from_slice(&[0x7f, 0x80, 0x00, 0xff]);
+
+        let mut expected = [0u8; 16];
+        expected[..4].copy_from_slice(&[0x00, 0xff, 0x00, 0xff]);
+
+        assert_eq!(
+            packed(
+                VCMPGT,
+                Size::One,
+                u128::from_le_bytes(lhs),
+                u128::from_le_bytes(rhs)
+            ),
+            u128::from_le_bytes(expected)
+        );
+    }
+
+    #[test]
+    fn shuffle_indices_wrap_around_at_the_lane_count() {
+        let source = (0..4).fold(0, |result, lane| {
+            set_lane(result, lane, 10 + lane as u64, Size::Four)
+        });
+
+        let selectors = [3, 4, 9, 0xffff_fffe]
+            .iter()
+            .enumerate()
+            .fold(0, |result, (lane, &selector)| {
+                set_lane(result, lane as u32, selector, Size::Four)
+            });
+
+        let shuffled = packed(VSHUF, Size::Four, selectors, source);
+
+        let lanes: Vec<u64> = (0..4)
+            .map(|lane| get_lane(shuffled, lane, Size::Four))
+            .collect();
+        assert_eq!(lanes, [13, 10, 11, 12]);
+
+        // Lane 17 of 16 single bytes is lane 1
+        let source = u128::from_le_bytes(std::array::from_fn(|lane| lane as u8));
+        assert_eq!(
+            packed(VSHUF, Size::One, broadcast(17, Size::One), source),
+            broadcast(1, Size::One)
+        );
+    }
+
+    #[test]
+    fn broadcast_truncates_to_the_lane_size() {
+        let value = 0x1122_3344_5566_7788;
+
+        for (size, lane) in [
+            (Size::One, 0x88),
+            (Size::Two, 0x7788),
+            (Size::Four, 0x5566_7788),
+            (Size::Eight, value),
+        ] {
+            let mut cpu = test_cpu(&[VBCST, operands(size, 2, RegisterId::X0 as u8)]);
+            cpu.register_assign(RegisterId::X0, value);
+
+            execute(&mut cpu).unwrap();
+
+            assert_eq!(cpu.vector_registers[2], broadcast(lane, size));
+            assert_eq!(get_lane(cpu.vector_registers[2], 0, size), lane);
+        }
+    }
+
+    #[test]
+    fn loads_and_stores_fault_unless_aligned_to_16_bytes() {
+        let vector = u128::from_le_bytes(std::array::from_fn(|byte| byte as u8));
+
+        for opcode in [VLD, VST] {
+            for (address, alignment_check, aligned) in [
+                (0x3010, true, true),
+                (0x3008, true, false),
+                (0x3008, false, true),
+            ] {
+                // VLD or VST V1, [X1]
+                let mut cpu = test_cpu(&[opcode, 1, 0b11_000_010, 0]);
+
+                if alignment_check {
+                    cpu.control_register_assign(ControlRegister::Mode, MODE_ALIGNMENT_CHECK);
+                }
+
+                cpu.register_assign(RegisterId::X1, address);
+                cpu.vector_registers[1] = vector;
+
+                if opcode == VLD {
+                    cpu.write(&vector.to_le_bytes(), address);
+                    cpu.vector_registers[1] = 0;
+                }
+
+                let result = execute(&mut cpu);
+
+                let mut memory = [0u8; 16];
+                cpu.read(&mut memory, address);
+
+                if aligned {
+                    assert_eq!(result, Ok(()));
+                    assert_eq!(cpu.vector_registers[1], vector);
+                    assert_eq!(u128::from_le_bytes(memory), vector);
+                } else {
+                    assert_eq!(result, Err(ALIGNMENT_CHECK));
+                    assert_eq!((cpu.fault_address, cpu.error_code), (address, 16));
+                    assert_eq!(
+                        cpu.vector_registers[1] == vector,
+                        opcode == VST,
+                        "The destination was written"
+                    );
+                    assert_eq!(
+                        u128::from_le_bytes(memory) == vector,
+                        opcode == VLD,
+                        "The destination was written"
+                    );
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,882 @@
+// Compiles hot basic blocks of guest code to host code with Cranelift. Only built with the "jit" feature.
+//
+// Every address the interpreter starts an instruction at is counted, and once it was reached HOT_THRESHOLD times the
+// block starting there is compiled. A block is a run of the instructions below, ending with a jump or before the
+// first instruction that isn't supported:
+//
+//   MOV, ADD, SUB, CMP, AND, OR and XOR with 8 byte operands, without prefixes, and without IP as an operand
+//   NOP
+//   JMP and the conditional jumps with an absolute 64-bit address (an address byte of 0 followed by the address)
+//
+// None of these access memory or ports or raise exceptions, so everything else, including faults and I/O, stays in
+// the interpreter. A compiled block loads the registers and the flags from the CPU when it starts and stores them
+// back before it returns, so the architectural state is exact between blocks, and every instruction sets the flags
+// the same way the interpreter does. A block runs in a single call to `Cpu::clock`, but is counted as a cycle, a
+// retired instruction and the bus accesses of its fetches per instruction, the same as in the interpreter. Interrupts
+// are only delivered between blocks, and a block only runs if it fits in what is left of the core's quantum, so the
+// scheduler switches cores at the same instructions as without the JIT.
+//
+// A block never crosses a page, and is compiled along with the version of its page on the address bus. If the page
+// is written the block is dropped, so self-modifying code is interpreted until it gets hot again
+
+use super::{Condition, CpuFlag, RegisterId};
+use crate::address_bus::{AddressBus, AddressMap, PAGE_SIZE_SHIFT};
+use crate::debug_println;
+
+use cranelift_codegen::ir::{condcodes::IntCC, types, AbiParam, InstBuilder, MemFlags, Value};
+use cranelift_codegen::settings::{self, Configurable};
+use cranelift_codegen::Context;
+use cranelift_frontend::{FunctionBuilder, FunctionBuilderContext, Variable};
+use cranelift_jit::{JITBuilder, JITModule};
+use cranelift_module::{default_libcall_names, Module};
+use num_traits::FromPrimitive;
+
+// Times the interpreter has to reach an address before the block starting there is compiled
+const HOT_THRESHOLD: u32 = 16;
+
+// The most instructions compiled into one block
+const MAX_BLOCK_INSTRUCTIONS: usize = 64;
+
+// X0-X4 and SP, the registers reachable without the EXT prefix, besides IP
+const BLOCK_REGISTER_COUNT: usize = 6;
+
+// Takes pointers to the registers and the flags of the CPU, and returns 1 if the block ended with a taken jump.
+// IP is set to the address the block continues at
+type BlockFunction = unsafe extern "C" fn(*mut u64, *mut u64) -> u64;
+
+pub struct CompiledBlock {
+    function: BlockFunction,
+    page_version: u64,
+    pub instruction_count: u64,
+    // Reads of the address bus the interpreter would have made to fetch the instructions
+    pub fetches: u64,
+}
+
+impl CompiledBlock {
+    // Runs the block. Returns true if it ended with a taken jump
+    pub fn run(&self, registers: &mut [u64], flags: &mut u64) -> bool {
+        // The block only touches the first 7 registers and the flags
+        unsafe { (self.function)(registers.as_mut_ptr(), flags) != 0 }
+    }
+}
+
+enum BlockState {
+    // Reached this many times by the interpreter
+    Cold(u32),
+    Compiled(CompiledBlock),
+    // The first instruction can't be compiled. Checked again if the page changes
+    Uncompilable { page_version: u64 },
+}
+
+#[derive(Clone, Copy)]
+enum Source {
+    Register(usize),
+    Immediate(u64),
+}
+
+#[derive(Clone, Copy)]
+enum Operation {
+    Move,
+    Add,
+    Sub,
+    Compare,
+    And,
+    Or,
+    Xor,
+}
+
+enum Instruction {
+    Operation {
+        operation: Operation,
+        dst: usize,
+        src: Source,
+    },
+    Nop,
+}
+
+enum BlockEnd {
+    Jump(u64),
+    ConditionalJump(Condition, u64),
+    // The block stopped before an instruction it can't compile, or got too long
+    FallThrough,
+}
+
+struct DecodedBlock {
+    instructions: Vec<Instruction>,
+    end: BlockEnd,
+    // The address right after the last compiled instruction
+    next_address: u64,
+}
+
+pub struct Jit {
+    module: JITModule,
+    context: Context,
+    builder_context: FunctionBuilderContext,
+    blocks: AddressMap<BlockState>,
+}
+
+impl Jit {
+    pub fn new() -> Result<Self, String> {
+        let mut flag_builder = settings::builder();
+        flag_builder
+            .set("opt_level", "speed")
+            .map_err(|e| e.to_string())?;
+
+        let isa = cranelift_native::builder()?
+            .finish(settings::Flags::new(flag_builder))
+            .map_err(|e| e.to_string())?;
+
+        let module = JITModule::new(JITBuilder::with_isa(isa, default_libcall_names()));
+        let context = module.make_context();
+
+        Ok(Self {
+            module,
+            context,
+            builder_context: FunctionBuilderContext::new(),
+            blocks: AddressMap::default(),
+        })
+    }
+
+    // Returns the compiled block starting at the address, compiling it if it just got hot. None means the
+    // interpreter has to execute the next instruction
+    pub fn block(&mut self, address: u64, address_bus: &mut AddressBus) -> Option<&CompiledBlock> {
+        let page_version = address_bus.page_version(address >> PAGE_SIZE_SHIFT);
+        let state = self.blocks.entry(address).or_insert(BlockState::Cold(0));
+
+        match state {
+            // The page was written since the block was compiled. The code of the block stays in the module, since
+            // Cranelift can't free single functions
+            BlockState::Compiled(block) if block.page_version != page_version => {
+                debug_println!("Dropping the compiled block at {:#x}", address);
+                *state = BlockState::Cold(1);
+            }
+
+            BlockState::Uncompilable {
+                page_version: uncompilable_version,
+            } if *uncompilable_version != page_version => {
+                *state = BlockState::Cold(1);
+            }
+
+            BlockState::Cold(count) => {
+                *count += 1;
+
+                if *count >= HOT_THRESHOLD {
+                    *state = compile(
+                        &mut self.module,
+                        &mut self.context,
+                        &mut self.builder_context,
+                        address,
+                        page_version,
+                        address_bus,
+                    );
+                }
+            }
+
+            _ => {}
+        }
+
+        match self.blocks.get(&address) {
+            Some(BlockState::Compiled(block)) => Some(block),
+            _ => None,
+        }
+    }
+}
+
+fn compile(
+    module: &mut JITModule,
+    context: &mut Context,
+    builder_context: &mut FunctionBuilderContext,
+    address: u64,
+    page_version: u64,
+    address_bus: &mut AddressBus,
+) -> BlockState {
+    let decoded = match decode_block(address, address_bus) {
+        Some(decoded) => decoded,
+        None => return BlockState::Uncompilable { page_version },
+    };
+
+    match compile_block(module, context, builder_context, &decoded) {
+        Some(function) => {
+            // The jump at the end counts as an instruction of the block
+            let instruction_count = decoded.instructions.len() as u64
+                + !matches!(decoded.end, BlockEnd::FallThrough) as u64;
+
+            debug_println!(
+                "Compiled the block at {:#x} with {} instructions",
+                address,
+                instruction_count
+            );
+
+            BlockState::Compiled(CompiledBlock {
+                function,
+                page_version,
+                instruction_count,
+                fetches: fetch_count(&decoded),
+            })
+        }
+        None => BlockState::Uncompilable { page_version },
+    }
+}
+
+// Decodes the instructions of a block. Returns None if not even the first instruction can be compiled
+fn decode_block(address: u64, address_bus: &mut AddressBus) -> Option<DecodedBlock> {
+    let page_size = 1 << PAGE_SIZE_SHIFT;
+
+    // The rest of the page. The last byte of the address space can't be mapped, so a block in the top page ends
+    // before it
+    let length = (page_size - (address & (page_size - 1))).min(u64::MAX - address);
+
+    if !address_bus.is_cacheable(address, length) {
+        return None;
+    }
+
+    let mut bytes = vec![0u8; length as usize];
+    address_bus.read(&mut bytes, address);
+
+    let mut instructions = Vec::new();
+    let mut offset = 0;
+
+    let end = loop {
+        if instructions.len() == MAX_BLOCK_INSTRUCTIONS {
+            break BlockEnd::FallThrough;
+        }
+
+        match decode_instruction(&bytes[offset..]) {
+            Some((Decoded::Instruction(instruction), length)) => {
+                instructions.push(instruction);
+                offset += length;
+            }
+            Some((Decoded::End(end), length)) => {
+                offset += length;
+                break end;
+            }
+            None => break BlockEnd::FallThrough,
+        }
+    };
+
+    if offset == 0 {
+        return None;
+    }
+
+    Some(DecodedBlock {
+        instructions,
+        end,
+        next_address: address + offset as u64,
+    })
+}
+
+enum Decoded {
+    Instruction(Instruction),
+    End(BlockEnd),
+}
+
+// The interpreter fetches the opcode and the operand byte, and then any immediate, or the address byte and the
+// address of a jump, each with one read
+fn fetch_count(decoded: &DecodedBlock) -> u64 {
+    let instruction_fetches: u64 = decoded
+        .instructions
+        .iter()
+        .map(|instruction| match instruction {
+            Instruction::Operation {
+                src: Source::Immediate(_),
+                ..
+            } => 3,
+            Instruction::Operation { .. } => 2,
+            Instruction::Nop => 1,
+        })
+        .sum();
+
+    let end_fetches = match decoded.end {
+        BlockEnd::Jump(_) | BlockEnd::ConditionalJump(..) => 3,
+        BlockEnd::FallThrough => 0,
+    };
+
+    instruction_fetches + end_fetches
+}
+
+// Decodes one instruction and returns its length, or None if it can't be compiled
+fn decode_instruction(bytes: &[u8]) -> Option<(Decoded, usize)> {
+    let opcode = *bytes.first()?;
+
+    let operation = match opcode {
+        0x90 => return Some((Decoded::Instruction(Instruction::Nop), 1)),
+        0x05 => {
+            let target = decode_absolute_address(&bytes[1..])?;
+            return Some((Decoded::End(BlockEnd::Jump(target)), 10));
+        }
+        // The conditional jumps are encoded in the order of `Condition`, starting at JZ with 0x15
+        opcode if opcode & 0xf == 0x5 => {
+            let condition = Condition::from_number((opcode >> 4) - 1)?;
+            let target = decode_absolute_address(&bytes[1..])?;
+            return Some((
+                Decoded::End(BlockEnd::ConditionalJump(condition, target)),
+                10,
+            ));
+        }
+        0x01 => Operation::Move,
+        0x03 => Operation::Add,
+        0x13 => Operation::Sub,
+        0x11 => Operation::Compare,
+        0x24 => Operation::And,
+        0x04 => Operation::Or,
+        0x14 => Operation::Xor,
+        _ => return None,
+    };
+
+    let operand_byte = *bytes.get(1)?;
+
+    // Only 8 byte operations
+    if operand_byte >> 6 != 0b11 {
+        return None;
+    }
+
+    let dst = decode_register(operand_byte >> 3 & 0b111)?;
+
+    let (src, length) = match operand_byte & 0b111 {
+        0 => {
+            let immediate = bytes.get(2..10)?;
+            (
+                Source::Immediate(u64::from_le_bytes(immediate.try_into().unwrap())),
+                10,
+            )
+        }
+        id => (Source::Register(decode_register(id)?), 2),
+    };
+
+    Some((
+        Decoded::Instruction(Instruction::Operation {
+            operation,
+            dst,
+            src,
+        }),
+        length,
+    ))
+}
+
+// Returns the index of a register in the registers of the CPU. IP isn't supported, since its value depends on how
+// much of the instruction was fetched
+fn decode_register(id: u8) -> Option<usize> {
+    match RegisterId::from_u8(id)? {
+        RegisterId::Ip => None,
+        id => Some(id.to_index()),
+    }
+}
+
+// Decodes an address byte of 0, which means a 64-bit displacement without a base or an index register
+fn decode_absolute_address(bytes: &[u8]) -> Option<u64> {
+    if *bytes.first()? != 0 {
+        return None;
+    }
+
+    Some(u64::from_le_bytes(bytes.get(1..9)?.try_into().unwrap()))
+}
+
+fn compile_block(
+    module: &mut JITModule,
+    context: &mut Context,
+    builder_context: &mut FunctionBuilderContext,
+    decoded: &DecodedBlock,
+) -> Option<BlockFunction> {
+    let pointer = module.target_config().pointer_type();
+
+    context.func.signature.params.push(AbiParam::new(pointer));
+    context.func.signature.params.push(AbiParam::new(pointer));
+    context
+        .func
+        .signature
+        .returns
+        .push(AbiParam::new(types::I64));
+
+    let mut builder = FunctionBuilder::new(&mut context.func, builder_context);
+
+    let entry = builder.create_block();
+    builder.append_block_params_for_function_params(entry);
+    builder.switch_to_block(entry);
+    builder.seal_block(entry);
+
+    let registers_pointer = builder.block_params(entry)[0];
+    let flags_pointer = builder.block_params(entry)[1];
+
+    let registers: Vec<Variable> = (0..BLOCK_REGISTER_COUNT)
+        .map(|index| {
+            let register = Variable::from_u32(index as u32);
+            builder.declare_var(register, types::I64);
+
+            let value = builder.ins().load(
+                types::I64,
+                MemFlags::trusted(),
+                registers_pointer,
+                (index * 8) as i32,
+            );
+            builder.def_var(register, value);
+
+            register
+        })
+        .collect();
+
+    let flags = Variable::from_u32(BLOCK_REGISTER_COUNT as u32);
+    builder.declare_var(flags, types::I64);
+    let value = builder
+        .ins()
+        .load(types::I64, MemFlags::trusted(), flags_pointer, 0);
+    builder.def_var(flags, value);
+
+    for instruction in &decoded.instructions {
+        if let Instruction::Operation {
+            operation,
+            dst,
+            src,
+        } = *instruction
+        {
+            let rhs = match src {
+                Source::Register(index) => builder.use_var(registers[index]),
+                Source::Immediate(value) => builder.ins().iconst(types::I64, value as i64),
+            };
+
+            let lhs = builder.use_var(registers[dst]);
+
+            let result = match operation {
+                Operation::Move => Some(rhs),
+                Operation::Add => {
+                    let result = builder.ins().iadd(lhs, rhs);
+
+                    // Signed overflow if both operands have a different sign than the result
+                    let lhs_sign = builder.ins().bxor(lhs, result);
+                    let rhs_sign = builder.ins().bxor(rhs, result);
+                    let overflow = builder.ins().band(lhs_sign, rhs_sign);
+                    let overflow = builder.ins().icmp_imm(IntCC::SignedLessThan, overflow, 0);
+
+                    let carry = builder.ins().icmp(IntCC::UnsignedGreaterThan, lhs, result);
+
+                    set_arithmetic_flags(&mut builder, flags, result, carry, overflow);
+                    Some(result)
+                }
+                Operation::Sub | Operation::Compare => {
+                    let result = builder.ins().isub(lhs, rhs);
+
+                    // Signed overflow if the operands have different signs and the result has the sign of rhs
+                    let operand_signs = builder.ins().bxor(lhs, rhs);
+                    let result_sign = builder.ins().bxor(lhs, result);
+                    let overflow = builder.ins().band(operand_signs, result_sign);
+                    let overflow = builder.ins().icmp_imm(IntCC::SignedLessThan, overflow, 0);
+
+                    let carry = builder.ins().icmp(IntCC::UnsignedLessThan, lhs, result);
+
+                    set_arithmetic_flags(&mut builder, flags, result, carry, overflow);
+
+                    match operation {
+                        Operation::Compare => None,
+                        _ => Some(result),
+                    }
+                }
+                Operation::And | Operation::Or | Operation::Xor => {
+                    let result = match operation {
+                        Operation::And => builder.ins().band(lhs, rhs),
+                        Operation::Or => builder.ins().bor(lhs, rhs),
+                        _ => builder.ins().bxor(lhs, rhs),
+                    };
+
+                    // The interpreter sets Zero to the sign bit of the result, and leaves the other flags alone
+                    let sign = builder.ins().icmp_imm(IntCC::SignedLessThan, result, 0);
+                    set_flag(&mut builder, flags, CpuFlag::Zero, sign);
+
+                    Some(result)
+                }
+            };
+
+            if let Some(result) = result {
+                builder.def_var(registers[dst], result);
+            }
+        }
+    }
+
+    let next_address = builder
+        .ins()
+        .iconst(types::I64, decoded.next_address as i64);
+
+    let (ip, taken) = match decoded.end {
+        BlockEnd::Jump(target) => (
+            builder.ins().iconst(types::I64, target as i64),
+            builder.ins().iconst(types::I64, 1),
+        ),
+        BlockEnd::ConditionalJump(condition, target) => {
+            let condition_met = condition_met(&mut builder, flags, condition);
+            let target = builder.ins().iconst(types::I64, target as i64);
+            let ip = builder.ins().select(condition_met, target, next_address);
+            (ip, builder.ins().uextend(types::I64, condition_met))
+        }
+        BlockEnd::FallThrough => (next_address, builder.ins().iconst(types::I64, 0)),
+    };
+
+    for (index, register) in registers.iter().enumerate() {
+        let value = builder.use_var(*register);
+        builder.ins().store(
+            MemFlags::trusted(),
+            value,
+            registers_pointer,
+            (index * 8) as i32,
+        );
+    }
+
+    builder.ins().store(
+        MemFlags::trusted(),
+        ip,
+        registers_pointer,
+        (RegisterId::Ip.to_index() * 8) as i32,
+    );
+
+    let value = builder.use_var(flags);
+    builder
+        .ins()
+        .store(MemFlags::trusted(), value, flags_pointer, 0);
+
+    builder.ins().return_(&[taken]);
+    builder.finalize();
+
+    let compiled = match module.declare_anonymous_function(&context.func.signature) {
+        Ok(id) => module.define_function(id, context).map(|_| id),
+        Err(e) => Err(e),
+    };
+
+    module.clear_context(context);
+
+    let id = match compiled {
+        Ok(id) => id,
+        Err(e) => {
+            debug_println!("Failed to compile block: {}", e);
+            return None;
+        }
+    };
+
+    if let Err(e) = module.finalize_definitions() {
+        debug_println!("Failed to finalize block: {}", e);
+        return None;
+    }
+
+    let code = module.get_finalized_function(id);
+
+    // The signature was built above to match BlockFunction
+    Some(unsafe { std::mem::transmute::<*const u8, BlockFunction>(code) })
+}
+
+// Sets Zero, Negative, Carry and Overflow the way ADD, SUB and CMP do with 8 byte operands
+fn set_arithmetic_flags(
+    builder: &mut FunctionBuilder,
+    flags: Variable,
+    result: Value,
+    carry: Value,
+    overflow: Value,
+) {
+    let zero = builder.ins().icmp_imm(IntCC::Equal, result, 0);
+    let negative = builder.ins().icmp_imm(IntCC::SignedLessThan, result, 0);
+
+    set_flag(builder, flags, CpuFlag::Zero, zero);
+    set_flag(builder, flags, CpuFlag::Negative, negative);
+    set_flag(builder, flags, CpuFlag::Carry, carry);
+    set_flag(builder, flags, CpuFlag::Overflow, overflow);
+}
+
+fn set_flag(builder: &mut FunctionBuilder, flags: Variable, flag: CpuFlag, value: Value) {
+    let bit = builder.ins().uextend(types::I64, value);
+    let bit = builder.ins().ishl_imm(bit, flag as i64);
+
+    let current = builder.use_var(flags);
+    let cleared = builder.ins().band_imm(current, !(1i64 << flag as i64));
+    let updated = builder.ins().bor(cleared, bit);
+
+    builder.def_var(flags, updated);
+}
+
+// Returns 1 if the flag is set and 0 otherwise
+fn get_flag(builder: &mut FunctionBuilder, flags: Variable, flag: CpuFlag) -> Value {
+    let current = builder.use_var(flags);
+    let shifted = builder.ins().ushr_imm(current, flag as i64);
+    builder.ins().band_imm(shifted, 1)
+}
+
+// The same tests as `Cpu::condition_met`. Returns a value the select instruction can test
+fn condition_met(builder: &mut FunctionBuilder, flags: Variable, condition: Condition) -> Value {
+    let negative = get_flag(builder, flags, CpuFlag::Negative);
+    let overflow = get_flag(builder, flags, CpuFlag::Overflow);
+    let zero = get_flag(builder, flags, CpuFlag::Zero);
+    let carry = get_flag(builder, flags, CpuFlag::Carry);
+
+    let met = match condition {
+        Condition::Zero => zero,
+        Condition::NotZero => builder.ins().bxor_imm(zero, 1),
+        Condition::Overflow => overflow,
+        Condition::NotOverflow => builder.ins().bxor_imm(overflow, 1),
+        Condition::Negative => negative,
+        Condition::NotNegative => builder.ins().bxor_imm(negative, 1),
+        Condition::Carry => carry,
+        Condition::NotCarry => builder.ins().bxor_imm(carry, 1),
+        Condition::BelowOrEqual => builder.ins().bor(carry, zero),
+        Condition::Above => {
+            let below_or_equal = builder.ins().bor(carry, zero);
+            builder.ins().bxor_imm(below_or_equal, 1)
+        }
+        Condition::Less => builder.ins().bxor(negative, overflow),
+        Condition::GreaterOrEqual => {
+            let less = builder.ins().bxor(negative, overflow);
+            builder.ins().bxor_imm(less, 1)
+        }
+        Condition::LessOrEqual => {
+            let less = builder.ins().bxor(negative, overflow);
+            builder.ins().bor(zero, less)
+        }
+        Condition::Greater => {
+            let less = builder.ins().bxor(negative, overflow);
+            let less_or_equal = builder.ins().bor(zero, less);
+            builder.ins().bxor_imm(less_or_equal, 1)
+        }
+    };
+
+    builder.ins().icmp_imm(IntCC::NotEqual, met, 0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::test_cpu::{test_cpu, CODE_ADDRESS};
+    use super::super::Cpu;
+    use super::*;
+    use crate::memory::Memory;
+
+    const ADD: u8 = 0x03;
+    const SUB: u8 = 0x13;
+    const CMP: u8 = 0x11;
+    const JMP: u8 = 0x05;
+    const JZ: u8 = 0x15;
+
+    // Values around the carry and the signed overflow of 64-bit additions and subtractions
+    const EDGE_VALUES: [u64; 7] = [
+        0,
+        1,
+        2,
+        i64::MAX as u64,
+        i64::MIN as u64,
+        u64::MAX - 1,
+        u64::MAX,
+    ];
+
+    fn operands(dst: RegisterId, src: Option<RegisterId>) -> u8 {
+        0b11 << 6 | (dst as u8) << 3 | src.map_or(0, |src| src as u8)
+    }
+
+    // A jump or conditional jump to an absolute address
+    fn jump(opcode: u8, target: u64) -> Vec<u8> {
+        let mut code = vec![opcode, 0x00];
+        code.extend(target.to_le_bytes());
+        code
+    }
+
+    // Returns a CPU with the JIT enabled whose next clock compiles and runs the block at CODE_ADDRESS
+    fn compiled_cpu(code: &[u8]) -> Cpu {
+        let mut cpu = test_cpu(code);
+        cpu.enable_jit().unwrap();
+
+        let jit = cpu.jit.as_mut().unwrap();
+
+        for _ in 1..HOT_THRESHOLD {
+            assert!(jit
+                .block(CODE_ADDRESS, &mut cpu.address_bus.borrow_mut())
+                .is_none());
+        }
+
+        cpu
+    }
+
+    fn state(cpu: &Cpu) -> (Vec<u64>, u64, Vec<Option<u64>>) {
+        let counters = (0..5)
+            .map(|index| cpu.performance_counters.read(index))
+            .collect();
+
+        (cpu.registers.to_vec(), cpu.flags, counters)
+    }
+
+    // Runs the block at CODE_ADDRESS once through the JIT and the same number of instructions through the
+    // interpreter, starting from the same registers, and checks that both end in the same state
+    fn compare(code: &[u8], registers: &[(RegisterId, u64)]) -> Cpu {
+        let mut compiled = compiled_cpu(code);
+        let mut interpreted = test_cpu(code);
+
+        for &(id, value) in registers {
+            compiled.register_assign(id, value);
+            interpreted.register_assign(id, value);
+        }
+
+        let instructions = compiled.clock(u64::MAX);
+        assert!(instructions > 1, "The block wasn't compiled");
+
+        interpreted.step(instructions as usize);
+
+        assert_eq!(state(&compiled), state(&interpreted), "{:x?}", registers);
+
+        compiled
+    }
+
+    #[test]
+    fn arithmetic_flags_match_the_interpreter() {
+        for opcode in [ADD, SUB, CMP] {
+            for lhs in EDGE_VALUES {
+                for rhs in EDGE_VALUES {
+                    let mut code = vec![opcode, operands(RegisterId::X1, Some(RegisterId::X2))];
+                    code.extend(jump(JMP, CODE_ADDRESS));
+
+                    compare(&code, &[(RegisterId::X1, lhs), (RegisterId::X2, rhs)]);
+
+                    let mut code = vec![opcode, operands(RegisterId::X1, None)];
+                    code.extend(rhs.to_le_bytes());
+                    code.extend(jump(JMP, CODE_ADDRESS));
+
+                    compare(&code, &[(RegisterId::X1, lhs)]);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn logical_operations_and_moves_match_the_interpreter() {
+        // MOV, AND, OR and XOR
+        for opcode in [0x01, 0x24, 0x04, 0x14] {
+            for lhs in EDGE_VALUES {
+                for rhs in EDGE_VALUES {
+                    let mut code = vec![opcode, operands(RegisterId::X1, Some(RegisterId::X2))];
+                    code.extend(jump(JMP, CODE_ADDRESS));
+
+                    compare(&code, &[(RegisterId::X1, lhs), (RegisterId::X2, rhs)]);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn conditional_jumps_match_the_interpreter() {
+        let target = CODE_ADDRESS + 0x100;
+
+        for condition in 0..14 {
+            let opcode = (condition + 1) << 4 | 0x5;
+
+            for (lhs, rhs) in [
+                (1, 1),
+                (1, 2),
+                (2, 1),
+                (i64::MIN as u64, 1),
+                (i64::MAX as u64, u64::MAX),
+            ] {
+                // CMP X1, X2, then the conditional jump. Without a compare the flags are all clear
+                let mut code = vec![CMP, operands(RegisterId::X1, Some(RegisterId::X2))];
+                code.extend(jump(opcode, target));
+
+                let cpu = compare(&code, &[(RegisterId::X1, lhs), (RegisterId::X2, rhs)]);
+                let ip = cpu.register(RegisterId::Ip);
+
+                assert!(ip == target || ip == CODE_ADDRESS + 12);
+            }
+        }
+
+        // JZ taken and not taken
+        let mut code = vec![CMP, operands(RegisterId::X1, Some(RegisterId::X2))];
+        code.extend(jump(JZ, target));
+
+        let taken = compare(&code, &[(RegisterId::X1, 5), (RegisterId::X2, 5)]);
+        assert_eq!(taken.register(RegisterId::Ip), target);
+
+        let not_taken = compare(&code, &[(RegisterId::X1, 5), (RegisterId::X2, 6)]);
+        assert_eq!(not_taken.register(RegisterId::Ip), CODE_ADDRESS + 12);
+    }
+
+    #[test]
+    fn writes_to_the_page_of_a_block_drop_it() {
+        let patch_address = CODE_ADDRESS + 0x100;
+
+        // ADD X1, 1 and a jump to the patch, which the JIT compiles
+        let mut code = vec![ADD, operands(RegisterId::X1, None)];
+        code.extend(1u64.to_le_bytes());
+        code.extend(jump(JMP, patch_address));
+
+        // The patch, in the same page, writes X2 over the immediate of the ADD with a MEMD MOV and jumps back
+        code.resize((patch_address - CODE_ADDRESS) as usize, 0x90);
+        code.extend([0x12, 0x01, 0b11 << 6 | RegisterId::X2 as u8, 0x00]);
+        code.extend((CODE_ADDRESS + 2).to_le_bytes());
+        code.extend(jump(JMP, CODE_ADDRESS));
+
+        let mut compiled = compiled_cpu(&code);
+        let mut interpreted = test_cpu(&code);
+
+        compiled.register_assign(RegisterId::X2, 16);
+        interpreted.register_assign(RegisterId::X2, 16);
+
+        // Long enough for the patched block to get hot and be compiled again
+        let mut instructions = 0;
+
+        for _ in 0..100 {
+            instructions += compiled.clock(u64::MAX);
+        }
+
+        interpreted.step(instructions as usize);
+
+        assert_eq!(state(&compiled), state(&interpreted));
+
+        // Only the first ADD ran with an immediate of 1
+        assert_eq!(compiled.register(RegisterId::X1) % 16, 1);
+        assert!(compiled.register(RegisterId::X1) > 16 * HOT_THRESHOLD as u64);
+    }
+
+    #[test]
+    fn blocks_longer_than_the_quantum_are_interpreted() {
+        let mut code = vec![ADD, operands(RegisterId::X1, Some(RegisterId::X2))];
+        code.extend(jump(JMP, CODE_ADDRESS));
+
+        let mut compiled = compiled_cpu(&code);
+        let mut interpreted = test_cpu(&code);
+
+        assert_eq!(compiled.clock(1), 1);
+        interpreted.step(1);
+
+        assert_eq!(state(&compiled), state(&interpreted));
+        assert_eq!(compiled.register(RegisterId::Ip), CODE_ADDRESS + 2);
+    }
+
+    #[test]
+    fn blocks_in_the_top_page_compile() {
+        let page = u64::MAX << PAGE_SIZE_SHIFT;
+
+        // At the start of the page, and ending right before the last byte of the address space, which can't be
+        // mapped
+        for address in [page, u64::MAX - 15] {
+            let mut code = vec![ADD, operands(RegisterId::X1, Some(RegisterId::X2))];
+            code.extend(jump(JMP, address));
+
+            let mut cpus = [test_cpu(&code), test_cpu(&code)];
+
+            for cpu in &mut cpus {
+                let mut address_bus = cpu.address_bus.borrow_mut();
+                address_bus
+                    .add_entry(page, u64::MAX - page, Memory::new(u64::MAX - page))
+                    .unwrap();
+                address_bus.write(&code, address);
+                drop(address_bus);
+
+                cpu.register_assign(RegisterId::Ip, address);
+                cpu.register_assign(RegisterId::X2, 3);
+            }
+
+            let [mut compiled, mut interpreted] = cpus;
+            compiled.enable_jit().unwrap();
+
+            let mut instructions = 0;
+
+            for _ in 0..2 * HOT_THRESHOLD {
+                instructions += compiled.clock(u64::MAX);
+            }
+
+            // Interpreted until the block got hot, and then run compiled
+            assert!(instructions > 2 * HOT_THRESHOLD as u64);
+
+            interpreted.step(instructions as usize);
+
+            assert_eq!(state(&compiled), state(&interpreted));
+            assert_eq!(compiled.register(RegisterId::X1), 3 * instructions / 2);
+        }
+    }
+}
@@ -0,0 +1,15 @@
+use super::RegisterId;
+
+// Set by the MEMD and MEMS prefixes to select which operand of the next instruction is in memory
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MemoryOperand {
+    Destination,
+    Source,
+}
+
+// The destination operand of an instruction in the form of "dst, src"
+#[derive(Debug, Clone, Copy)]
+pub enum Operand {
+    Register(RegisterId),
+    Memory(u64),
+}
@@ -0,0 +1,64 @@
+// Counters of events in the CPU that guests read with RDPMC to measure themselves
+
+#[derive(Debug, Clone, Copy)]
+pub enum PerformanceCounter {
+    // Instructions that finished without raising an exception
+    RetiredInstructions = 0,
+    // Clock cycles, including the ones spent halted. Every instruction takes one, also when it runs in a block
+    // compiled by the JIT
+    Cycles = 1,
+    // Jumps whose condition was met, calls, and returns
+    TakenBranches = 2,
+    // Interrupts and exceptions that were delivered
+    Interrupts = 3,
+    // Reads and writes of the address bus and the port bus, including instruction fetches
+    BusAccesses = 4,
+}
+
+pub const PERFORMANCE_COUNTER_COUNT: usize = 5;
+
+// Every counter is enabled after a reset
+const ALL_COUNTERS: u64 = (1 << PERFORMANCE_COUNTER_COUNT) - 1;
+
+pub struct PerformanceCounters {
+    counts: [u64; PERFORMANCE_COUNTER_COUNT],
+    // Bit n enables the counter with index n
+    enabled: u64,
+}
+
+impl PerformanceCounters {
+    pub fn new() -> Self {
+        Self {
+            counts: [0; PERFORMANCE_COUNTER_COUNT],
+            enabled: ALL_COUNTERS,
+        }
+    }
+
+    pub fn increment(&mut self, counter: PerformanceCounter) {
+        self.add(counter, 1);
+    }
+
+    pub fn add(&mut self, counter: PerformanceCounter, count: u64) {
+        if self.enabled >> counter as u64 & 1 == 1 {
+            self.counts[counter as usize] = self.counts[counter as usize].wrapping_add(count);
+        }
+    }
+
+    pub fn read(&self, index: u64) -> Option<u64> {
+        self.counts.get(index as usize).copied()
+    }
+
+    // The lowest 8 bits of the control value enable the counters with the same index and disable the rest.
+    // Bits 8-15 reset the counter with the index 8 lower to 0
+    pub fn control(&mut self, value: u64) {
+        self.enabled = value & ALL_COUNTERS;
+
+        let reset = value >> 8 & ALL_COUNTERS;
+
+        for (index, count) in self.counts.iter_mut().enumerate() {
+            if reset >> index & 1 == 1 {
+                *count = 0;
+            }
+        }
+    }
+}
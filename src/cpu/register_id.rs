@@ -1,6 +1,15 @@
 use num_derive::FromPrimitive;
 
-#[derive(Debug, Copy, Clone, FromPrimitive)]
+pub const REGISTER_COUNT: usize = 18;
+
+// The floating point registers F0-F15 are numbered directly by their register fields, without the immediate value id
+pub const FLOAT_REGISTER_COUNT: usize = 16;
+
+// The vector registers V0-V15 are numbered the same way as the floating point registers
+pub const VECTOR_REGISTER_COUNT: usize = 16;
+
+/// X5 through X15 are only reachable through the `EXT` prefix, since a plain register field is only 3 bits wide
+#[derive(Debug, Copy, Clone, PartialEq, Eq, FromPrimitive)]
 pub enum RegisterId {
     X0 = 1,
     X1 = 2,
@@ -9,6 +18,17 @@ pub enum RegisterId {
     X4 = 5,
     Sp = 6,
     Ip = 7,
+    X5 = 8,
+    X6 = 9,
+    X7 = 10,
+    X8 = 11,
+    X9 = 12,
+    X10 = 13,
+    X11 = 14,
+    X12 = 15,
+    X13 = 16,
+    X14 = 17,
+    X15 = 18,
 }
 
 impl RegisterId {
@@ -17,3 +37,31 @@ impl RegisterId {
         self as usize - 1
     }
 }
+
+impl TryFrom<&str> for RegisterId {
+    type Error = ();
+
+    fn try_from(value: &str) -> Result<Self, Self::Error> {
+        match value.to_ascii_lowercase().as_str() {
+            "x0" => Ok(Self::X0),
+            "x1" => Ok(Self::X1),
+            "x2" => Ok(Self::X2),
+            "x3" => Ok(Self::X3),
+            "x4" => Ok(Self::X4),
+            "x5" => Ok(Self::X5),
+            "x6" => Ok(Self::X6),
+            "x7" => Ok(Self::X7),
+            "x8" => Ok(Self::X8),
+            "x9" => Ok(Self::X9),
+            "x10" => Ok(Self::X10),
+            "x11" => Ok(Self::X11),
+            "x12" => Ok(Self::X12),
+            "x13" => Ok(Self::X13),
+            "x14" => Ok(Self::X14),
+            "x15" => Ok(Self::X15),
+            "sp" => Ok(Self::Sp),
+            "ip" => Ok(Self::Ip),
+            _ => Err(()),
+        }
+    }
+}
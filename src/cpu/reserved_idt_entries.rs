@@ -1,3 +1,36 @@
+// Interrupt vectors raised by the CPU itself.
+//
+// Every interrupt pushes the same frame, so RETI can return from any of them:
+//
+//   SP + 40  SP from before the interrupt
+//   SP + 32  Mode control register from before the interrupt
+//   SP + 24  Flags from before the interrupt
+//   SP + 16  Return address
+//   SP + 8   Error code
+//   SP + 0   Fault address
+//
+// Faults return to the start of the faulting instruction, including its prefixes, so the handler can retry it.
+// Traps return to the next instruction. The error code and the fault address are 0 unless noted otherwise.
+//
+// The frame is pushed on the stack in the InterruptStack control register for the vectors in InterruptStackVectors,
+// on the stack in KernelStack when the interrupt is taken while running as a user, and on the current stack
+// otherwise. Handlers run as the supervisor, and RETI restores SP and the mode from the frame
+
+// Fault
 pub const DIVIDE_BY_ZERO: u8 = 0;
+// Fault
 pub const INVALID_INSTRUCTION: u8 = 1;
+// Fault
 pub const GENERAL_PROTECTION_FAULT: u8 = 2;
+// Fault
+pub const DIVIDE_OVERFLOW: u8 = 3;
+// Fault. The error code holds the status bits of the unmasked exceptions
+pub const FLOATING_POINT_EXCEPTION: u8 = 4;
+// Trap
+pub const SYSTEM_CALL: u8 = 5;
+// Fault. The error code is the size of the access and the fault address is the unaligned address
+pub const ALIGNMENT_CHECK: u8 = 6;
+// Trap
+pub const OVERFLOW: u8 = 7;
+// Trap. The error code is 1 after BRK and 2 after an instruction that started with the Trap flag set
+pub const DEBUG: u8 = 8;
@@ -0,0 +1,55 @@
+// Builds CPUs that run hand assembled code for the unit tests
+
+use super::{BootParameters, Cpu, InstructionResult, ResetVector};
+use crate::address_bus::AddressBus;
+use crate::memory::Memory;
+use crate::port_bus::PortBus;
+
+use std::{cell::RefCell, rc::Rc};
+
+pub const MEMORY_SIZE: u64 = 0x10000;
+
+// The code under test is loaded here. The stack starts at the end of memory
+pub const CODE_ADDRESS: u64 = 0x1000;
+
+// Returns a CPU with MEMORY_SIZE bytes of memory that starts executing the code at CODE_ADDRESS
+pub fn test_cpu(code: &[u8]) -> Cpu {
+    let address_bus = Rc::new(RefCell::new(AddressBus::new()));
+
+    address_bus
+        .borrow_mut()
+        .add_entry(0, MEMORY_SIZE, Memory::new(MEMORY_SIZE))
+        .unwrap();
+    address_bus.borrow_mut().write(code, CODE_ADDRESS);
+
+    let boot_parameters = BootParameters {
+        reset_vector: ResetVector::Address(CODE_ADDRESS),
+        initial_sp: MEMORY_SIZE,
+        ..Default::default()
+    };
+
+    Cpu::new(
+        address_bus,
+        Rc::new(RefCell::new(PortBus::new())),
+        boot_parameters,
+        0,
+        1,
+    )
+}
+
+// Executes the next instruction without delivering the exception it raises, so the test can check it
+pub fn execute(cpu: &mut Cpu) -> InstructionResult {
+    let opcode = cpu.fetch_byte();
+    let callback = cpu.lookup_instruction(opcode).unwrap().callback.unwrap();
+
+    callback(cpu)
+}
+
+impl Cpu {
+    // Runs the given number of instructions, one at a time
+    pub fn step(&mut self, instructions: usize) {
+        for _ in 0..instructions {
+            self.clock(1);
+        }
+    }
+}
@@ -0,0 +1,254 @@
+mod inter_processor_interrupt;
+mod scheduler;
+
+use crate::address_bus::AddressBus;
+use crate::cpu::{BootParameters, Cpu, DebugHook};
+use crate::port_bus::PortBus;
+use crate::system_call_host::SystemCallHost;
+use inter_processor_interrupt::{InterProcessorInterruptDevice, PendingInterrupts};
+use scheduler::Scheduler;
+
+use std::{cell::RefCell, collections::VecDeque, rc::Rc};
+
+/// The port of the inter-processor interrupt device unless the config file moves it
+pub const INTER_PROCESSOR_INTERRUPT_PORT: u16 = 0xfff0;
+
+/// How the cores of a machine are set up and scheduled
+#[derive(Debug, Clone)]
+pub struct MachineParameters {
+    pub cores: usize,
+    /// Number of instructions a core executes before the next core runs. 1 switches cores after every instruction
+    pub quantum: u64,
+    /// Picks the next core and the length of its quantum at random, seeded with this value
+    pub schedule_seed: Option<u64>,
+    pub inter_processor_interrupt_port: u16,
+}
+
+impl Default for MachineParameters {
+    fn default() -> Self {
+        Self {
+            cores: 1,
+            quantum: 1,
+            schedule_seed: None,
+            inter_processor_interrupt_port: INTER_PROCESSOR_INTERRUPT_PORT,
+        }
+    }
+}
+
+/// A number of cores sharing the address bus and the port bus. The cores run interleaved on one host thread, so
+/// every instruction is atomic with respect to the other cores.
+///
+/// Every core boots with the same boot parameters. Guests read their core ID with CPUID and use it to give each
+/// core its own stack
+pub struct Machine {
+    cores: Vec<Cpu>,
+    scheduler: Scheduler,
+    pending_interrupts: PendingInterrupts,
+    system_call_host: Option<Rc<RefCell<SystemCallHost>>>,
+}
+
+impl Machine {
+    pub fn new(
+        address_bus: Rc<RefCell<AddressBus>>,
+        port_bus: Rc<RefCell<PortBus>>,
+        boot_parameters: BootParameters,
+        machine_parameters: &MachineParameters,
+    ) -> Result<Self, ()> {
+        let core_count = machine_parameters.cores;
+
+        if core_count == 0 {
+            println!("A machine needs at least one core");
+            return Err(());
+        }
+
+        let pending_interrupts: PendingInterrupts =
+            Rc::new(RefCell::new(vec![VecDeque::new(); core_count]));
+
+        if port_bus
+            .borrow_mut()
+            .add_device(
+                machine_parameters.inter_processor_interrupt_port,
+                InterProcessorInterruptDevice::new(Rc::clone(&pending_interrupts)),
+            )
+            .is_err()
+        {
+            println!(
+                "Port {:#x} of the inter-processor interrupt device is already in use",
+                machine_parameters.inter_processor_interrupt_port
+            );
+            return Err(());
+        }
+
+        let cores = (0..core_count)
+            .map(|core_id| {
+                Cpu::new(
+                    Rc::clone(&address_bus),
+                    Rc::clone(&port_bus),
+                    boot_parameters.clone(),
+                    core_id as u64,
+                    core_count as u64,
+                )
+            })
+            .collect();
+
+        Ok(Self {
+            cores,
+            scheduler: Scheduler::new(
+                core_count,
+                machine_parameters.quantum,
+                machine_parameters.schedule_seed,
+            ),
+            pending_interrupts,
+            system_call_host: None,
+        })
+    }
+
+    /// Runs the next instruction of the core picked by the scheduler. With the JIT this can be a compiled block of
+    /// instructions, as long as it fits in the quantum of the core
+    pub fn clock(&mut self) {
+        let (core_id, max_instructions) = self.scheduler.next_core();
+        let core = &mut self.cores[core_id];
+
+        let mut pending_interrupts = self.pending_interrupts.borrow_mut();
+        let queue = &mut pending_interrupts[core_id];
+
+        if let Some(&idt_entry) = queue.front() {
+            if core.external_interrupt(idt_entry) {
+                queue.pop_front();
+            }
+        }
+
+        drop(pending_interrupts);
+
+        let cycles = core.clock(max_instructions);
+        self.scheduler.charge(cycles);
+    }
+
+    /// All cores service system calls through the same host, so they share open files and the program break
+    pub fn enable_hosted_mode(&mut self, system_call_host: SystemCallHost) {
+        let system_call_host = Rc::new(RefCell::new(system_call_host));
+
+        for core in &mut self.cores {
+            core.enable_hosted_mode(Rc::clone(&system_call_host));
+        }
+
+        self.system_call_host = Some(system_call_host);
+    }
+
+    /// Makes every core fetch each instruction from the address bus, which is slower but shows every fetch in the
+    /// debug output
+    pub fn disable_instruction_cache(&mut self) {
+        for core in &mut self.cores {
+            core.disable_instruction_cache();
+        }
+    }
+
+    /// Every core compiles its hot blocks to host code on its own
+    #[cfg(feature = "jit")]
+    pub fn enable_jit(&mut self) -> Result<(), ()> {
+        for core in &mut self.cores {
+            if let Err(e) = core.enable_jit() {
+                println!("Failed to set up the JIT: {}", e);
+                return Err(());
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Every core reports its debug traps to the same hook
+    pub fn set_debug_hook(&mut self, debug_hook: DebugHook) {
+        for core in &mut self.cores {
+            core.set_debug_hook(Rc::clone(&debug_hook));
+        }
+    }
+
+    /// The status the guest exited with in hosted mode. Read from the host the cores share, so it doesn't matter
+    /// which core called exit
+    pub fn exit_status(&self) -> Option<i32> {
+        self.system_call_host
+            .as_ref()
+            .and_then(|system_call_host| system_call_host.borrow().exit_status())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cpu::ResetVector;
+    use crate::memory::Memory;
+
+    const MEMORY_SIZE: u64 = 0x10000;
+    const IDT_BASE: u64 = 0x100;
+    const CODE_ADDRESS: u64 = 0x1000;
+    const HANDLER_ADDRESS: u64 = 0x2000;
+    const VECTOR: u8 = 0x20;
+
+    fn jump(target: u64) -> Vec<u8> {
+        let mut code = vec![0x05, 0x00];
+        code.extend(target.to_le_bytes());
+        code
+    }
+
+    #[test]
+    fn interrupts_stay_queued_while_interrupts_are_disabled() {
+        let address_bus = Rc::new(RefCell::new(AddressBus::new()));
+        let port_bus = Rc::new(RefCell::new(PortBus::new()));
+
+        // Every core runs LIDT, 4 NOPs and STI with interrupts disabled, and then loops
+        let mut code = vec![0x08, 0x00];
+        code.extend(IDT_BASE.to_le_bytes());
+        code.extend([0x90; 4]);
+        code.push(0x48);
+        code.extend(jump(CODE_ADDRESS + code.len() as u64));
+
+        {
+            let mut address_bus = address_bus.borrow_mut();
+            address_bus
+                .add_entry(0, MEMORY_SIZE, Memory::new(MEMORY_SIZE))
+                .unwrap();
+            address_bus.write(&code, CODE_ADDRESS);
+            address_bus.write(&jump(HANDLER_ADDRESS), HANDLER_ADDRESS);
+            address_bus.write(&HANDLER_ADDRESS.to_le_bytes(), IDT_BASE + VECTOR as u64 * 8);
+        }
+
+        let boot_parameters = BootParameters {
+            reset_vector: ResetVector::Address(CODE_ADDRESS),
+            initial_sp: MEMORY_SIZE,
+            initial_flags: 0,
+            ..Default::default()
+        };
+
+        let mut machine = Machine::new(
+            address_bus,
+            Rc::clone(&port_bus),
+            boot_parameters,
+            &MachineParameters {
+                cores: 2,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+
+        // Core 1
+        port_bus
+            .borrow_mut()
+            .write(INTER_PROCESSOR_INTERRUPT_PORT, 1 << 8 | VECTOR as u64);
+
+        // The cores take turns, and each runs LIDT and the NOPs
+        for _ in 0..2 * 5 {
+            machine.clock();
+        }
+
+        assert_eq!(machine.pending_interrupts.borrow()[1], [VECTOR]);
+
+        // STI on both cores, and then the interrupt is delivered before core 1 runs again
+        for _ in 0..2 * 2 {
+            machine.clock();
+        }
+
+        assert!(machine.pending_interrupts.borrow()[1].is_empty());
+        assert_eq!(machine.cores[1].instruction_pointer(), HANDLER_ADDRESS);
+        assert_ne!(machine.cores[0].instruction_pointer(), HANDLER_ADDRESS);
+    }
+}
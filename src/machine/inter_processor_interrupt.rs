@@ -0,0 +1,100 @@
+// A built-in port device that lets cores interrupt each other.
+//
+// Writing to the port sends the interrupt with the vector in bits 0-7 to the core whose ID is in bits 8-62. With bit
+// 63 set the interrupt is sent to every core, including the sender. Interrupts sent to a core that doesn't exist are
+// dropped. Reading the port returns the number of cores.
+//
+// The interrupts are queued until the receiving core has interrupts enabled, and wake the core if it is halted
+
+use crate::debug_println;
+use crate::PortBusDevice;
+
+use std::{cell::RefCell, collections::VecDeque, rc::Rc};
+
+const BROADCAST: u64 = 1 << 63;
+
+// The interrupts waiting to be delivered to each core, indexed by core ID
+pub type PendingInterrupts = Rc<RefCell<Vec<VecDeque<u8>>>>;
+
+pub struct InterProcessorInterruptDevice {
+    pending: PendingInterrupts,
+}
+
+impl InterProcessorInterruptDevice {
+    pub fn new(pending: PendingInterrupts) -> Self {
+        Self { pending }
+    }
+}
+
+impl PortBusDevice for InterProcessorInterruptDevice {
+    fn write(&mut self, value: u64) {
+        let idt_entry = value as u8;
+        let mut pending = self.pending.borrow_mut();
+
+        if value & BROADCAST != 0 {
+            debug_println!("Broadcasting interrupt {} to every core", idt_entry);
+
+            pending
+                .iter_mut()
+                .for_each(|queue| queue.push_back(idt_entry));
+        } else {
+            let core_id = (value & !BROADCAST) >> 8;
+
+            debug_println!("Sending interrupt {} to core {}", idt_entry, core_id);
+
+            if let Some(queue) = pending.get_mut(core_id as usize) {
+                queue.push_back(idt_entry);
+            }
+        }
+    }
+
+    fn read(&mut self) -> u64 {
+        self.pending.borrow().len() as u64
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn device(core_count: usize) -> (InterProcessorInterruptDevice, PendingInterrupts) {
+        let pending: PendingInterrupts = Rc::new(RefCell::new(vec![VecDeque::new(); core_count]));
+
+        (
+            InterProcessorInterruptDevice::new(Rc::clone(&pending)),
+            pending,
+        )
+    }
+
+    #[test]
+    fn interrupts_are_queued_for_the_target_core() {
+        let (mut device, pending) = device(3);
+
+        device.write(2 << 8 | 0x20);
+        device.write(2 << 8 | 0x21);
+        device.write(0x22);
+
+        assert_eq!(*pending.borrow(), [vec![0x22], vec![], vec![0x20, 0x21]]);
+    }
+
+    #[test]
+    fn broadcasts_reach_every_core_including_the_sender() {
+        let (mut device, pending) = device(3);
+
+        // The core ID is ignored
+        device.write(BROADCAST | 1 << 8 | 0x30);
+
+        assert_eq!(*pending.borrow(), [vec![0x30], vec![0x30], vec![0x30]]);
+    }
+
+    #[test]
+    fn interrupts_for_missing_cores_are_dropped() {
+        let (mut device, pending) = device(2);
+
+        device.write(2 << 8 | 0x20);
+        device.write(0x7fff_ffff_ffff_ff00 | 0x20);
+
+        assert!(pending.borrow().iter().all(|queue| queue.is_empty()));
+        assert_eq!(device.read(), 2);
+    }
+}
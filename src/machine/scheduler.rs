@@ -0,0 +1,154 @@
+// Decides which core runs next. Cores run for a quantum of instructions each, taking turns in order. With a seed the
+// next core and the length of its quantum are picked at random instead, so races in guest software show up in
+// different interleavings while every run with the same seed is the same
+
+pub struct Scheduler {
+    core_count: usize,
+    quantum: u64,
+    random: Option<SplitMix64>,
+
+    current: usize,
+    remaining: u64,
+}
+
+impl Scheduler {
+    pub fn new(core_count: usize, quantum: u64, seed: Option<u64>) -> Self {
+        let mut scheduler = Self {
+            core_count,
+            quantum: quantum.max(1),
+            random: seed.map(SplitMix64::new),
+
+            current: 0,
+            remaining: 0,
+        };
+
+        scheduler.remaining = scheduler.next_quantum();
+
+        scheduler
+    }
+
+    // Returns the index of the core that executes next, and how many instructions it can execute before its quantum
+    // ends. With a single core there is nothing to switch to, so there is no limit
+    pub fn next_core(&mut self) -> (usize, u64) {
+        if self.remaining == 0 {
+            self.current = match &mut self.random {
+                Some(random) => (random.next() % self.core_count as u64) as usize,
+                None => (self.current + 1) % self.core_count,
+            };
+
+            self.remaining = self.next_quantum();
+        }
+
+        let max_instructions = if self.core_count == 1 {
+            u64::MAX
+        } else {
+            self.remaining
+        };
+
+        (self.current, max_instructions)
+    }
+
+    // Charges the core returned by `next_core` for the clock cycles it ran
+    pub fn charge(&mut self, cycles: u64) {
+        self.remaining = self.remaining.saturating_sub(cycles);
+    }
+
+    fn next_quantum(&mut self) -> u64 {
+        match &mut self.random {
+            Some(random) => random.next() % self.quantum + 1,
+            None => self.quantum,
+        }
+    }
+}
+
+// A small pseudo random number generator. Any seed, including 0, gives a usable sequence
+struct SplitMix64 {
+    state: u64,
+}
+
+impl SplitMix64 {
+    fn new(seed: u64) -> Self {
+        Self { state: seed }
+    }
+
+    fn next(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(0x9e3779b97f4a7c15);
+
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+        z ^ (z >> 31)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Runs the scheduler for a number of instructions, charging one cycle each, and returns the core that ran each
+    // of them
+    fn schedule(scheduler: &mut Scheduler, instructions: usize) -> Vec<usize> {
+        (0..instructions)
+            .map(|_| {
+                let (core_id, _) = scheduler.next_core();
+                scheduler.charge(1);
+                core_id
+            })
+            .collect()
+    }
+
+    #[test]
+    fn cores_take_turns_in_order() {
+        let mut scheduler = Scheduler::new(3, 2, None);
+
+        assert_eq!(schedule(&mut scheduler, 8), [0, 0, 1, 1, 2, 2, 0, 0]);
+    }
+
+    #[test]
+    fn quantum_is_charged_by_cycles() {
+        let mut scheduler = Scheduler::new(2, 10, None);
+
+        assert_eq!(scheduler.next_core(), (0, 10));
+        scheduler.charge(4);
+        assert_eq!(scheduler.next_core(), (0, 6));
+        scheduler.charge(6);
+        assert_eq!(scheduler.next_core(), (1, 10));
+
+        // Charging more than is left ends the quantum
+        scheduler.charge(20);
+        assert_eq!(scheduler.next_core(), (0, 10));
+    }
+
+    #[test]
+    fn single_cores_have_no_limit() {
+        let mut scheduler = Scheduler::new(1, 10, None);
+
+        for _ in 0..3 {
+            assert_eq!(scheduler.next_core(), (0, u64::MAX));
+            scheduler.charge(10);
+        }
+    }
+
+    #[test]
+    fn the_same_seed_gives_the_same_schedule() {
+        let first = schedule(&mut Scheduler::new(4, 8, Some(42)), 1000);
+
+        assert_eq!(first, schedule(&mut Scheduler::new(4, 8, Some(42)), 1000));
+        assert_ne!(first, schedule(&mut Scheduler::new(4, 8, Some(43)), 1000));
+
+        // Every core gets to run
+        assert!((0..4).all(|core_id| first.contains(&core_id)));
+    }
+
+    #[test]
+    fn random_quanta_stay_within_the_quantum() {
+        let mut scheduler = Scheduler::new(2, 8, Some(0));
+
+        for _ in 0..1000 {
+            let (_, max_instructions) = scheduler.next_core();
+            assert!((1..=8).contains(&max_instructions));
+
+            scheduler.charge(max_instructions);
+        }
+    }
+}
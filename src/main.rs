@@ -7,23 +7,28 @@ mod config_file_parse;
 mod cpu;
 mod library_device;
 mod logger;
+mod machine;
 mod memory;
 mod port_bus;
 mod port_bus_device;
+mod system_call_host;
 
 use std::cell::RefCell;
+use std::path::PathBuf;
 use std::rc::Rc;
 use std::time::{Duration, Instant};
 
 use address_bus::AddressBus;
 use address_bus_device::AddressBusDevice;
 use clap::Parser;
-use config_file_parse::Config;
-use cpu::Cpu;
+use config_file_parse::{try_parse_number, Config};
+use cpu::{parse_feature, BootParameters, Cpu, DebugHook, DebugTrap, RegisterId, ResetVector};
 use library_device::LibraryAddressDevice;
+use machine::{Machine, MachineParameters};
 use memory::Memory;
 use port_bus::PortBus;
 use port_bus_device::PortBusDevice;
+use system_call_host::SystemCallHost;
 
 #[derive(Parser, Debug)]
 struct Args {
@@ -32,9 +37,164 @@ struct Args {
 
     #[clap(long = "--config")]
     config_file: Option<String>,
+
+    /// Start executing at this address after a reset
+    #[clap(long = "--reset-vector", value_parser = parse_number)]
+    reset_vector: Option<u64>,
+
+    /// Start executing at the address stored in the qword at this address after a reset
+    #[clap(long = "--reset-vector-pointer", value_parser = parse_number, conflicts_with = "reset-vector")]
+    reset_vector_pointer: Option<u64>,
+
+    /// Initial value of the stack pointer
+    #[clap(long = "--initial-sp", value_parser = parse_number)]
+    initial_sp: Option<u64>,
+
+    /// Initial value of the flags register
+    #[clap(long = "--initial-flags", value_parser = parse_number)]
+    initial_flags: Option<u64>,
+
+    /// Initial value of a general purpose register, given as <REGISTER>=<VALUE>. Can be repeated
+    #[clap(long = "--initial-register", value_parser = parse_initial_register)]
+    initial_registers: Vec<(RegisterId, u64)>,
+
+    /// Disable a CPU feature, making its instructions invalid and hiding it from CPUID. Can be repeated.
+    /// One of fpu, vector, register-extension, memory-operands, block-memory, atomics or performance-counters
+    #[clap(long = "--disable-feature", value_parser = parse_feature_name)]
+    disabled_features: Vec<u64>,
+
+    /// Run in hosted mode, servicing system calls on the host with file paths confined to this directory
+    #[clap(long = "--sandbox")]
+    sandbox: Option<PathBuf>,
+
+    /// Number of cores sharing the memory and the ports
+    #[clap(long = "--cores", value_parser = parse_core_count)]
+    cores: Option<usize>,
+
+    /// Number of instructions a core executes before the next core runs
+    #[clap(long = "--quantum", value_parser = parse_quantum)]
+    quantum: Option<u64>,
+
+    /// Schedule the cores in a random order, seeded with this value, instead of taking turns
+    #[clap(long = "--schedule-seed", value_parser = parse_number)]
+    schedule_seed: Option<u64>,
+
+    /// Stop on BRK and single step traps on the host instead of raising the DEBUG vector in the guest
+    #[clap(long = "--host-debug")]
+    host_debug: bool,
+
+    /// Fetch every instruction from the address bus instead of caching instructions that were already executed
+    #[clap(long = "--no-instruction-cache")]
+    no_instruction_cache: bool,
+
+    /// Compile hot blocks of guest code to host code
+    #[cfg(feature = "jit")]
+    #[clap(long = "--jit")]
+    jit: bool,
+}
+
+impl Args {
+    /// Command line options take precedence over the config file
+    fn apply_boot_parameters(&self, boot_parameters: &mut BootParameters) {
+        if let Some(address) = self.reset_vector {
+            boot_parameters.reset_vector = ResetVector::Address(address);
+        }
+
+        if let Some(address) = self.reset_vector_pointer {
+            boot_parameters.reset_vector = ResetVector::Pointer(address);
+        }
+
+        if let Some(sp) = self.initial_sp {
+            boot_parameters.initial_sp = sp;
+        }
+
+        if let Some(flags) = self.initial_flags {
+            boot_parameters.initial_flags = flags;
+        }
+
+        for &(id, value) in &self.initial_registers {
+            boot_parameters.set_initial_register(id, value);
+        }
+
+        for &feature in &self.disabled_features {
+            boot_parameters.features &= !feature;
+        }
+    }
+
+    fn apply_machine_parameters(&self, machine_parameters: &mut MachineParameters) {
+        if let Some(cores) = self.cores {
+            machine_parameters.cores = cores;
+        }
+
+        if let Some(quantum) = self.quantum {
+            machine_parameters.quantum = quantum;
+        }
+
+        if let Some(seed) = self.schedule_seed {
+            machine_parameters.schedule_seed = Some(seed);
+        }
+    }
+}
+
+fn parse_number(number: &str) -> Result<u64, String> {
+    try_parse_number(number).map_err(|e| e.into_owned())
+}
+
+fn parse_core_count(count: &str) -> Result<usize, String> {
+    match parse_number(count)? {
+        0 => Err(String::from("A machine needs at least one core")),
+        count => Ok(count as usize),
+    }
+}
+
+fn parse_quantum(quantum: &str) -> Result<u64, String> {
+    match parse_number(quantum)? {
+        0 => Err(String::from("The quantum must be at least 1")),
+        quantum => Ok(quantum),
+    }
 }
 
-fn load_file(file: &str, address_bus: &mut AddressBus) -> Result<(), ()> {
+fn parse_initial_register(argument: &str) -> Result<(RegisterId, u64), String> {
+    let (register, value) = argument
+        .split_once('=')
+        .ok_or_else(|| String::from("Expected <REGISTER>=<VALUE>"))?;
+
+    let id = match RegisterId::try_from(register) {
+        Ok(RegisterId::Sp | RegisterId::Ip) => {
+            return Err(String::from(
+                "Use --initial-sp or --reset-vector to set SP or IP",
+            ))
+        }
+        Ok(id) => id,
+        Err(_) => return Err(format!("Invalid register name \"{}\"", register)),
+    };
+
+    Ok((id, parse_number(value)?))
+}
+
+fn parse_feature_name(name: &str) -> Result<u64, String> {
+    parse_feature(name).ok_or_else(|| format!("Unknown feature \"{}\"", name))
+}
+
+// Prints where a core stopped and waits for enter before continuing
+fn host_debugger() -> DebugHook {
+    Rc::new(RefCell::new(|cpu: &Cpu, trap: DebugTrap| {
+        info_println!(
+            "Core {} stopped by {:?} at {:#x}. Press enter to continue",
+            cpu.core_id(),
+            trap,
+            cpu.instruction_pointer()
+        );
+
+        let mut line = String::new();
+        let _ = std::io::stdin().read_line(&mut line);
+
+        true
+    }))
+}
+
+// Returns the address of the end of the loaded file
+fn load_file(file: &str, address_bus: &mut AddressBus) -> Result<u64, ()> {
     let data: Vec<u8> = match std::fs::read(file) {
         Ok(d) => d,
         Err(_) => return Err(()),
@@ -50,7 +210,7 @@ fn load_file(file: &str, address_bus: &mut AddressBus) -> Result<(), ()> {
     // address_bus.write(&entry_point.to_le_bytes(), 0);
     // address_bus.write(&data[8..], 8);
 
-    Ok(())
+    Ok(data.len() as u64)
 }
 
 fn main() -> Result<(), ()> {
@@ -59,9 +219,15 @@ fn main() -> Result<(), ()> {
     let mut address_bus: Rc<RefCell<AddressBus>> = Rc::new(RefCell::new(AddressBus::new()));
     let mut port_bus: Rc<RefCell<PortBus>> = Rc::new(RefCell::new(PortBus::new()));
 
-    if let Some(config_file) = args.config_file {
-        let config = Config::new(&config_file)?;
+    let mut boot_parameters = BootParameters::default();
+    let mut machine_parameters = MachineParameters::default();
+
+    if let Some(config_file) = &args.config_file {
+        let config = Config::new(config_file)?;
         config.apply_config(&mut address_bus.borrow_mut(), &mut port_bus.borrow_mut())?;
+
+        boot_parameters = config.boot_parameters().clone();
+        machine_parameters = config.machine_parameters().clone();
     } else {
         println!("No config file found. Using default configuration");
 
@@ -74,15 +240,141 @@ fn main() -> Result<(), ()> {
             .unwrap();
     }
 
-    load_file(&args.input_file, &mut *address_bus.borrow_mut())?;
+    args.apply_boot_parameters(&mut boot_parameters);
+    args.apply_machine_parameters(&mut machine_parameters);
+
+    let program_end = load_file(&args.input_file, &mut *address_bus.borrow_mut())?;
+
+    let mut machine = Machine::new(
+        Rc::clone(&address_bus),
+        Rc::clone(&port_bus),
+        boot_parameters,
+        &machine_parameters,
+    )?;
+
+    if let Some(sandbox) = &args.sandbox {
+        machine.enable_hosted_mode(SystemCallHost::new(sandbox, program_end)?);
+    }
+
+    if args.no_instruction_cache {
+        machine.disable_instruction_cache();
+    }
 
-    let mut cpu = Cpu::new(Rc::clone(&address_bus), Rc::clone(&port_bus));
+    #[cfg(feature = "jit")]
+    if args.jit {
+        machine.enable_jit()?;
+    }
+
+    if args.host_debug {
+        machine.set_debug_hook(host_debugger());
+    }
 
     loop {
-        cpu.clock();
+        machine.clock();
+
+        if let Some(status) = machine.exit_status() {
+            std::process::exit(status);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Boot parameters as they would be after reading a config file
+    fn config_boot_parameters() -> BootParameters {
+        let mut boot_parameters = BootParameters {
+            reset_vector: ResetVector::Address(0x1000),
+            initial_sp: 0x8000,
+            initial_flags: 0b101,
+            ..BootParameters::default()
+        };
+        boot_parameters.set_initial_register(RegisterId::X1, 5);
+        boot_parameters.set_initial_register(RegisterId::X2, 7);
+        boot_parameters
+    }
+
+    fn apply(args: &[&str]) -> BootParameters {
+        let args = Args::try_parse_from(["emulator", "program.bin"].iter().chain(args)).unwrap();
 
-        if !cpu.halted() {
-            debug_println!("");
+        let mut boot_parameters = config_boot_parameters();
+        args.apply_boot_parameters(&mut boot_parameters);
+        boot_parameters
+    }
+
+    #[test]
+    fn command_line_boot_parameters_override_the_config_file() {
+        let boot_parameters = apply(&[
+            "--reset-vector-pointer",
+            "0x20",
+            "--initial-sp",
+            "0x100",
+            "--initial-flags",
+            "0",
+            "--initial-register",
+            "x1=9",
+            "--initial-register",
+            "x3=0x10",
+        ]);
+
+        assert!(matches!(
+            boot_parameters.reset_vector,
+            ResetVector::Pointer(0x20)
+        ));
+        assert_eq!(boot_parameters.initial_sp, 0x100);
+        assert_eq!(boot_parameters.initial_flags, 0);
+        assert_eq!(
+            boot_parameters.initial_registers,
+            [
+                (RegisterId::X2, 7),
+                (RegisterId::X1, 9),
+                (RegisterId::X3, 0x10)
+            ]
+        );
+
+        let boot_parameters = apply(&["--reset-vector", "0x40"]);
+        assert!(matches!(
+            boot_parameters.reset_vector,
+            ResetVector::Address(0x40)
+        ));
+    }
+
+    #[test]
+    fn the_config_file_is_kept_without_command_line_boot_parameters() {
+        let boot_parameters = apply(&[]);
+        let config = config_boot_parameters();
+
+        assert!(matches!(
+            boot_parameters.reset_vector,
+            ResetVector::Address(0x1000)
+        ));
+        assert_eq!(boot_parameters.initial_sp, config.initial_sp);
+        assert_eq!(boot_parameters.initial_flags, config.initial_flags);
+        assert_eq!(boot_parameters.initial_registers, config.initial_registers);
+        assert_eq!(boot_parameters.features, config.features);
+    }
+
+    #[test]
+    fn invalid_command_line_boot_parameters() {
+        for args in [
+            ["--initial-register", "sp=0x8000"],
+            ["--initial-register", "x16=1"],
+            ["--initial-register", "x1"],
+            ["--initial-sp", "stack"],
+        ] {
+            let args = ["emulator", "program.bin"].iter().chain(&args);
+            assert!(Args::try_parse_from(args).is_err());
         }
+
+        assert!(Args::try_parse_from([
+            "emulator",
+            "program.bin",
+            "--reset-vector",
+            "0x10",
+            "--reset-vector-pointer",
+            "0x20"
+        ])
+        .is_err());
     }
 }
@@ -33,4 +33,8 @@ impl AddressBusDevice for Memory {
             .zip(self.memory[offset as usize..offset as usize + len].iter())
             .for_each(|(x, y)| *x = *y);
     }
+
+    fn cacheable(&self) -> bool {
+        true
+    }
 }
@@ -1,15 +1,16 @@
 use crate::PortBusDevice;
 
-const INIT: Option<Box<dyn PortBusDevice>> = None;
+const PORT_COUNT: usize = 0xffff;
 
 pub struct PortBus {
-    entries: [Option<Box<dyn PortBusDevice>>; 0xffff],
+    // On the heap, since a slot for every port is too large to move around on the stack
+    entries: Box<[Option<Box<dyn PortBusDevice>>]>,
 }
 
 impl PortBus {
     pub fn new() -> Self {
         Self {
-            entries: [INIT; 0xffff],
+            entries: (0..PORT_COUNT).map(|_| None).collect(),
         }
     }
 
@@ -0,0 +1,601 @@
+// Services the SYSCALL instruction on the host in hosted mode, so guest programs can do file I/O and exit like user
+// processes without an operating system.
+//
+// The system call number is passed in X0 and the arguments in X1-X4. The result is returned in X0, where errors are
+// returned as the negated host errno, like Linux does. Buffers and paths that aren't entirely in mapped memory fail
+// with EFAULT, without transferring anything.
+//
+//   Number  Name   Arguments                   Result
+//   0       exit   status                      Doesn't return, the emulator exits with the status
+//   1       open   path, flags                 File descriptor
+//   2       read   fd, buffer, length          Number of bytes read, 0 at the end of the file
+//   3       write  fd, buffer, length          Number of bytes written
+//   4       close  fd                          0
+//   5       lseek  fd, offset, whence          New offset from the start of the file
+//   6       brk    address                     The program break after the call
+//   7       time                               Seconds since the Unix epoch
+//
+// Paths are NUL terminated and relative to the sandbox directory. Paths that lead outside of the sandbox, through
+// ".." or a symbolic link, fail with EACCES. The open flags are OPEN_READ, OPEN_WRITE, OPEN_CREATE, OPEN_TRUNCATE and
+// OPEN_APPEND below. The whence of lseek is 0 for the start of the file, 1 for the current offset and 2 for the end
+// of the file. File descriptors 0, 1 and 2 are the standard input, output and error of the emulator.
+//
+// brk moves the program break, which starts at the end of the loaded program, to the address and returns the new
+// break. An address of 0, or one below the initial break, leaves it where it is. brk doesn't allocate memory, the
+// memory up to the new break must already be mapped through the config file. If it isn't, the break stays where it
+// is, so guests detect the failure by comparing the result with the address they asked for
+
+use crate::address_bus::AddressBus;
+use crate::debug_println;
+
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Component, Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+pub const SYSTEM_CALL_EXIT: u64 = 0;
+pub const SYSTEM_CALL_OPEN: u64 = 1;
+pub const SYSTEM_CALL_READ: u64 = 2;
+pub const SYSTEM_CALL_WRITE: u64 = 3;
+pub const SYSTEM_CALL_CLOSE: u64 = 4;
+pub const SYSTEM_CALL_LSEEK: u64 = 5;
+pub const SYSTEM_CALL_BRK: u64 = 6;
+pub const SYSTEM_CALL_TIME: u64 = 7;
+
+pub const OPEN_READ: u64 = 1 << 0;
+pub const OPEN_WRITE: u64 = 1 << 1;
+pub const OPEN_CREATE: u64 = 1 << 2;
+pub const OPEN_TRUNCATE: u64 = 1 << 3;
+pub const OPEN_APPEND: u64 = 1 << 4;
+
+// The longest path, including the NUL terminator, that open reads from guest memory
+const MAX_PATH_LENGTH: usize = 4096;
+
+// The most bytes a single read or write transfers. Larger requests return a short count, which guests have to
+// handle anyway
+const MAX_TRANSFER_SIZE: u64 = 0x100000;
+
+// The first file descriptor handed out by open, after the standard streams
+const FIRST_FILE_DESCRIPTOR: u64 = 3;
+
+pub struct SystemCallHost {
+    sandbox: PathBuf,
+    files: HashMap<u64, File>,
+    next_file_descriptor: u64,
+
+    initial_break: u64,
+    program_break: u64,
+
+    exit_status: Option<i32>,
+}
+
+impl SystemCallHost {
+    pub fn new(sandbox: &Path, program_break: u64) -> Result<Self, ()> {
+        let sandbox = match sandbox.canonicalize() {
+            Ok(sandbox) if sandbox.is_dir() => sandbox,
+            _ => {
+                println!("Sandbox directory \"{}\" does not exist", sandbox.display());
+                return Err(());
+            }
+        };
+
+        Ok(Self {
+            sandbox,
+            files: HashMap::new(),
+            next_file_descriptor: FIRST_FILE_DESCRIPTOR,
+
+            initial_break: program_break,
+            program_break,
+
+            exit_status: None,
+        })
+    }
+
+    // Set once the guest called exit
+    pub fn exit_status(&self) -> Option<i32> {
+        self.exit_status
+    }
+
+    // Runs a system call and returns the value for X0
+    pub fn system_call(
+        &mut self,
+        address_bus: &mut AddressBus,
+        number: u64,
+        args: [u64; 4],
+    ) -> u64 {
+        debug_println!("System call {} with arguments {:x?}", number, args);
+
+        let result = match number {
+            SYSTEM_CALL_EXIT => {
+                self.exit_status = Some(args[0] as i32);
+                Ok(0)
+            }
+            SYSTEM_CALL_OPEN => self.open(address_bus, args[0], args[1]),
+            SYSTEM_CALL_READ => self.read(address_bus, args[0], args[1], args[2]),
+            SYSTEM_CALL_WRITE => self.write(address_bus, args[0], args[1], args[2]),
+            SYSTEM_CALL_CLOSE => self.close(args[0]),
+            SYSTEM_CALL_LSEEK => self.lseek(args[0], args[1], args[2]),
+            SYSTEM_CALL_BRK => Ok(self.brk(address_bus, args[0])),
+            SYSTEM_CALL_TIME => Ok(SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |time| time.as_secs())),
+            _ => Err(libc::ENOSYS),
+        };
+
+        match result {
+            Ok(value) => value,
+            Err(errno) => (-(errno as i64)) as u64,
+        }
+    }
+
+    fn open(&mut self, address_bus: &mut AddressBus, path: u64, flags: u64) -> Result<u64, i32> {
+        let path = read_string(address_bus, path)?;
+        let path = self.sandbox_path(&path)?;
+
+        let file = OpenOptions::new()
+            .read(flags & OPEN_READ != 0)
+            .write(flags & OPEN_WRITE != 0)
+            .create(flags & OPEN_CREATE != 0)
+            .truncate(flags & OPEN_TRUNCATE != 0)
+            .append(flags & OPEN_APPEND != 0)
+            .open(&path)
+            .map_err(errno)?;
+
+        let fd = self.next_file_descriptor;
+        self.next_file_descriptor += 1;
+
+        debug_println!("Opened \"{}\" as {}", path.display(), fd);
+
+        self.files.insert(fd, file);
+
+        Ok(fd)
+    }
+
+    fn read(
+        &mut self,
+        address_bus: &mut AddressBus,
+        fd: u64,
+        buffer: u64,
+        length: u64,
+    ) -> Result<u64, i32> {
+        let length = length.min(MAX_TRANSFER_SIZE);
+        check_buffer(address_bus, buffer, length)?;
+
+        let mut data = vec![0u8; length as usize];
+
+        let count = match fd {
+            0 => io::stdin().read(&mut data),
+            _ => self.file(fd)?.read(&mut data),
+        }
+        .map_err(errno)?;
+
+        address_bus.write(&data[..count], buffer);
+
+        Ok(count as u64)
+    }
+
+    fn write(
+        &mut self,
+        address_bus: &mut AddressBus,
+        fd: u64,
+        buffer: u64,
+        length: u64,
+    ) -> Result<u64, i32> {
+        let length = length.min(MAX_TRANSFER_SIZE);
+        check_buffer(address_bus, buffer, length)?;
+
+        let mut data = vec![0u8; length as usize];
+        address_bus.read(&mut data, buffer);
+
+        let count = match fd {
+            1 => io::stdout().write(&data),
+            2 => io::stderr().write(&data),
+            _ => self.file(fd)?.write(&data),
+        }
+        .map_err(errno)?;
+
+        Ok(count as u64)
+    }
+
+    fn close(&mut self, fd: u64) -> Result<u64, i32> {
+        match self.files.remove(&fd) {
+            Some(_) => Ok(0),
+            None => Err(libc::EBADF),
+        }
+    }
+
+    fn lseek(&mut self, fd: u64, offset: u64, whence: u64) -> Result<u64, i32> {
+        let position = match whence {
+            0 => SeekFrom::Start(offset),
+            1 => SeekFrom::Current(offset as i64),
+            2 => SeekFrom::End(offset as i64),
+            _ => return Err(libc::EINVAL),
+        };
+
+        self.file(fd)?.seek(position).map_err(errno)
+    }
+
+    fn brk(&mut self, address_bus: &AddressBus, address: u64) -> u64 {
+        if address >= self.initial_break
+            && address_bus.is_mapped(self.initial_break, address - self.initial_break)
+        {
+            self.program_break = address;
+        }
+
+        self.program_break
+    }
+
+    fn file(&mut self, fd: u64) -> Result<&mut File, i32> {
+        self.files.get_mut(&fd).ok_or(libc::EBADF)
+    }
+
+    // Resolves a guest path inside of the sandbox. Leading slashes are ignored, so "/a" and "a" are the same file
+    fn sandbox_path(&self, path: &str) -> Result<PathBuf, i32> {
+        let mut resolved = self.sandbox.clone();
+
+        for component in Path::new(path).components() {
+            match component {
+                Component::Normal(name) => resolved.push(name),
+                Component::RootDir | Component::CurDir => {}
+                Component::ParentDir | Component::Prefix(_) => return Err(libc::EACCES),
+            }
+        }
+
+        if resolved == self.sandbox {
+            return Err(libc::EISDIR);
+        }
+
+        // A symbolic link could still point outside of the sandbox, so check where the directory really is.
+        // The file itself may not exist yet if it is being created
+        let parent = resolved
+            .parent()
+            .and_then(|parent| parent.canonicalize().ok())
+            .ok_or(libc::ENOENT)?;
+
+        if !parent.starts_with(&self.sandbox) {
+            return Err(libc::EACCES);
+        }
+
+        let resolved = parent.join(resolved.file_name().ok_or(libc::EINVAL)?);
+
+        match resolved.canonicalize() {
+            Ok(target) if !target.starts_with(&self.sandbox) => Err(libc::EACCES),
+            Ok(_) => Ok(resolved),
+
+            // A dangling symbolic link would let open create a file wherever it points
+            Err(_) if resolved.symlink_metadata().is_ok() => Err(libc::EACCES),
+            Err(_) => Ok(resolved),
+        }
+    }
+}
+
+fn errno(error: io::Error) -> i32 {
+    error.raw_os_error().unwrap_or(libc::EIO)
+}
+
+// Fails with EFAULT unless the whole guest buffer is mapped, before anything is transferred
+fn check_buffer(address_bus: &AddressBus, buffer: u64, length: u64) -> Result<(), i32> {
+    match buffer.checked_add(length) {
+        Some(_) if address_bus.is_mapped(buffer, length) => Ok(()),
+        _ => Err(libc::EFAULT),
+    }
+}
+
+// Reads a NUL terminated UTF-8 string from guest memory
+fn read_string(address_bus: &mut AddressBus, address: u64) -> Result<String, i32> {
+    let mut bytes = Vec::new();
+
+    for offset in 0..MAX_PATH_LENGTH as u64 {
+        let byte_address = address.checked_add(offset).ok_or(libc::EFAULT)?;
+        check_buffer(address_bus, byte_address, 1)?;
+
+        let mut byte = [0u8; 1];
+        address_bus.read(&mut byte, byte_address);
+
+        if byte[0] == 0 {
+            return String::from_utf8(bytes).map_err(|_| libc::EINVAL);
+        }
+
+        bytes.push(byte[0]);
+    }
+
+    Err(libc::ENAMETOOLONG)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory::Memory;
+
+    use std::fs;
+    use std::os::unix::fs::symlink;
+
+    const MEMORY_SIZE: u64 = 0x1000;
+    const PATH_ADDRESS: u64 = 0x100;
+    const BUFFER_ADDRESS: u64 = 0x200;
+
+    // A directory with a "sandbox" directory for the host and an "outside" directory next to it, removed when
+    // dropped
+    struct TestDirectory {
+        path: PathBuf,
+    }
+
+    impl TestDirectory {
+        fn new(name: &str) -> Self {
+            let path = std::env::temp_dir().join(format!(
+                "rust-cpu-emulator-{}-{}",
+                std::process::id(),
+                name
+            ));
+
+            let _ = fs::remove_dir_all(&path);
+            fs::create_dir_all(path.join("sandbox")).unwrap();
+            fs::create_dir_all(path.join("outside")).unwrap();
+
+            Self { path }
+        }
+
+        fn sandbox(&self) -> PathBuf {
+            self.path.join("sandbox")
+        }
+
+        fn outside(&self) -> PathBuf {
+            self.path.join("outside")
+        }
+    }
+
+    impl Drop for TestDirectory {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.path);
+        }
+    }
+
+    fn test_address_bus() -> AddressBus {
+        let mut address_bus = AddressBus::new();
+        address_bus
+            .add_entry(0, MEMORY_SIZE, Memory::new(MEMORY_SIZE))
+            .unwrap();
+        address_bus
+    }
+
+    fn error(errno: i32) -> u64 {
+        (-(errno as i64)) as u64
+    }
+
+    // Opens the path through guest memory
+    fn open(
+        host: &mut SystemCallHost,
+        address_bus: &mut AddressBus,
+        path: &str,
+        flags: u64,
+    ) -> u64 {
+        address_bus.write(format!("{}\0", path).as_bytes(), PATH_ADDRESS);
+        host.system_call(address_bus, SYSTEM_CALL_OPEN, [PATH_ADDRESS, flags, 0, 0])
+    }
+
+    #[test]
+    fn files_are_written_read_and_seeked() {
+        let directory = TestDirectory::new("files");
+        let mut host = SystemCallHost::new(&directory.sandbox(), 0x800).unwrap();
+        let mut address_bus = test_address_bus();
+
+        let fd = open(
+            &mut host,
+            &mut address_bus,
+            "/file",
+            OPEN_WRITE | OPEN_CREATE | OPEN_TRUNCATE,
+        );
+        assert_eq!(fd, FIRST_FILE_DESCRIPTOR);
+
+        address_bus.write(b"hello world", BUFFER_ADDRESS);
+        let written = host.system_call(
+            &mut address_bus,
+            SYSTEM_CALL_WRITE,
+            [fd, BUFFER_ADDRESS, 11, 0],
+        );
+        assert_eq!(written, 11);
+        assert_eq!(
+            host.system_call(&mut address_bus, SYSTEM_CALL_CLOSE, [fd, 0, 0, 0]),
+            0
+        );
+        assert_eq!(
+            host.system_call(&mut address_bus, SYSTEM_CALL_CLOSE, [fd, 0, 0, 0]),
+            error(libc::EBADF)
+        );
+
+        assert_eq!(
+            fs::read(directory.sandbox().join("file")).unwrap(),
+            b"hello world"
+        );
+
+        let fd = open(&mut host, &mut address_bus, "file", OPEN_READ);
+        assert_eq!(fd, FIRST_FILE_DESCRIPTOR + 1);
+
+        // From the end, from the start and from the current offset
+        let lseek =
+            |host: &mut SystemCallHost, address_bus: &mut AddressBus, offset: i64, whence| {
+                host.system_call(
+                    address_bus,
+                    SYSTEM_CALL_LSEEK,
+                    [fd, offset as u64, whence, 0],
+                )
+            };
+
+        assert_eq!(lseek(&mut host, &mut address_bus, -5, 2), 6);
+        assert_eq!(lseek(&mut host, &mut address_bus, 0, 0), 0);
+        assert_eq!(lseek(&mut host, &mut address_bus, 6, 1), 6);
+        assert_eq!(
+            lseek(&mut host, &mut address_bus, 0, 3),
+            error(libc::EINVAL)
+        );
+
+        let read = host.system_call(
+            &mut address_bus,
+            SYSTEM_CALL_READ,
+            [fd, BUFFER_ADDRESS, 0x10, 0],
+        );
+        assert_eq!(read, 5);
+
+        let mut data = [0u8; 5];
+        address_bus.read(&mut data, BUFFER_ADDRESS);
+        assert_eq!(&data, b"world");
+
+        // At the end of the file
+        let read = host.system_call(
+            &mut address_bus,
+            SYSTEM_CALL_READ,
+            [fd, BUFFER_ADDRESS, 0x10, 0],
+        );
+        assert_eq!(read, 0);
+
+        // Nothing to transfer
+        assert_eq!(
+            host.system_call(
+                &mut address_bus,
+                SYSTEM_CALL_WRITE,
+                [1, BUFFER_ADDRESS, 0, 0]
+            ),
+            0
+        );
+        assert_eq!(
+            host.system_call(
+                &mut address_bus,
+                SYSTEM_CALL_READ,
+                [99, BUFFER_ADDRESS, 1, 0]
+            ),
+            error(libc::EBADF)
+        );
+    }
+
+    #[test]
+    fn buffers_outside_of_mapped_memory_fault() {
+        let directory = TestDirectory::new("buffers");
+        fs::write(directory.sandbox().join("file"), b"data").unwrap();
+
+        let mut host = SystemCallHost::new(&directory.sandbox(), 0x800).unwrap();
+        let mut address_bus = test_address_bus();
+
+        let fd = open(&mut host, &mut address_bus, "file", OPEN_READ | OPEN_WRITE);
+
+        // Wrapping around the end of the address space, unmapped, and only partly mapped
+        for (buffer, length) in [
+            (u64::MAX - 1, 4),
+            (u64::MAX, 1),
+            (MEMORY_SIZE, 1),
+            (MEMORY_SIZE - 2, 4),
+        ] {
+            for number in [SYSTEM_CALL_READ, SYSTEM_CALL_WRITE] {
+                assert_eq!(
+                    host.system_call(&mut address_bus, number, [fd, buffer, length, 0]),
+                    error(libc::EFAULT)
+                );
+            }
+        }
+
+        // Nothing was read or written
+        let read = host.system_call(
+            &mut address_bus,
+            SYSTEM_CALL_READ,
+            [fd, BUFFER_ADDRESS, 0x10, 0],
+        );
+        assert_eq!(read, 4);
+        assert_eq!(fs::read(directory.sandbox().join("file")).unwrap(), b"data");
+
+        // A path running into unmapped memory
+        address_bus.write(b"file", MEMORY_SIZE - 4);
+        assert_eq!(
+            host.system_call(
+                &mut address_bus,
+                SYSTEM_CALL_OPEN,
+                [MEMORY_SIZE - 4, OPEN_READ, 0, 0]
+            ),
+            error(libc::EFAULT)
+        );
+        assert_eq!(
+            host.system_call(
+                &mut address_bus,
+                SYSTEM_CALL_OPEN,
+                [u64::MAX, OPEN_READ, 0, 0]
+            ),
+            error(libc::EFAULT)
+        );
+    }
+
+    #[test]
+    fn paths_cannot_leave_the_sandbox() {
+        let directory = TestDirectory::new("paths");
+        fs::write(directory.outside().join("secret"), b"secret").unwrap();
+        fs::create_dir(directory.sandbox().join("inner")).unwrap();
+        fs::write(directory.sandbox().join("inner").join("file"), b"file").unwrap();
+
+        symlink(directory.outside(), directory.sandbox().join("linked")).unwrap();
+        symlink(
+            directory.outside().join("created"),
+            directory.sandbox().join("dangling"),
+        )
+        .unwrap();
+        symlink(
+            directory.outside().join("secret"),
+            directory.sandbox().join("secret"),
+        )
+        .unwrap();
+
+        let mut host = SystemCallHost::new(&directory.sandbox(), 0x800).unwrap();
+        let mut address_bus = test_address_bus();
+
+        for path in [
+            "../outside/secret",
+            "inner/../../outside/secret",
+            "linked/secret",
+            "secret",
+        ] {
+            assert_eq!(
+                open(&mut host, &mut address_bus, path, OPEN_READ),
+                error(libc::EACCES),
+                "{}",
+                path
+            );
+        }
+
+        // Creating a file through a symbolic link that doesn't lead anywhere yet
+        assert_eq!(
+            open(
+                &mut host,
+                &mut address_bus,
+                "dangling",
+                OPEN_WRITE | OPEN_CREATE
+            ),
+            error(libc::EACCES)
+        );
+        assert!(!directory.outside().join("created").exists());
+
+        assert_eq!(
+            open(&mut host, &mut address_bus, "/", OPEN_READ),
+            error(libc::EISDIR)
+        );
+        assert_eq!(
+            open(&mut host, &mut address_bus, "./inner/file", OPEN_READ),
+            FIRST_FILE_DESCRIPTOR
+        );
+    }
+
+    #[test]
+    fn brk_stays_inside_mapped_memory() {
+        let mut address_bus = AddressBus::new();
+        address_bus
+            .add_entry(0, 0x1000, Memory::new(0x1000))
+            .unwrap();
+        address_bus
+            .add_entry(0x1000, 0x1000, Memory::new(0x1000))
+            .unwrap();
+
+        let mut host = SystemCallHost::new(&std::env::temp_dir(), 0x800).unwrap();
+
+        assert_eq!(host.brk(&address_bus, 0x1800), 0x1800);
+        assert_eq!(host.brk(&address_bus, 0x2000), 0x2000);
+        assert_eq!(host.brk(&address_bus, 0x2001), 0x2000);
+        assert_eq!(host.brk(&address_bus, 0x400), 0x2000);
+        assert_eq!(host.brk(&address_bus, 0x800), 0x800);
+    }
+}